@@ -4,7 +4,10 @@ use std::fs::create_dir_all;
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
 use cw_nride_escrow::msg::{
-    DetailsResponse, ExecuteMsg, InstantiateMsg, ListResponse, QueryMsg, ReceiveMsg,
+    BalanceResponse, BatchResponse, ConfigResponse, ContributionsResponse, CreateResponse,
+    DetailsListResponse, DetailsResponse, ExecuteMsg, HistoryResponse, InstantiateMsg,
+    ListResponse, MigrateMsg, QueryMsg, ReceiveMsg, StatsResponse, SudoMsg, TopUpResponse,
+    WithdrawPayload,
 };
 
 fn main() {
@@ -15,8 +18,20 @@ fn main() {
 
     export_schema(&schema_for!(InstantiateMsg), &out_dir);
     export_schema(&schema_for!(ExecuteMsg), &out_dir);
+    export_schema(&schema_for!(MigrateMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
     export_schema(&schema_for!(ReceiveMsg), &out_dir);
+    export_schema(&schema_for!(SudoMsg), &out_dir);
+    export_schema(&schema_for!(CreateResponse), &out_dir);
     export_schema(&schema_for!(DetailsResponse), &out_dir);
     export_schema(&schema_for!(ListResponse), &out_dir);
+    export_schema(&schema_for!(DetailsListResponse), &out_dir);
+    export_schema(&schema_for!(ConfigResponse), &out_dir);
+    export_schema(&schema_for!(ContributionsResponse), &out_dir);
+    export_schema(&schema_for!(BalanceResponse), &out_dir);
+    export_schema(&schema_for!(TopUpResponse), &out_dir);
+    export_schema(&schema_for!(BatchResponse), &out_dir);
+    export_schema(&schema_for!(HistoryResponse), &out_dir);
+    export_schema(&schema_for!(StatsResponse), &out_dir);
+    export_schema(&schema_for!(WithdrawPayload), &out_dir);
 }
@@ -0,0 +1,124 @@
+use cosmwasm_std::Uint128;
+use cw20::{Balance, Cw20CoinVerified};
+
+/// normalize_balance sorts native coins and drops the empty ones, so that
+/// balances holding the same funds compare equal. Any empty balance is
+/// normalized to the default (empty native) balance.
+pub fn normalize_balance(balance: &Balance) -> Balance {
+    match balance {
+        Balance::Native(native) => {
+            let mut native = native.clone();
+            native.normalize();
+            Balance::Native(native)
+        }
+        Balance::Cw20(token) if token.amount.is_zero() => Balance::default(),
+        Balance::Cw20(_) => balance.clone(),
+    }
+}
+
+/// add_balances returns the sum of two balances, or None if the result
+/// cannot be represented by a single Balance (native coins mixed with a cw20
+/// token, or two different cw20 tokens). An empty balance can be added to
+/// any balance.
+pub fn add_balances(a: &Balance, b: &Balance) -> Option<Balance> {
+    match (normalize_balance(a), normalize_balance(b)) {
+        (Balance::Native(x), Balance::Native(y)) => {
+            let mut sum = x + y;
+            sum.normalize();
+            Some(Balance::Native(sum))
+        }
+        (Balance::Cw20(x), Balance::Cw20(y)) if x.address == y.address => {
+            Some(Balance::Cw20(Cw20CoinVerified {
+                address: x.address,
+                amount: x.amount + y.amount,
+            }))
+        }
+        (Balance::Cw20(x), other) | (other, Balance::Cw20(x)) if other.is_empty() => {
+            Some(Balance::Cw20(x))
+        }
+        _ => None,
+    }
+}
+
+/// single_amount returns the amount of a balance holding a single token, or
+/// None if it holds several native coins or none
+pub fn single_amount(balance: &Balance) -> Option<Uint128> {
+    match balance {
+        Balance::Native(native) => match native.0.as_slice() {
+            [coin] => Some(coin.amount),
+            _ => None,
+        },
+        Balance::Cw20(token) => Some(token.amount),
+    }
+}
+
+/// with_amount returns a balance of the same token as a single token
+/// balance, holding amount instead
+pub fn with_amount(balance: &Balance, amount: Uint128) -> Balance {
+    let mut balance = balance.clone();
+    match &mut balance {
+        Balance::Native(native) => native.0.iter_mut().for_each(|coin| coin.amount = amount),
+        Balance::Cw20(token) => token.amount = amount,
+    }
+    balance
+}
+
+/// format_balance renders a balance like a list of coins, e.g.
+/// "10atom,5osmo" for native coins or "10juno1..." for a cw20 token
+pub fn format_balance(balance: &Balance) -> String {
+    match balance {
+        Balance::Native(native) => native
+            .0
+            .iter()
+            .map(|coin| coin.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+        Balance::Cw20(token) => format!("{}{}", token.amount, token.address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cosmwasm_std::{Addr, Coin};
+    use cw_utils::NativeBalance;
+
+    fn cw20(address: &str, amount: u128) -> Balance {
+        Balance::Cw20(Cw20CoinVerified {
+            address: Addr::unchecked(address),
+            amount: Uint128::new(amount),
+        })
+    }
+
+    #[test]
+    fn add_native_balances() {
+        let a = Balance::Native(NativeBalance(vec![Coin::new(10, "osmo"), Coin::new(5, "atom")]));
+        let b = Balance::Native(NativeBalance(vec![Coin::new(7, "atom"), Coin::new(0, "juno")]));
+
+        let sum = add_balances(&a, &b).unwrap();
+        assert_eq!(
+            sum,
+            Balance::Native(NativeBalance(vec![Coin::new(12, "atom"), Coin::new(10, "osmo")]))
+        );
+    }
+
+    #[test]
+    fn add_cw20_balances() {
+        assert_eq!(add_balances(&cw20("cash", 3), &cw20("cash", 4)), Some(cw20("cash", 7)));
+        assert_eq!(add_balances(&cw20("cash", 3), &Balance::default()), Some(cw20("cash", 3)));
+        assert_eq!(add_balances(&cw20("other", 0), &cw20("cash", 4)), Some(cw20("cash", 4)));
+
+        // balances that cannot be represented together
+        assert_eq!(add_balances(&cw20("cash", 3), &cw20("other", 4)), None);
+        let native = Balance::Native(NativeBalance(vec![Coin::new(1, "atom")]));
+        assert_eq!(add_balances(&cw20("cash", 3), &native), None);
+    }
+
+    #[test]
+    fn format_balances() {
+        let native = Balance::Native(NativeBalance(vec![Coin::new(5, "atom"), Coin::new(10, "osmo")]));
+        assert_eq!("5atom,10osmo", format_balance(&native));
+        assert_eq!("7token", format_balance(&cw20("token", 7)));
+    }
+}
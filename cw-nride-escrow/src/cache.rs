@@ -0,0 +1,155 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::ops::Bound;
+
+use cosmwasm_std::{Order, Record, Storage};
+
+/// pending writes of a range, in order
+type Writes<'a> = Box<dyn Iterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)> + 'a>;
+
+/// StorageCache keeps the writes made on top of a storage until they are
+/// committed, so that they can be dropped instead, e.g. when a batch item
+/// fails half-way. Reads, ranges included, see the pending writes.
+pub struct StorageCache<'a> {
+    storage: &'a mut dyn Storage,
+    /// pending writes, None for a removed key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> StorageCache<'a> {
+    pub fn new(storage: &'a mut dyn Storage) -> Self {
+        StorageCache { storage, writes: BTreeMap::new() }
+    }
+
+    /// commit applies the pending writes to the storage
+    pub fn commit(self) {
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => self.storage.set(&key, &value),
+                None => self.storage.remove(&key),
+            }
+        }
+    }
+}
+
+impl<'a> Storage for StorageCache<'a> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.writes.get(key) {
+            Some(value) => value.clone(),
+            None => self.storage.get(key),
+        }
+    }
+
+    fn range<'b>(
+        &'b self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'b> {
+        let bounds = (
+            start.map_or(Bound::Unbounded, |start| Bound::Included(start.to_vec())),
+            end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.to_vec())),
+        );
+        // an empty range, which BTreeMap::range does not accept
+        if let (Some(start), Some(end)) = (start, end) {
+            if start >= end {
+                return Box::new(std::iter::empty());
+            }
+        }
+        let writes: Writes = match order {
+            Order::Ascending => Box::new(self.writes.range(bounds)),
+            Order::Descending => Box::new(self.writes.range(bounds).rev()),
+        };
+        Box::new(MergedRange {
+            base: self.storage.range(start, end, order).peekable(),
+            writes: writes.peekable(),
+            order,
+        })
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+}
+
+/// MergedRange goes through the records of the storage and the pending
+/// writes in order, the writes replacing the records with the same key
+struct MergedRange<'a, B: Iterator<Item = Record>> {
+    base: Peekable<B>,
+    writes: Peekable<Writes<'a>>,
+    order: Order,
+}
+
+impl<'a, B: Iterator<Item = Record>> Iterator for MergedRange<'a, B> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            let next = match (self.base.peek(), self.writes.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((base, _)), Some((write, _))) => match self.order {
+                    Order::Ascending => base.cmp(*write),
+                    Order::Descending => (*write).cmp(base),
+                },
+            };
+            if next == Ordering::Less {
+                return self.base.next();
+            }
+            if next == Ordering::Equal {
+                self.base.next();
+            }
+            if let Some((key, Some(value))) = self.writes.next() {
+                return Some((key.clone(), value.clone()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cosmwasm_std::testing::MockStorage;
+
+    #[test]
+    fn cached_writes() {
+        let mut storage = MockStorage::new();
+        storage.set(b"a", b"1");
+        storage.set(b"c", b"3");
+        storage.set(b"e", b"5");
+
+        let mut cache = StorageCache::new(&mut storage);
+        cache.set(b"b", b"2");
+        cache.set(b"c", b"33");
+        cache.remove(b"e");
+        assert_eq!(Some(b"33".to_vec()), cache.get(b"c"));
+        assert_eq!(None, cache.get(b"e"));
+
+        let keys = |cache: &StorageCache, start: Option<&[u8]>, order| {
+            cache.range(start, None, order).map(|(key, value)| [key, value].concat()).collect::<Vec<_>>()
+        };
+        assert_eq!(vec![b"a1".to_vec(), b"b2".to_vec(), b"c33".to_vec()], keys(&cache, None, Order::Ascending));
+        assert_eq!(vec![b"c33".to_vec(), b"b2".to_vec(), b"a1".to_vec()], keys(&cache, None, Order::Descending));
+        assert_eq!(vec![b"b2".to_vec(), b"c33".to_vec()], keys(&cache, Some(b"b"), Order::Ascending));
+        assert_eq!(0, cache.range(Some(b"c"), Some(b"a"), Order::Ascending).count());
+
+        // dropped writes leave the storage as it was
+        drop(cache);
+        assert_eq!(None, storage.get(b"b"));
+        assert_eq!(Some(b"5".to_vec()), storage.get(b"e"));
+
+        let mut cache = StorageCache::new(&mut storage);
+        cache.set(b"b", b"2");
+        cache.remove(b"e");
+        cache.commit();
+        assert_eq!(Some(b"2".to_vec()), storage.get(b"b"));
+        assert_eq!(None, storage.get(b"e"));
+    }
+}
@@ -1,28 +1,67 @@
 #[cfg(not(feature = "library"))]
-
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    from_binary, to_binary, coins, Addr, Binary, Deps, DepsMut, Env, MessageInfo, Response,
-    StdResult, SubMsg, WasmMsg, BankMsg, StdError,
+    from_binary, to_binary, to_vec, Addr, Api, Binary, Coin, CosmosMsg, Deps, DepsMut, Empty, Env, Event, IbcMsg,
+    MessageInfo, Reply, Response, StdResult, Storage, SubMsg, SubMsgResult, Timestamp, Uint128,
+    WasmMsg, BankMsg,
 };
 
-use cw2::set_contract_version;
-use cw20::{Balance, Cw20CoinVerified, Cw20ExecuteMsg, Cw20ReceiveMsg};
+use cw2::{get_contract_version, set_contract_version};
+use semver::Version;
+use cw20::{Balance, Cw20Coin, Cw20CoinVerified, Cw20ExecuteMsg, Cw20ReceiveMsg, Denom};
+use cw_utils::NativeBalance;
 
-use crate::error::ContractError;
+use crate::error::{ContractError, EscrowError};
 use crate::msg::{
     InstantiateMsg,
+    MigrateMsg,
     CreateMsg, 
+    CreateResponse,
+    TopUpResponse,
     ExecuteMsg,
     WithdrawMsg,
+    WithdrawPayload,
     ReceiveMsg,
     QueryMsg,
     ListResponse,
-    DetailsResponse
+    DetailsResponse,
+    DetailsListResponse,
+    ConfigResponse,
+    BalanceResponse,
+    BatchCreateItem,
+    BatchResponse,
+    BatchResult,
+    Contribution,
+    ContributionsResponse,
+    ArchiveRecord,
+    HistoryResponse,
+    StatsResponse,
+    Totals,
+    SudoMsg,
+    IbcLifecycleComplete,
+};
+
+use crate::balance::{format_balance, single_amount, with_amount};
+use crate::cache::StorageCache;
+use crate::escrow::{check_deposit_amount, CancellationFee, Escrow, EscrowStatus, IbcDestination, Milestone};
+use crate::ibc::{transfer_msg, transfer_sequence};
+use crate::migrations::migrate_state;
+use crate::state::{
+    archive, archived_escrows, contributions, escrow_ids, escrows, escrows_by_creator,
+    escrows_by_recipient, has_ibc_transfers, ibc_transfers, is_reserved, open_escrows,
+    remove_stale_payouts, settled_before, update_stats, ArchivedEscrow, Config, IbcTransfer, Payout,
+    PayoutMode, PendingCreate, ALLOWANCE_REPLY_ID, ARCHIVE_COUNT, CLAIMABLE, CONFIG, CONTRIBUTIONS,
+    ESCROW_COUNT, MAX_FEE_BPS, NONCE_COUNT, PAYOUTS, PAYOUT_COUNT, PAYOUT_MODES, PENDING_CREATE,
+    RELAYER_APPROVALS, STATS,
 };
 
-use crate::escrow::Escrow;
-use crate::state::{all_escrow_ids, ESCROWS};
+// length of escrow ids chosen by the creator, in bytes
+const MIN_ID_LENGTH: usize = 3;
+const MAX_ID_LENGTH: usize = 20;
+
+// pagination of list queries
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:cw-nride-escrow";
@@ -32,14 +71,185 @@ const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub fn instantiate(
     deps: DepsMut,
     _env: Env,
-    _info: MessageInfo,
-    _msg: InstantiateMsg,
-) -> StdResult<Response> {
+    info: MessageInfo,
+    msg: InstantiateMsg,
+) -> Result<Response, ContractError> {
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
-    // no setup
+
+    if msg.fee_bps > MAX_FEE_BPS {
+        return Err(ContractError::InvalidFee {});
+    }
+
+    let config = Config {
+        admin: info.sender,
+        fee_collector: deps.api.addr_validate(&msg.fee_collector)?,
+        fee_bps: msg.fee_bps,
+        allowed_native: msg.allowed_native,
+        allowed_cw20: validate_addrs(deps.api, &msg.allowed_cw20)?,
+        pending_admin: None,
+        paused: false,
+        relayers: vec![],
+    };
+    CONFIG.save(deps.storage, &config)?;
+
     Ok(Response::default())
 }
 
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(
+    mut deps: DepsMut,
+    env: Env,
+    msg: MigrateMsg,
+) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    let version: Version = CONTRACT_VERSION.parse()?;
+    let storage_version: Version = stored.version.parse()?;
+    // only upgrades of this contract are supported
+    if stored.contract != CONTRACT_NAME || storage_version > version {
+        return Err(ContractError::CannotMigrate {
+            previous_contract: stored.contract,
+            previous_version: stored.version,
+        });
+    }
+    if storage_version < version {
+        migrate_state(deps.branch(), &env, &storage_version, &msg)?;
+        set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    if msg.id == ALLOWANCE_REPLY_ID {
+        return allowance_pulled(deps, env);
+    }
+
+    let payout = PAYOUTS.load(deps.storage, msg.id)?;
+    PAYOUTS.remove(deps.storage, msg.id);
+
+    // payouts are sent with reply_on_error, except IBC transfers which also
+    // reply on success with their sequence
+    let error = match msg.result {
+        SubMsgResult::Err(error) => error,
+        SubMsgResult::Ok(response) => return ibc_transfer_sent(deps, payout, response.data),
+    };
+
+    // the transfer was reverted, the funds are kept for a retry. A failed
+    // IBC transfer is retried as a payment to its fallback, as its timeout
+    // would have passed by then.
+    let mut escrow = escrows().load(deps.storage, &payout.escrow)?;
+    let status = escrow.status;
+    let failed = match (payout.msg, &escrow.ibc_destination) {
+        (CosmosMsg::Ibc(IbcMsg::Transfer { amount, .. }), Some(destination)) => BankMsg::Send {
+            to_address: destination.fallback.to_string(),
+            amount: vec![amount],
+        }
+        .into(),
+        (msg, _) => msg,
+    };
+    escrow.payout_failed(failed, env.block.time)?;
+    escrows().save(deps.storage, &payout.escrow, &escrow)?;
+
+    let mut res = Response::new().add_attributes(vec![
+        ("action", "payout_failed"),
+        ("id", payout.escrow.as_str()),
+        ("payout_error", error.as_str()),
+    ]);
+    if escrow.status != status {
+        res = res.add_event(status_event(&payout.escrow, &escrow));
+    }
+    Ok(res)
+}
+
+/// allowance_pulled creates the escrow of a CreateFromAllowance once the
+/// TransferFrom brought its deposit in. It is only sent on success; if the
+/// escrow cannot be created, the transfer is reverted with it.
+fn allowance_pulled(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let pending = PENDING_CREATE.load(deps.storage)?;
+    PENDING_CREATE.remove(deps.storage);
+
+    execute_create(deps, env, pending.msg, Balance::Cw20(pending.deposit), &pending.owner)
+}
+
+/// ibc_transfer_sent records the sequence of an IBC transfer, to match the
+/// report of its outcome
+fn ibc_transfer_sent(
+    deps: DepsMut,
+    payout: Payout,
+    data: Option<Binary>,
+) -> Result<Response, ContractError> {
+    let (channel_id, amount) = match payout.msg {
+        CosmosMsg::Ibc(IbcMsg::Transfer { channel_id, amount, .. }) => (channel_id, amount),
+        _ => return Ok(Response::default()),
+    };
+    let sequence = data
+        .as_deref()
+        .and_then(transfer_sequence)
+        .ok_or(ContractError::MissingIbcSequence {})?;
+
+    let escrow = escrows().load(deps.storage, &payout.escrow)?;
+    let fallback = escrow.ibc_destination.map(|d| d.fallback).unwrap_or(escrow.user_b);
+    ibc_transfers().save(deps.storage, (&channel_id, sequence), &IbcTransfer {
+        escrow: payout.escrow.clone(),
+        fallback,
+        amount,
+    })?;
+
+    Ok(Response::new().add_attributes(vec![
+        ("action", "ibc_transfer".to_string()),
+        ("id", payout.escrow),
+        ("channel", channel_id),
+        ("sequence", sequence.to_string()),
+    ]))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn sudo(deps: DepsMut, env: Env, msg: SudoMsg) -> Result<Response, ContractError> {
+    match msg {
+        SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcAck { channel, sequence, success, .. }) => {
+            let outcome = if success { "acknowledged" } else { "failed" };
+            sudo_ibc_complete(deps, env, channel, sequence, outcome)
+        }
+        SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcTimeout { channel, sequence }) => {
+            sudo_ibc_complete(deps, env, channel, sequence, "timed_out")
+        }
+    }
+}
+
+/// sudo_ibc_complete settles an IBC transfer. Unless it was acknowledged,
+/// the coins came back to the contract and are paid to the fallback.
+fn sudo_ibc_complete(
+    deps: DepsMut,
+    env: Env,
+    channel: String,
+    sequence: u64,
+    outcome: &str,
+) -> Result<Response, ContractError> {
+    // this fails if the transfer is unknown or already settled
+    let transfer = ibc_transfers().load(deps.storage, (&channel, sequence))?;
+    ibc_transfers().remove(deps.storage, (&channel, sequence))?;
+
+    let mut res = Response::new().add_attributes(vec![
+        ("action", "ibc_lifecycle"),
+        ("id", transfer.escrow.as_str()),
+        ("channel", channel.as_str()),
+        ("sequence", sequence.to_string().as_str()),
+        ("outcome", outcome),
+    ]);
+    if outcome != "acknowledged" {
+        let refund = SubMsg::new(BankMsg::Send {
+            to_address: transfer.fallback.to_string(),
+            amount: vec![transfer.amount],
+        });
+        res = res.add_submessages(track_payouts(deps.storage, &env, &transfer.escrow, vec![refund])?);
+    }
+    Ok(res)
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
     deps: DepsMut,
@@ -47,10 +257,75 @@ pub fn execute(
     info: MessageInfo,
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
+    // while paused, only the messages bringing new funds in are refused
+    let deposit = matches!(
+        msg,
+        ExecuteMsg::Create(_)
+            | ExecuteMsg::BatchCreate { .. }
+            | ExecuteMsg::TopUp { .. }
+            | ExecuteMsg::Contribute { .. }
+            | ExecuteMsg::CreateFromAllowance { .. }
+            | ExecuteMsg::Receive(_)
+    );
+    if deposit && CONFIG.load(deps.storage)?.paused {
+        return Err(ContractError::Paused {});
+    }
+
     match msg {
         ExecuteMsg::Create(msg)=>execute_create(deps, env, msg, Balance::from(info.funds), &info.sender),
-        ExecuteMsg::Withdraw(msg)  => execute_withdraw(deps, env, msg),
+        ExecuteMsg::Accept{id} => {
+            execute_transition(deps, env, id, EscrowStatus::Accepted, &info.sender)
+        },
+        ExecuteMsg::Start{id} => {
+            execute_transition(deps, env, id, EscrowStatus::Started, &info.sender)
+        },
+        ExecuteMsg::Withdraw(msg)  => execute_withdraw(deps, env, msg, &info.sender),
         ExecuteMsg::Cancel{id} => execute_cancel(deps, env, id, &info.sender),
+        ExecuteMsg::Refund{id} => execute_refund(deps, env, id, &info.sender),
+        ExecuteMsg::ApproveSettlement{id, to_user_a, to_user_b} => {
+            execute_approve_settlement(deps, env, id, to_user_a, to_user_b, &info.sender)
+        },
+        ExecuteMsg::TopUp{id} => execute_top_up(deps, env, id, Balance::from(info.funds), &info.sender),
+        ExecuteMsg::Contribute{id} => {
+            execute_contribute(deps, env, id, Balance::from(info.funds), &info.sender)
+        },
+        ExecuteMsg::Dispute{id} => execute_dispute(deps, env, id, &info.sender),
+        ExecuteMsg::Resolve{id, to_user_a, to_user_b} => {
+            execute_resolve(deps, env, id, to_user_a, to_user_b, &info.sender)
+        },
+        ExecuteMsg::UpdateAllowlist{allowed_native, allowed_cw20} => {
+            execute_update_allowlist(deps, allowed_native, allowed_cw20, &info.sender)
+        },
+        ExecuteMsg::TransferAdmin{admin} => execute_transfer_admin(deps, admin, &info.sender),
+        ExecuteMsg::AcceptAdmin{} => execute_accept_admin(deps, &info.sender),
+        ExecuteMsg::Pause{} => execute_set_paused(deps, true, &info.sender),
+        ExecuteMsg::Unpause{} => execute_set_paused(deps, false, &info.sender),
+        ExecuteMsg::BatchCreate{items, skip_failures} => {
+            execute_batch_create(deps, env, items, info.funds, skip_failures, &info.sender)
+        },
+        ExecuteMsg::BatchWithdraw{items, skip_failures} => {
+            run_batch(deps, "batch_withdraw", items, skip_failures, |deps, msg| {
+                (Some(msg.id.clone()), execute_withdraw(deps, env.clone(), msg, &info.sender))
+            })
+        },
+        ExecuteMsg::BatchCancel{ids, skip_failures} => {
+            run_batch(deps, "batch_cancel", ids, skip_failures, |deps, id| {
+                (Some(id.clone()), execute_cancel(deps, env.clone(), id, &info.sender))
+            })
+        },
+        ExecuteMsg::RetryPayout{id} => execute_retry_payout(deps, env, id),
+        ExecuteMsg::RecoverPayouts{id} => execute_recover_payouts(deps, env, id, &info.sender),
+        ExecuteMsg::SetPayoutMode{mode} => execute_set_payout_mode(deps, mode, &info.sender),
+        ExecuteMsg::Claim{tokens} => execute_claim(deps, tokens, &info.sender),
+        ExecuteMsg::Prune{ids, before, limit, reuse_ids} => {
+            execute_prune(deps, env, ids, before, limit, reuse_ids, &info.sender)
+        },
+        ExecuteMsg::UpdateRelayers{relayers} => execute_update_relayers(deps, relayers, &info.sender),
+        ExecuteMsg::ApproveRelayer{relayer} => execute_approve_relayer(deps, relayer, true, &info.sender),
+        ExecuteMsg::RevokeRelayer{relayer} => execute_approve_relayer(deps, relayer, false, &info.sender),
+        ExecuteMsg::CreateFromAllowance{token, amount, owner, msg} => {
+            execute_create_from_allowance(deps, env, token, amount, owner, msg, &info.sender)
+        },
         ExecuteMsg::Receive(msg) => execute_receive(deps, env, info, msg),
     }
 }
@@ -71,529 +346,4392 @@ pub fn execute_receive(
         ReceiveMsg::Create(msg) => {
             execute_create(deps, env,  msg, balance, &api.addr_validate(&wrapper.sender)?)
         },
+        ReceiveMsg::TopUp{id} => {
+            execute_top_up(deps, env, id, balance, &api.addr_validate(&wrapper.sender)?)
+        },
+        ReceiveMsg::Contribute{id} => {
+            execute_contribute(deps, env, id, balance, &api.addr_validate(&wrapper.sender)?)
+        },
     }
 }
 
 pub fn execute_create(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     msg: CreateMsg,
     balance: Balance,
     sender: &Addr,
 ) -> Result<Response, ContractError> {
   
+    let config = CONFIG.load(deps.storage)?;
+    config.check_allowed(&balance)?;
+    if let Some(expected) = msg.expected_amount {
+        check_deposit_amount(&balance, expected)?;
+    }
+
     let user_b_addr = deps.api.addr_validate(&msg.user_b)?;
+    let arbiter_addr = msg.arbiter
+        .map(|arbiter| deps.api.addr_validate(&arbiter))
+        .transpose()?;
+    let ibc_destination = msg.ibc_destination
+        .map(|d| -> StdResult<_> {
+            Ok(IbcDestination {
+                channel_id: d.channel_id,
+                remote_address: d.remote_address,
+                timeout_seconds: d.timeout_seconds,
+                fallback: match d.fallback {
+                    Some(fallback) => deps.api.addr_validate(&fallback)?,
+                    None => user_b_addr.clone(),
+                },
+            })
+        })
+        .transpose()?;
+    let nonce = NONCE_COUNT.may_load(deps.storage)?.unwrap_or_default() + 1;
 
     let escrow = Escrow::create(
         sender.clone(),
         user_b_addr,
         balance,
         &msg.lock,
-    )?;
+        msg.lock_kind,
+        msg.expires_at,
+        arbiter_addr,
+        env.block.time,
+    )?
+    .with_cancellation_fee(msg.cancellation_fee)?
+    .with_target(msg.target)?
+    .with_nonce(nonce)
+    .with_payout_msg(msg.payout_msg)
+    .with_ibc_destination(ibc_destination)?
+    .with_milestones(msg.milestones
+        .into_iter()
+        .map(|m| Milestone {
+            amount: m.amount,
+            lock: m.lock,
+            lock_kind: m.lock_kind,
+            released: false,
+        })
+        .collect())?;
+
+    if escrow.is_expired(&env.block) {
+        return Err(ContractError::Expired {});
+    }
+
+    let id = match msg.id {
+        Some(id) => {
+            validate_id(&id)?;
+            id
+        }
+        None => next_escrow_id(deps.storage)?,
+    };
+
+    // the id of a pruned escrow stays taken unless it was released
+    if is_reserved(deps.storage, &id)? {
+        return Err(ContractError::AlreadyInUse {});
+    }
 
     // try to store it, fail if the id was already in use
-    ESCROWS.update(deps.storage, &msg.id, |existing| match existing {
-        None => Ok(escrow),
+    escrows().update(deps.storage, &id, |existing| match existing {
+        None => Ok(escrow.clone()),
         Some(_) => Err(ContractError::AlreadyInUse {}),
     })?;
+    NONCE_COUNT.save(deps.storage, &nonce)?;
+
+    // the creator funds the first share of a shared fare
+    if escrow.target.is_some() {
+        let amount = single_amount(&escrow.deposit).unwrap_or_default();
+        CONTRIBUTIONS.save(deps.storage, (&id, sender), &amount)?;
+    }
+    update_stats(deps.storage, |stats| stats.add_escrow(&escrow.deposit))?;
 
-    let res = Response::new().add_attributes(vec![("action", "create"), ("id", msg.id.as_str())]);
+    let res = Response::new()
+        .add_attributes(vec![("action", "create"), ("id", id.as_str())])
+        .add_event(status_event(&id, &escrow))
+        .set_data(to_binary(&CreateResponse { id })?);
     Ok(res)
 }
 
-
-pub fn execute_withdraw(
+pub fn execute_create_from_allowance(
     deps: DepsMut,
-    _env: Env,
-    msg: WithdrawMsg,
+    env: Env,
+    token: String,
+    amount: Uint128,
+    owner: String,
+    msg: CreateMsg,
+    sender: &Addr,
 ) -> Result<Response, ContractError> {
-    // this fails if no escrow there
-    let mut escrow = ESCROWS.load(deps.storage, &msg.id)?;
+    let config = CONFIG.load(deps.storage)?;
+    if !config.relayers.contains(sender) {
+        return Err(ContractError::Unauthorized {});
+    }
 
-    if escrow.closed {
-        return Err(ContractError::Closed {  });
+    // fail before pulling a deposit that would be refused
+    let deposit = Cw20CoinVerified {
+        address: deps.api.addr_validate(&token)?,
+        amount,
+    };
+    config.check_allowed(&Balance::Cw20(deposit.clone()))?;
+    let owner = deps.api.addr_validate(&owner)?;
+    // the allowance is only spent by relayers the owner trusts
+    if !RELAYER_APPROVALS.has(deps.storage, (&owner, sender)) {
+        return Err(ContractError::Unauthorized {});
     }
-        
-    escrow.unlock(&msg.secret)?;
 
-    escrow.close();
-    
-    ESCROWS.save(deps.storage, &msg.id, &escrow)?;
+    // the escrow is created in the reply, once the deposit is in
+    let transfer = WasmMsg::Execute {
+        contract_addr: deposit.address.to_string(),
+        msg: to_binary(&Cw20ExecuteMsg::TransferFrom {
+            owner: owner.to_string(),
+            recipient: env.contract.address.to_string(),
+            amount,
+        })?,
+        funds: vec![],
+    };
+    PENDING_CREATE.save(deps.storage, &PendingCreate {
+        owner: owner.clone(),
+        deposit,
+        msg,
+    })?;
+
+    let res = Response::new()
+        .add_attributes(vec![
+            ("action", "create_from_allowance"),
+            ("owner", owner.as_str()),
+            ("relayer", sender.as_str()),
+        ])
+        .add_submessage(SubMsg::reply_on_success(transfer, ALLOWANCE_REPLY_ID));
+    Ok(res)
+}
+
+pub fn execute_approve_relayer(
+    deps: DepsMut,
+    relayer: String,
+    approved: bool,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    let relayer = deps.api.addr_validate(&relayer)?;
+    let action = if approved {
+        RELAYER_APPROVALS.save(deps.storage, (sender, &relayer), &Empty {})?;
+        "approve_relayer"
+    } else {
+        RELAYER_APPROVALS.remove(deps.storage, (sender, &relayer));
+        "revoke_relayer"
+    };
 
-    let payments = create_payment_submsgs(escrow.deposit, escrow.user_b).unwrap();
-    
     let res = Response::new().add_attributes(vec![
-        ("action", "withdraw"),
-        ("id", &msg.id.as_str()),
-    ]).add_submessages(payments);
-        
+        ("action", action),
+        ("owner", sender.as_str()),
+        ("relayer", relayer.as_str()),
+    ]);
     Ok(res)
 }
 
-pub fn execute_cancel(
+pub fn execute_retry_payout(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
+    id: String,
+) -> Result<Response, ContractError> {
+    // this fails is no escrow there
+    let mut escrow = escrows().load(deps.storage, &id)?;
+
+    // anyone can retry, the payouts go to the same recipients
+    let msgs = escrow.retry_payouts(env.block.time)?;
+
+    escrows().save(deps.storage, &id, &escrow)?;
+    let event = status_event(&id, &escrow);
+
+    let payments = msgs.into_iter().map(SubMsg::new).collect();
+
+    let res = Response::new().add_attributes(vec![
+        ("action", "retry_payout"),
+        ("id", id.as_str()),
+    ])
+    .add_event(event)
+    .add_submessages(track_payouts(deps.storage, &env, &id, payments)?);
+
+    Ok(res)
+}
+
+pub fn execute_set_payout_mode(
+    deps: DepsMut,
+    mode: PayoutMode,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // funds credited so far stay claimable when switching back to transfer
+    PAYOUT_MODES.save(deps.storage, sender, &mode)?;
+
+    let res = Response::new().add_attributes(vec![
+        ("action", "set_payout_mode"),
+        ("user", sender.as_str()),
+        ("mode", mode.to_string().as_str()),
+    ]);
+    Ok(res)
+}
+
+pub fn execute_recover_payouts(
+    deps: DepsMut,
+    env: Env,
     id: String,
     sender: &Addr,
 ) -> Result<Response, ContractError> {
     // this fails is no escrow there
-    let mut escrow = ESCROWS.load(deps.storage, &id)?;
+    let mut escrow = escrows().load(deps.storage, &id)?;
+    let status = escrow.status;
 
-    if escrow.closed {
-        return Err(ContractError::Closed {  });
+    let msgs = escrow.take_failed_payouts(
+        |msg| matches!(payout_of(msg), Some((recipient, _)) if recipient == sender.as_str()),
+        env.block.time,
+    )?;
+    if msgs.is_empty() {
+        return Err(ContractError::Unauthorized {});
     }
+    escrows().save(deps.storage, &id, &escrow)?;
 
-    if sender != escrow.user_a {
-        return Err(ContractError::InvalidUser { });
+    let mut claimable = CLAIMABLE.may_load(deps.storage, sender)?.unwrap_or_default();
+    for (_, balance) in msgs.iter().filter_map(payout_of) {
+        claimable.add(&balance);
     }
+    CLAIMABLE.save(deps.storage, sender, &claimable)?;
 
-    escrow.close();
-    
-    ESCROWS.save(deps.storage, &id, &escrow)?;
-
-    let payments = create_payment_submsgs(escrow.deposit, escrow.user_a).unwrap();
-    
-    let res = Response::new().add_attributes(vec![
-        ("action", "cancel"),
-        ("id", &id.as_str()),
-    ]).add_submessages(payments);
-        
+    let mut res = Response::new().add_attributes(vec![
+        ("action", "recover_payouts"),
+        ("id", id.as_str()),
+        ("user", sender.as_str()),
+    ]);
+    if escrow.status != status {
+        res = res.add_event(status_event(&id, &escrow));
+    }
     Ok(res)
 }
 
-pub fn create_payment_submsgs(deposit: Balance, recipient: Addr) -> StdResult<Vec<SubMsg>> {
-    let mut msgs: Vec<SubMsg> = vec![];
-    
-    match deposit {
-        Balance::Cw20(token) => {
-            let payoff_msg = Cw20ExecuteMsg::Transfer {
-                recipient: recipient.to_string(),
-                amount:  token.amount,
-            };
-            let payoff_exec = SubMsg::new(WasmMsg::Execute {
-                contract_addr: token.address.to_string(),
-                msg: to_binary(&payoff_msg)?,
-                funds: vec![],
-            });
-            msgs.push(payoff_exec);   
+/// payout_of returns the recipient and the funds of a payout, as built by
+/// create_payment_submsgs or create_notify_submsgs
+fn payout_of(msg: &CosmosMsg) -> Option<(String, Balance)> {
+    match msg {
+        CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
+            Some((to_address.clone(), Balance::from(amount.clone())))
         }
-        Balance::Native(native_balance) => {
-            let payoff_msg = SubMsg::new(BankMsg::Send {
-                to_address: recipient.to_string(),
-                amount: native_balance.into_vec(),
-            });
-            msgs.push(payoff_msg);
+        CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, funds, .. }) if !funds.is_empty() => {
+            Some((contract_addr.clone(), Balance::from(funds.clone())))
+        }
+        CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+            let (recipient, amount) = match from_binary(msg).ok()? {
+                Cw20ExecuteMsg::Transfer { recipient, amount } => (recipient, amount),
+                Cw20ExecuteMsg::Send { contract, amount, .. } => (contract, amount),
+                _ => return None,
+            };
+            let token = Cw20CoinVerified {
+                address: Addr::unchecked(contract_addr),
+                amount,
+            };
+            Some((recipient, Balance::Cw20(token)))
         }
+        _ => None,
     }
-    Ok(msgs)
 }
 
+pub fn execute_claim(
+    deps: DepsMut,
+    tokens: Vec<Denom>,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    let mut claimable = CLAIMABLE.may_load(deps.storage, sender)?.unwrap_or_default();
+    let balances = claimable.take(&tokens);
+    if balances.is_empty() {
+        return Err(ContractError::NothingToClaim {});
+    }
+
+    if claimable.is_empty() {
+        CLAIMABLE.remove(deps.storage, sender);
+    } else {
+        CLAIMABLE.save(deps.storage, sender, &claimable)?;
+    }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::List {} => to_binary(&query_list(deps)?),
-        QueryMsg::Details { id } => to_binary(&query_details(deps, id)?),
+    let mut payments = vec![];
+    for balance in balances {
+        payments.extend(create_payment_submsgs(balance, sender.clone())?);
     }
+
+    let res = Response::new()
+        .add_attributes(vec![
+            ("action", "claim"),
+            ("user", sender.as_str()),
+        ])
+        .add_submessages(payments);
+    Ok(res)
 }
 
-fn query_details(deps: Deps, id: String) -> StdResult<DetailsResponse> {
-    let escrow = ESCROWS.load(deps.storage, &id)?;
+#[allow(clippy::too_many_arguments)]
+pub fn execute_prune(
+    deps: DepsMut,
+    env: Env,
+    ids: Vec<String>,
+    before: Option<Timestamp>,
+    limit: Option<u32>,
+    reuse_ids: bool,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if *sender != config.admin {
+        return Err(ContractError::Unauthorized {});
+    }
 
-    let details = DetailsResponse {
-        id,
-        user_a: escrow.user_a.to_string(),
-        user_b: escrow.user_b.to_string(),
-        deposit: escrow.deposit,
-        lock: escrow.lock,
-        closed: escrow.closed,
+    let ids = match (ids.is_empty(), before) {
+        (false, None) => ids,
+        (true, Some(before)) => settled_before(deps.storage, before, page_limit(limit))?,
+        _ => return Err(ContractError::InvalidPrune {}),
     };
 
-    Ok(details)
+    let mut res = Response::new()
+        .add_attributes(vec![
+            ("action", "prune".to_string()),
+            ("pruned", ids.len().to_string()),
+        ])
+        .add_event(admin_event("prune", sender).add_attribute("pruned", ids.len().to_string()));
+    for id in ids {
+        // this fails is no escrow there
+        let escrow = escrows().load(deps.storage, &id)?;
+        // funds of unsettled escrows are still held by the contract, or may
+        // come back to it from an IBC transfer
+        if !escrow.is_settled() || has_ibc_transfers(deps.storage, &id)? {
+            return Err(ContractError::NotSettled { id });
+        }
+        prune_escrow(deps.storage, &env, &id, escrow, !reuse_ids)?;
+        res = res.add_event(Event::new("escrow_pruned").add_attribute("id", id));
+    }
+    Ok(res)
 }
 
-fn query_list(deps: Deps) -> StdResult<ListResponse> {
-    Ok(ListResponse {
-        escrows: all_escrow_ids(deps.storage)?,
-    })
+/// prune_escrow replaces a settled escrow and its contributions with an
+/// archive record
+fn prune_escrow(
+    storage: &mut dyn Storage,
+    env: &Env,
+    id: &str,
+    escrow: Escrow,
+    reserved: bool,
+) -> StdResult<()> {
+    let index = ARCHIVE_COUNT.may_load(storage)?.unwrap_or_default() + 1;
+    ARCHIVE_COUNT.save(storage, &index)?;
+    archive().save(storage, index, &ArchivedEscrow {
+        id: id.to_string(),
+        closed_at: escrow.closed_at().unwrap_or_default(),
+        user_a: escrow.user_a,
+        user_b: escrow.user_b,
+        deposit: escrow.deposit,
+        outcome: escrow.status,
+        paid: escrow.paid,
+        pruned_at: env.block.height,
+        reserved,
+    })?;
+
+    escrows().remove(storage, id)?;
+    for (contributor, _) in contributions(storage, id, None, usize::MAX)? {
+        CONTRIBUTIONS.remove(storage, (id, &contributor));
+    }
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
-    use cosmwasm_std::Uint128;
-    use cw_utils::NativeBalance;
+pub fn execute_batch_create(
+    deps: DepsMut,
+    env: Env,
+    items: Vec<BatchCreateItem>,
+    funds: Vec<Coin>,
+    skip_failures: bool,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // the amounts are given by the sender, their sum may overflow
+    let total = items
+        .iter()
+        .try_fold(Uint128::zero(), |total, item| total.checked_add(item.amount))
+        .map_err(|_| ContractError::InvalidBatchDeposit {})?;
+    let denom = match funds.as_slice() {
+        [coin] if coin.amount == total => coin.denom.clone(),
+        _ => return Err(ContractError::InvalidBatchDeposit {}),
+    };
 
-    use super::*;
+    // the deposit of the skipped items goes back to the sender
+    let mut refund = Uint128::zero();
+    let res = run_batch(deps, "batch_create", items, skip_failures, |deps, item| {
+        let balance = Balance::from(vec![Coin { denom: denom.clone(), amount: item.amount }]);
+        let id = item.escrow.id.clone();
+        match execute_create(deps, env.clone(), item.escrow, balance, sender) {
+            Ok(res) => {
+                // the assigned id is only known from the response
+                let id = res.data.as_ref()
+                    .and_then(|data| from_binary::<CreateResponse>(data).ok())
+                    .map(|data| data.id)
+                    .or(id);
+                (id, Ok(res))
+            }
+            Err(err) => {
+                refund += item.amount;
+                (id, Err(err))
+            }
+        }
+    })?;
 
-    const ESCROW_ID: &str ="foobar";
-    const USER_A_ADDR: &str= "user_a";  
-    const USER_B_ADDR : &str = "user_b";
-    const REQUIRED_NATIVE_TOKEN_DENOM: &str = "token";
-    const REQUIRED_CW20_TOKEN_ADDR: &str = "the_cw20_token";
-    const REQUIRED_TOKEN_AMOUNT: u128 =  100;
-    const LOCK_A: &str = "0330347c5cb0f1627bdd2e7b082504a443b2bf50ad2e3efbb4e754ebd687c78c24";
-    const SECRET_A: &str =  "27874aa2b70ce7281c94413c36d44fac6fa6a1198f2c529188c4dd4f7a4e1870"; 
+    let refund = Balance::from(vec![Coin { denom, amount: refund }]);
+    Ok(res.add_submessages(create_payment_submsgs(refund, sender.clone())?))
+}
 
-    fn get_instantiate_msg() -> (MessageInfo, InstantiateMsg) {
-        let instantiate_msg = InstantiateMsg {};
-        let info = mock_info(&String::from("anyone"), &[]);
-        return (info, instantiate_msg);
+/// run_batch applies handle to every item. By default the first failure
+/// aborts the whole batch. With skip_failures, the failed items are only
+/// reported in the response data; each item runs on a storage cache that is
+/// only committed on success, so a failed item leaves no state behind. The
+/// attributes of each item are kept in a batch_item event.
+fn run_batch<T>(
+    deps: DepsMut,
+    action: &str,
+    items: Vec<T>,
+    skip_failures: bool,
+    mut handle: impl FnMut(DepsMut, T) -> (Option<String>, Result<Response, ContractError>),
+) -> Result<Response, ContractError> {
+    let mut res = Response::new();
+    let mut results = vec![];
+    for item in items {
+        let mut cache = StorageCache::new(deps.storage);
+        let item_deps = DepsMut { storage: &mut cache, api: deps.api, querier: deps.querier };
+        let (id, result) = handle(item_deps, item);
+        match result {
+            Ok(item_res) => {
+                cache.commit();
+                res = res
+                    .add_submessages(item_res.messages)
+                    .add_event(Event::new("batch_item").add_attributes(item_res.attributes))
+                    .add_events(item_res.events);
+                results.push(BatchResult { id, error: None });
+            }
+            Err(err) if skip_failures => {
+                results.push(BatchResult { id, error: Some(err.to_string()) });
+            }
+            Err(err) => return Err(err),
+        }
     }
 
-    fn get_create_msg(
-        sender_addr: String, 
-        escrow_id: String,
-        user_b_addr: String,
-        lock: String) -> (MessageInfo, ExecuteMsg) {
+    let failed = results.iter().filter(|result| result.error.is_some()).count();
+    let res = res
+        .add_attributes(vec![
+            ("action", action.to_string()),
+            ("succeeded", (results.len() - failed).to_string()),
+            ("failed", failed.to_string()),
+        ])
+        .set_data(to_binary(&BatchResponse { results })?);
+    Ok(res)
+}
 
-        let create_msg = CreateMsg {
-            id: escrow_id,
-            user_b: user_b_addr,
-            lock: lock,
-        };
-        let msg = ExecuteMsg::Create(create_msg.clone());
-        let balance = coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM);
-        let info = mock_info(&sender_addr, &balance);
-        return (info, msg);
+pub fn execute_update_allowlist(
+    deps: DepsMut,
+    allowed_native: Vec<String>,
+    allowed_cw20: Vec<String>,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if *sender != config.admin {
+        return Err(ContractError::Unauthorized {});
     }
 
-    fn get_receive_create_msg(
-        sender_addr: String,
-        escrow_id: String,
-        user_b_addr: String,
-        lock: String,
-        required_token_addr: String,
-        required_token_amount: u128 ) -> (MessageInfo, ExecuteMsg){
+    config.allowed_native = allowed_native;
+    config.allowed_cw20 = validate_addrs(deps.api, &allowed_cw20)?;
+    CONFIG.save(deps.storage, &config)?;
 
-        let create = CreateMsg {
-            id: escrow_id,
+    let event = admin_event("update_allowlist", sender)
+        .add_attribute("allowed_native", config.allowed_native.join(","))
+        .add_attribute(
+            "allowed_cw20",
+            config.allowed_cw20.iter().map(Addr::as_str).collect::<Vec<_>>().join(","),
+        );
+    Ok(Response::new().add_attribute("action", "update_allowlist").add_event(event))
+}
+
+pub fn execute_update_relayers(
+    deps: DepsMut,
+    relayers: Vec<String>,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if *sender != config.admin {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    config.relayers = validate_addrs(deps.api, &relayers)?;
+    CONFIG.save(deps.storage, &config)?;
+
+    let event = admin_event("update_relayers", sender).add_attribute(
+        "relayers",
+        config.relayers.iter().map(Addr::as_str).collect::<Vec<_>>().join(","),
+    );
+    Ok(Response::new().add_attribute("action", "update_relayers").add_event(event))
+}
+
+pub fn execute_transfer_admin(
+    deps: DepsMut,
+    admin: String,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if *sender != config.admin {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    // a new proposal replaces the previous one
+    let pending_admin = deps.api.addr_validate(&admin)?;
+    config.pending_admin = Some(pending_admin.clone());
+    CONFIG.save(deps.storage, &config)?;
+
+    let event = admin_event("transfer_admin", sender)
+        .add_attribute("pending_admin", pending_admin.as_str());
+    Ok(Response::new().add_attribute("action", "transfer_admin").add_event(event))
+}
+
+pub fn execute_accept_admin(
+    deps: DepsMut,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if config.pending_admin.as_ref() != Some(sender) {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let previous_admin = std::mem::replace(&mut config.admin, sender.clone());
+    config.pending_admin = None;
+    CONFIG.save(deps.storage, &config)?;
+
+    let event = admin_event("accept_admin", sender)
+        .add_attribute("previous_admin", previous_admin.as_str());
+    Ok(Response::new().add_attribute("action", "accept_admin").add_event(event))
+}
+
+pub fn execute_set_paused(
+    deps: DepsMut,
+    paused: bool,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if *sender != config.admin {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    config.paused = paused;
+    CONFIG.save(deps.storage, &config)?;
+
+    let action = if paused { "pause" } else { "unpause" };
+    Ok(Response::new()
+        .add_attribute("action", action)
+        .add_event(admin_event(action, sender)))
+}
+
+/// admin_event records an action of the admin
+fn admin_event(action: &str, admin: &Addr) -> Event {
+    Event::new("escrow_admin")
+        .add_attribute("action", action)
+        .add_attribute("admin", admin.as_str())
+}
+
+pub fn validate_addrs(api: &dyn Api, addrs: &[String]) -> StdResult<Vec<Addr>> {
+    addrs.iter().map(|addr| api.addr_validate(addr)).collect()
+}
+
+/// validate_id checks the format of an id chosen by the creator
+fn validate_id(id: &str) -> Result<(), ContractError> {
+    let invalid = |reason: &str| Err(ContractError::InvalidId { reason: reason.to_string() });
+
+    if id.len() < MIN_ID_LENGTH || id.len() > MAX_ID_LENGTH {
+        return invalid("must be 3-20 bytes long");
+    }
+    if id.trim() != id {
+        return invalid("must not start or end with whitespace");
+    }
+    if id.chars().any(char::is_control) {
+        return invalid("must not contain control characters");
+    }
+    // numeric ids are assigned by the contract
+    if id.bytes().all(|b| b.is_ascii_digit()) {
+        return invalid("must not be numeric");
+    }
+    Ok(())
+}
+
+/// next_escrow_id assigns the next number from the counter, skipping any id
+/// already taken by an escrow created before ids were validated, or kept by
+/// a pruned escrow
+fn next_escrow_id(storage: &mut dyn Storage) -> StdResult<String> {
+    let mut count = ESCROW_COUNT.may_load(storage)?.unwrap_or_default();
+    loop {
+        count += 1;
+        let id = count.to_string();
+        if !escrows().has(storage, &id) && !is_reserved(storage, &id)? {
+            ESCROW_COUNT.save(storage, &count)?;
+            return Ok(id);
+        }
+    }
+}
+
+/// execute_transition moves the ride forward when the driver accepts or
+/// starts it
+pub fn execute_transition(
+    deps: DepsMut,
+    env: Env,
+    id: String,
+    status: EscrowStatus,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // this fails if no escrow there
+    let mut escrow = escrows().load(deps.storage, &id)?;
+
+    if escrow.is_closed() {
+        return Err(ContractError::Closed {  });
+    }
+
+    if escrow.is_disputed() {
+        return Err(ContractError::Disputed {  });
+    }
+
+    if escrow.is_expired(&env.block) {
+        return Err(ContractError::Expired {  });
+    }
+
+    if *sender != escrow.user_b {
+        return Err(ContractError::NotRecipient {  });
+    }
+
+    escrow.set_status(status, env.block.time)?;
+
+    escrows().save(deps.storage, &id, &escrow)?;
+
+    let action = match status {
+        EscrowStatus::Accepted => "accept",
+        _ => "start",
+    };
+    let res = Response::new().add_attributes(vec![
+        ("action", action),
+        ("id", id.as_str()),
+    ])
+    .add_event(status_event(&id, &escrow));
+
+    Ok(res)
+}
+
+pub fn execute_withdraw(
+    deps: DepsMut,
+    env: Env,
+    msg: WithdrawMsg,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // this fails if no escrow there
+    let mut escrow = escrows().load(deps.storage, &msg.id)?;
+
+    if escrow.is_closed() {
+        return Err(ContractError::Closed {  });
+    }
+
+    if escrow.is_disputed() {
+        return Err(ContractError::Disputed {  });
+    }
+
+    // the recipient can only claim the deposit before the expiry
+    if escrow.is_expired(&env.block) {
+        return Err(ContractError::Expired {  });
+    }
+
+    // a shared fare is paid out once every rider contributed
+    if !escrow.is_funded() {
+        return Err(EscrowError::NotFunded {}.into());
+    }
+
+    escrow.check_milestone(msg.milestone)?;
+
+    match (&msg.secret, &msg.signature) {
+        (Some(secret), None) => escrow.unlock(msg.milestone, secret)?,
+        (None, Some(signature)) => {
+            let payload = withdraw_payload(
+                &env.contract.address,
+                &msg.id,
+                escrow.nonce,
+                &escrow.user_b,
+                msg.milestone,
+                msg.payout_msg.as_ref(),
+            )?;
+            escrow.verify_signature(deps.api, msg.milestone, &payload, signature)?;
+        }
+        _ => return Err(EscrowError::NoSecret {}.into()),
+    }
+
+    // a payout_msg that would not reach user_b is refused, not dropped
+    if msg.payout_msg.is_some() {
+        let mode = PAYOUT_MODES.may_load(deps.storage, &escrow.user_b)?.unwrap_or_default();
+        if escrow.ibc_destination.is_some() || mode == PayoutMode::Ledger {
+            return Err(ContractError::PayoutMsgNotDelivered {});
+        }
+    }
+
+    let tranche = escrow.release(msg.milestone)?;
+    // the escrow stays open until the last milestone is released
+    let completed = escrow.is_fully_released();
+    if completed {
+        escrow.set_status(EscrowStatus::Completed, env.block.time)?;
+    }
+    let config = CONFIG.load(deps.storage)?;
+    record_paid(&mut escrow, &config, &Balance::default(), &tranche)?;
+    
+    escrows().save(deps.storage, &msg.id, &escrow)?;
+    let event = status_event(&msg.id, &escrow);
+    update_stats(deps.storage, |stats| {
+        stats.release(&tranche);
+        if completed {
+            stats.close(escrow.status);
+        }
+    })?;
+
+    // anyone who saw the secret could send it with their own payout_msg, so
+    // it is only taken when signed or sent by the recipient
+    let payout_msg = match (&msg.payout_msg, &msg.signature) {
+        (Some(payout_msg), Some(_)) => Some(payout_msg),
+        (Some(payout_msg), None) if *sender == escrow.user_b => Some(payout_msg),
+        _ => escrow.payout_msg.as_ref(),
+    };
+    let payments = match (&escrow.ibc_destination, &tranche) {
+        (Some(destination), Balance::Native(_)) => {
+            create_ibc_withdraw_submsgs(deps.storage, &env, &config, &tranche, destination)?
+        }
+        _ => create_withdraw_submsgs(deps.storage, &config, &tranche, escrow.user_b, payout_msg)?,
+    };
+    
+    let mut res = Response::new().add_attributes(vec![
+        ("action", "withdraw"),
+        ("id", msg.id.as_str()),
+    ])
+    .add_submessages(track_payouts(deps.storage, &env, &msg.id, payments)?);
+    if let Some(milestone) = msg.milestone {
+        res = res.add_attribute("milestone", milestone.to_string());
+    }
+    if completed {
+        res = res.add_event(event);
+    }
+        
+    Ok(res)
+}
+
+/// withdraw_payload returns the bytes to sign with the lock key to withdraw
+/// the escrow, or one of its milestones, without revealing the key. The
+/// payout_msg of the withdraw is signed too, so that whoever relays the
+/// signature cannot choose what user_b executes.
+pub fn withdraw_payload(
+    contract: &Addr,
+    id: &str,
+    nonce: u64,
+    recipient: &Addr,
+    milestone: Option<u32>,
+    payout_msg: Option<&Binary>,
+) -> StdResult<Vec<u8>> {
+    to_vec(&WithdrawPayload {
+        contract: contract.to_string(),
+        id: id.to_string(),
+        nonce,
+        recipient: recipient.to_string(),
+        milestone,
+        payout_msg: payout_msg.cloned(),
+    })
+}
+
+pub fn execute_cancel(
+    deps: DepsMut,
+    env: Env,
+    id: String,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // this fails is no escrow there
+    let mut escrow = escrows().load(deps.storage, &id)?;
+
+    if escrow.is_closed() {
+        return Err(ContractError::Closed {  });
+    }
+
+    if escrow.is_disputed() {
+        return Err(ContractError::Disputed {  });
+    }
+
+    if escrow.is_expired(&env.block) {
+        // after expiry, anyone can trigger the refund
+    } else if escrow.expires_at.is_none() && escrow.status != EscrowStatus::Started {
+        // without expiry, the creator can cancel until the ride starts
+        if sender != escrow.user_a {
+            return Err(ContractError::InvalidUser { });
+        }
+    } else {
+        // before expiry, or once the ride started, both users have to agree
+        // to cancel
+        escrow.approve_cancel(sender)?;
+        if !escrow.cancel_agreed() {
+            escrows().save(deps.storage, &id, &escrow)?;
+            let res = Response::new().add_attributes(vec![
+                ("action", "approve_cancel"),
+                ("id", id.as_str()),
+                ("user", sender.as_str()),
+            ]);
+            return Ok(res);
+        }
+    }
+
+    // the driver is compensated when the ride is cancelled after they
+    // accepted it, but not when they let it expire
+    let cancellation_fee = match escrow.is_expired(&env.block) {
+        true => None,
+        false => escrow.cancellation_fee_due().cloned(),
+    };
+
+    escrow.set_status(EscrowStatus::Cancelled, env.block.time)?;
+    // only the tranches not released yet are refunded
+    let (to_user_a, to_user_b) = cancel_split(&escrow.remaining(), cancellation_fee.as_ref());
+    let config = CONFIG.load(deps.storage)?;
+    record_paid(&mut escrow, &config, &to_user_a, &to_user_b)?;
+    
+    escrows().save(deps.storage, &id, &escrow)?;
+    let event = status_event(&id, &escrow);
+    close_stats(deps.storage, &escrow)?;
+
+    let payments = create_cancel_submsgs(deps.storage, &config, &id, &escrow, to_user_a, &to_user_b)?;
+    
+    let res = Response::new().add_attributes(vec![
+        ("action", "cancel"),
+        ("id", id.as_str()),
+    ])
+    .add_event(event)
+    .add_submessages(track_payouts(deps.storage, &env, &id, payments)?);
+        
+    Ok(res)
+}
+
+pub fn execute_refund(
+    deps: DepsMut,
+    env: Env,
+    id: String,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // this fails is no escrow there
+    let mut escrow = escrows().load(deps.storage, &id)?;
+
+    if escrow.is_closed() {
+        return Err(ContractError::Closed {  });
+    }
+
+    if escrow.is_disputed() {
+        return Err(ContractError::Disputed {  });
+    }
+
+    // the recipient can give up the deposit at any time
+    if *sender != escrow.user_b {
+        return Err(ContractError::NotRecipient {  });
+    }
+
+    escrow.set_status(EscrowStatus::Cancelled, env.block.time)?;
+    let refund = escrow.remaining();
+    escrow.record_paid(&refund, &Balance::default(), &Balance::default())?;
+
+    escrows().save(deps.storage, &id, &escrow)?;
+    let event = status_event(&id, &escrow);
+    close_stats(deps.storage, &escrow)?;
+
+    let payments = create_refund_submsgs(deps.storage, &id, &escrow, refund)?;
+
+    let res = Response::new().add_attributes(vec![
+        ("action", "refund"),
+        ("id", id.as_str()),
+    ])
+    .add_event(event)
+    .add_submessages(track_payouts(deps.storage, &env, &id, payments)?);
+
+    Ok(res)
+}
+
+pub fn execute_approve_settlement(
+    deps: DepsMut,
+    env: Env,
+    id: String,
+    to_user_a: Balance,
+    to_user_b: Balance,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // this fails is no escrow there
+    let mut escrow = escrows().load(deps.storage, &id)?;
+
+    if escrow.is_closed() {
+        return Err(ContractError::Closed {  });
+    }
+
+    if escrow.is_disputed() {
+        return Err(ContractError::Disputed {  });
+    }
+
+    if !escrow.approve_settlement(sender, to_user_a.clone(), to_user_b.clone())? {
+        escrows().save(deps.storage, &id, &escrow)?;
+        let res = Response::new().add_attributes(vec![
+            ("action", "approve_settlement"),
+            ("id", id.as_str()),
+            ("user", sender.as_str()),
+        ]);
+        return Ok(res);
+    }
+
+    escrow.set_status(EscrowStatus::Cancelled, env.block.time)?;
+    let config = CONFIG.load(deps.storage)?;
+    record_paid(&mut escrow, &config, &to_user_a, &to_user_b)?;
+
+    escrows().save(deps.storage, &id, &escrow)?;
+    let event = status_event(&id, &escrow);
+    close_stats(deps.storage, &escrow)?;
+
+    // user_b's share is paid like a withdraw, platform fee included
+    let mut payments = create_refund_submsgs(deps.storage, &id, &escrow, to_user_a)?;
+    payments.extend(create_withdraw_submsgs(
+        deps.storage,
+        &config,
+        &to_user_b,
+        escrow.user_b,
+        escrow.payout_msg.as_ref(),
+    )?);
+
+    let res = Response::new().add_attributes(vec![
+        ("action", "settle"),
+        ("id", id.as_str()),
+    ])
+    .add_event(event)
+    .add_submessages(track_payouts(deps.storage, &env, &id, payments)?);
+
+    Ok(res)
+}
+
+pub fn execute_top_up(
+    deps: DepsMut,
+    env: Env,
+    id: String,
+    funds: Balance,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // this fails is no escrow there
+    let mut escrow = escrows().load(deps.storage, &id)?;
+
+    if escrow.is_closed() {
+        return Err(ContractError::Closed {  });
+    }
+
+    if escrow.is_disputed() {
+        return Err(ContractError::Disputed {  });
+    }
+
+    if escrow.is_expired(&env.block) {
+        return Err(ContractError::Expired {  });
+    }
+
+    CONFIG.load(deps.storage)?.check_allowed(&funds)?;
+    escrow.top_up(sender, &funds)?;
+
+    escrows().save(deps.storage, &id, &escrow)?;
+    update_stats(deps.storage, |stats| stats.lock(&funds))?;
+
+    let res = Response::new()
+        .add_attributes(vec![
+            ("action", "top_up"),
+            ("id", id.as_str()),
+            ("deposit", format_balance(&escrow.deposit).as_str()),
+        ])
+        .set_data(to_binary(&TopUpResponse { deposit: escrow.deposit })?);
+
+    Ok(res)
+}
+
+pub fn execute_contribute(
+    deps: DepsMut,
+    env: Env,
+    id: String,
+    funds: Balance,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // this fails is no escrow there
+    let mut escrow = escrows().load(deps.storage, &id)?;
+
+    if escrow.is_closed() {
+        return Err(ContractError::Closed {  });
+    }
+
+    if escrow.is_disputed() {
+        return Err(ContractError::Disputed {  });
+    }
+
+    if escrow.is_expired(&env.block) {
+        return Err(ContractError::Expired {  });
+    }
+
+    CONFIG.load(deps.storage)?.check_allowed(&funds)?;
+    let amount = escrow.contribute(&funds)?;
+
+    escrows().save(deps.storage, &id, &escrow)?;
+    CONTRIBUTIONS.update(deps.storage, (&id, sender), |total| -> StdResult<_> {
+        Ok(total.unwrap_or_default() + amount)
+    })?;
+    update_stats(deps.storage, |stats| stats.lock(&funds))?;
+
+    let res = Response::new()
+        .add_attributes(vec![
+            ("action", "contribute"),
+            ("id", id.as_str()),
+            ("user", sender.as_str()),
+            ("amount", amount.to_string().as_str()),
+            ("funded", escrow.is_funded().to_string().as_str()),
+        ]);
+
+    Ok(res)
+}
+
+pub fn execute_dispute(
+    deps: DepsMut,
+    env: Env,
+    id: String,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // this fails is no escrow there
+    let mut escrow = escrows().load(deps.storage, &id)?;
+
+    if escrow.is_closed() {
+        return Err(ContractError::Closed {  });
+    }
+
+    if escrow.is_disputed() {
+        return Err(ContractError::Disputed {  });
+    }
+
+    // once expired, the deposit can be refunded instead
+    if escrow.is_expired(&env.block) {
+        return Err(ContractError::Expired {  });
+    }
+
+    escrow.dispute(sender, env.block.time)?;
+
+    escrows().save(deps.storage, &id, &escrow)?;
+
+    let res = Response::new().add_attributes(vec![
+        ("action", "dispute"),
+        ("id", id.as_str()),
+        ("user", sender.as_str()),
+    ])
+    .add_event(status_event(&id, &escrow));
+
+    Ok(res)
+}
+
+pub fn execute_resolve(
+    deps: DepsMut,
+    env: Env,
+    id: String,
+    to_user_a: Balance,
+    to_user_b: Balance,
+    sender: &Addr,
+) -> Result<Response, ContractError> {
+    // this fails is no escrow there
+    let mut escrow = escrows().load(deps.storage, &id)?;
+
+    if escrow.is_closed() {
+        return Err(ContractError::Closed {  });
+    }
+
+    if !escrow.is_disputed() {
+        return Err(ContractError::NotDisputed {  });
+    }
+
+    if escrow.arbiter.as_ref() != Some(sender) {
+        return Err(ContractError::NotArbiter {  });
+    }
+
+    escrow.check_split(&to_user_a, &to_user_b)?;
+
+    escrow.set_status(EscrowStatus::Completed, env.block.time)?;
+    let config = CONFIG.load(deps.storage)?;
+    record_paid(&mut escrow, &config, &to_user_a, &to_user_b)?;
+
+    escrows().save(deps.storage, &id, &escrow)?;
+    let event = status_event(&id, &escrow);
+    close_stats(deps.storage, &escrow)?;
+
+    // user_b's share is paid like a withdraw, platform fee included
+    let mut payments = create_refund_submsgs(deps.storage, &id, &escrow, to_user_a)?;
+    payments.extend(create_withdraw_submsgs(
+        deps.storage,
+        &config,
+        &to_user_b,
+        escrow.user_b,
+        escrow.payout_msg.as_ref(),
+    )?);
+
+    let res = Response::new().add_attributes(vec![
+        ("action", "resolve"),
+        ("id", id.as_str()),
+    ])
+    .add_event(event)
+    .add_submessages(track_payouts(deps.storage, &env, &id, payments)?);
+
+    Ok(res)
+}
+
+/// close_stats counts an escrow that was just closed, paying out everything
+/// it had not released yet
+fn close_stats(storage: &mut dyn Storage, escrow: &Escrow) -> StdResult<()> {
+    update_stats(storage, |stats| {
+        stats.release(&escrow.remaining());
+        stats.close(escrow.status);
+    })
+}
+
+/// record_paid tallies what the escrow pays out, the share of user_b
+/// being split with the platform fee like in create_withdraw_submsgs
+fn record_paid(
+    escrow: &mut Escrow,
+    config: &Config,
+    to_user_a: &Balance,
+    to_user_b: &Balance,
+) -> Result<(), EscrowError> {
+    let (fee, payout) = split_fee(to_user_b, config.fee_bps);
+    escrow.record_paid(to_user_a, &payout, &fee)
+}
+
+/// track_payouts sends the payouts of an escrow with reply_on_error, and
+/// records them so that a rejected transfer can be kept and retried. IBC
+/// transfers are recorded as IbcMsg::Transfer and sent as MsgTransfer.
+fn track_payouts(
+    storage: &mut dyn Storage,
+    env: &Env,
+    id: &str,
+    payments: Vec<SubMsg>,
+) -> StdResult<Vec<SubMsg>> {
+    remove_stale_payouts(storage, env.block.height)?;
+
+    let mut count = PAYOUT_COUNT.may_load(storage)?.unwrap_or_default();
+    let mut msgs = vec![];
+    for payment in payments {
+        count += 1;
+        PAYOUTS.save(storage, count, &Payout {
+            escrow: id.to_string(),
+            msg: payment.msg.clone(),
+            height: env.block.height,
+        })?;
+        // IBC transfers are sent with a memo asking for the outcome, and
+        // also reply on success, with their sequence
+        msgs.push(match payment.msg {
+            CosmosMsg::Ibc(IbcMsg::Transfer { channel_id, to_address, amount, timeout }) => {
+                let msg = transfer_msg(&env.contract.address, &channel_id, &to_address, &amount, &timeout);
+                SubMsg::reply_always(msg, count)
+            }
+            _ => SubMsg::reply_on_error(payment.msg, count),
+        });
+    }
+    PAYOUT_COUNT.save(storage, &count)?;
+    Ok(msgs)
+}
+
+/// status_event reports a transition of the escrow to its current status
+fn status_event(id: &str, escrow: &Escrow) -> Event {
+    Event::new("escrow_status")
+        .add_attribute("id", id)
+        .add_attribute("status", escrow.status.to_string())
+}
+
+/// create_withdraw_submsgs pays the deposit to the recipient after taking
+/// out the platform fee for the fee collector
+pub fn create_withdraw_submsgs(
+    storage: &mut dyn Storage,
+    config: &Config,
+    deposit: &Balance,
+    recipient: Addr,
+    payout_msg: Option<&Binary>,
+) -> StdResult<Vec<SubMsg>> {
+    let (fee, payout) = split_fee(deposit, config.fee_bps);
+
+    let mut msgs = create_payout_submsgs(storage, payout, recipient, payout_msg)?;
+    msgs.extend(create_payout_submsgs(storage, fee, config.fee_collector.clone(), None)?);
+    Ok(msgs)
+}
+
+/// create_ibc_withdraw_submsgs sends the deposit to user_b on another chain,
+/// one IBC transfer per coin, after taking out the platform fee, which is
+/// paid on this chain
+fn create_ibc_withdraw_submsgs(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    deposit: &Balance,
+    destination: &IbcDestination,
+) -> Result<Vec<SubMsg>, ContractError> {
+    let (fee, payout) = split_fee(deposit, config.fee_bps);
+
+    let timeout = destination.timeout(env.block.time)?;
+    let mut msgs: Vec<SubMsg> = match payout {
+        Balance::Native(coins) => coins
+            .into_vec()
+            .into_iter()
+            .map(|coin| SubMsg::new(IbcMsg::Transfer {
+                channel_id: destination.channel_id.clone(),
+                to_address: destination.remote_address.clone(),
+                amount: coin,
+                timeout: timeout.clone(),
+            }))
+            .collect(),
+        Balance::Cw20(_) => vec![],
+    };
+    msgs.extend(create_payout_submsgs(storage, fee, config.fee_collector.clone(), None)?);
+    Ok(msgs)
+}
+
+/// create_payout_submsgs transfers released funds to the recipient, with
+/// payout_msg if set, or credits them to its claimable balance in the
+/// ledger payout mode
+fn create_payout_submsgs(
+    storage: &mut dyn Storage,
+    payout: Balance,
+    recipient: Addr,
+    payout_msg: Option<&Binary>,
+) -> StdResult<Vec<SubMsg>> {
+    if payout.is_empty() {
+        return Ok(vec![]);
+    }
+    match PAYOUT_MODES.may_load(storage, &recipient)?.unwrap_or_default() {
+        PayoutMode::Transfer => match payout_msg {
+            Some(msg) => create_notify_submsgs(payout, recipient, msg.clone()),
+            None => create_payment_submsgs(payout, recipient),
+        },
+        PayoutMode::Ledger => {
+            CLAIMABLE.update(storage, &recipient, |claimable| -> StdResult<_> {
+                let mut claimable = claimable.unwrap_or_default();
+                claimable.add(&payout);
+                Ok(claimable)
+            })?;
+            Ok(vec![])
+        }
+    }
+}
+
+/// cancel_split divides the deposit of a cancelled escrow into the refund
+/// to user_a and the cancellation fee, if due, taken out of every coin for
+/// user_b
+pub fn cancel_split(deposit: &Balance, cancellation_fee: Option<&CancellationFee>) -> (Balance, Balance) {
+    match cancellation_fee {
+        Some(fee) => {
+            let (to_user_b, to_user_a) = split_balance(deposit, |amount| fee.amount_of(amount));
+            (to_user_a, to_user_b)
+        }
+        None => (deposit.clone(), Balance::default()),
+    }
+}
+
+/// create_cancel_submsgs refunds user_a like create_refund_submsgs, and
+/// pays the cancellation fee to user_b like a withdraw, platform fee
+/// included
+pub fn create_cancel_submsgs(
+    storage: &mut dyn Storage,
+    config: &Config,
+    id: &str,
+    escrow: &Escrow,
+    to_user_a: Balance,
+    to_user_b: &Balance,
+) -> StdResult<Vec<SubMsg>> {
+    let mut msgs = create_refund_submsgs(storage, id, escrow, to_user_a)?;
+    msgs.extend(create_withdraw_submsgs(
+        storage,
+        config,
+        to_user_b,
+        escrow.user_b.clone(),
+        escrow.payout_msg.as_ref(),
+    )?);
+    Ok(msgs)
+}
+
+/// create_refund_submsgs pays a refund to user_a or, for a shared fare, to
+/// every contributor pro rata to their contribution
+fn create_refund_submsgs(
+    storage: &dyn Storage,
+    id: &str,
+    escrow: &Escrow,
+    refund: Balance,
+) -> StdResult<Vec<SubMsg>> {
+    let amount = match (escrow.target, single_amount(&refund)) {
+        (Some(_), Some(amount)) if !amount.is_zero() => amount,
+        _ => return create_payment_submsgs(refund, escrow.user_a.clone()),
+    };
+
+    let contributions = contributions(storage, id, None, usize::MAX)?;
+    let mut msgs = vec![];
+    for (contributor, share) in pro_rata(amount, contributions, &escrow.user_a) {
+        msgs.extend(create_payment_submsgs(with_amount(&refund, share), contributor)?);
+    }
+    Ok(msgs)
+}
+
+/// pro_rata splits amount in proportion to the contributions. Shares are
+/// rounded down, and the rounding dust goes to dust_to.
+fn pro_rata(
+    amount: Uint128,
+    contributions: Vec<(Addr, Uint128)>,
+    dust_to: &Addr,
+) -> Vec<(Addr, Uint128)> {
+    let total: Uint128 = contributions.iter().map(|(_, c)| *c).sum();
+    if total.is_zero() {
+        return vec![(dust_to.clone(), amount)];
+    }
+    let mut shares: Vec<(Addr, Uint128)> = contributions
+        .into_iter()
+        .map(|(contributor, c)| (contributor, amount.multiply_ratio(c, total)))
+        .collect();
+    let dust = amount - shares.iter().map(|(_, share)| *share).sum::<Uint128>();
+    match shares.iter_mut().find(|(contributor, _)| contributor == dust_to) {
+        Some((_, share)) => *share += dust,
+        None => shares.push((dust_to.clone(), dust)),
+    }
+    shares
+}
+
+/// split_fee divides every coin of the deposit into a fee part, computed
+/// from fee_bps, and the remainder. The fee is rounded down so that the
+/// rounding dust goes to the remainder and nothing is lost.
+pub fn split_fee(deposit: &Balance, fee_bps: u16) -> (Balance, Balance) {
+    split_balance(deposit, |amount| amount.multiply_ratio(fee_bps, MAX_FEE_BPS))
+}
+
+/// split_balance divides every coin of the deposit into the part computed
+/// by fee_of, which must not exceed the amount, and the remainder
+fn split_balance(deposit: &Balance, fee_of: impl Fn(Uint128) -> Uint128) -> (Balance, Balance) {
+    match deposit {
+        Balance::Cw20(token) => {
+            let fee = fee_of(token.amount);
+            (
+                Balance::Cw20(Cw20CoinVerified {
+                    address: token.address.clone(),
+                    amount: fee,
+                }),
+                Balance::Cw20(Cw20CoinVerified {
+                    address: token.address.clone(),
+                    amount: token.amount - fee,
+                }),
+            )
+        }
+        Balance::Native(native_balance) => {
+            let mut fee = vec![];
+            let mut rest = vec![];
+            for coin in native_balance.0.iter() {
+                let coin_fee = fee_of(coin.amount);
+                fee.push(Coin {
+                    denom: coin.denom.clone(),
+                    amount: coin_fee,
+                });
+                rest.push(Coin {
+                    denom: coin.denom.clone(),
+                    amount: coin.amount - coin_fee,
+                });
+            }
+            let mut fee = NativeBalance(fee);
+            fee.normalize();
+            let mut rest = NativeBalance(rest);
+            rest.normalize();
+            (Balance::Native(fee), Balance::Native(rest))
+        }
+    }
+}
+
+/// create_payment_submsgs creates the messages transferring the deposit to
+/// the recipient. Nothing is sent for an empty deposit.
+pub fn create_payment_submsgs(deposit: Balance, recipient: Addr) -> StdResult<Vec<SubMsg>> {
+    let mut msgs: Vec<SubMsg> = vec![];
+
+    if deposit.is_empty() {
+        return Ok(msgs);
+    }
+    
+    match deposit {
+        Balance::Cw20(token) => {
+            let payoff_msg = Cw20ExecuteMsg::Transfer {
+                recipient: recipient.to_string(),
+                amount:  token.amount,
+            };
+            let payoff_exec = SubMsg::new(WasmMsg::Execute {
+                contract_addr: token.address.to_string(),
+                msg: to_binary(&payoff_msg)?,
+                funds: vec![],
+            });
+            msgs.push(payoff_exec);   
+        }
+        Balance::Native(native_balance) => {
+            let payoff_msg = SubMsg::new(BankMsg::Send {
+                to_address: recipient.to_string(),
+                amount: native_balance.into_vec(),
+            });
+            msgs.push(payoff_msg);
+        }
+    }
+    Ok(msgs)
+}
+
+/// create_notify_submsgs pays a contract and has it execute msg: cw20
+/// tokens are sent with Send, which calls back the recipient with msg, and
+/// native coins are attached to an Execute of msg
+pub fn create_notify_submsgs(deposit: Balance, recipient: Addr, msg: Binary) -> StdResult<Vec<SubMsg>> {
+    if deposit.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let payoff_exec = match deposit {
+        Balance::Cw20(token) => WasmMsg::Execute {
+            contract_addr: token.address.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Send {
+                contract: recipient.to_string(),
+                amount: token.amount,
+                msg,
+            })?,
+            funds: vec![],
+        },
+        Balance::Native(native_balance) => WasmMsg::Execute {
+            contract_addr: recipient.to_string(),
+            msg,
+            funds: native_balance.into_vec(),
+        },
+    };
+    Ok(vec![SubMsg::new(payoff_exec)])
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::List { start_after, limit } => {
+            to_binary(&query_list(deps, start_after, limit)?)
+        },
+        QueryMsg::ListByCreator { user_a, start_after, limit } => {
+            to_binary(&query_list_by_creator(deps, env, user_a, start_after, limit)?)
+        },
+        QueryMsg::ListByRecipient { user_b, start_after, limit } => {
+            to_binary(&query_list_by_recipient(deps, env, user_b, start_after, limit)?)
+        },
+        QueryMsg::ListOpen { start_after, limit } => {
+            to_binary(&query_list_open(deps, env, start_after, limit)?)
+        },
+        QueryMsg::Details { id } => to_binary(&query_details(deps, env, id)?),
+        QueryMsg::Config {} => to_binary(&query_config(deps)?),
+        QueryMsg::Balance { address } => to_binary(&query_balance(deps, address)?),
+        QueryMsg::Contributions { id, start_after, limit } => {
+            to_binary(&query_contributions(deps, id, start_after, limit)?)
+        },
+        QueryMsg::History { id, start_after, limit } => {
+            to_binary(&query_history(deps, id, start_after, limit)?)
+        },
+        QueryMsg::Stats {} => to_binary(&query_stats(deps)?),
+    }
+}
+
+fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    Ok(ConfigResponse {
+        admin: config.admin.to_string(),
+        fee_collector: config.fee_collector.to_string(),
+        fee_bps: config.fee_bps,
+        allowed_native: config.allowed_native,
+        allowed_cw20: config.allowed_cw20.into_iter().map(String::from).collect(),
+        pending_admin: config.pending_admin.map(String::from),
+        paused: config.paused,
+        relayers: config.relayers.into_iter().map(String::from).collect(),
+    })
+}
+
+fn query_balance(deps: Deps, address: String) -> StdResult<BalanceResponse> {
+    let address = deps.api.addr_validate(&address)?;
+    let claimable = CLAIMABLE.may_load(deps.storage, &address)?.unwrap_or_default();
+    Ok(BalanceResponse {
+        payout_mode: PAYOUT_MODES.may_load(deps.storage, &address)?.unwrap_or_default(),
+        native: claimable.native,
+        cw20: cw20_coins(claimable.cw20),
+    })
+}
+
+fn query_stats(deps: Deps) -> StdResult<StatsResponse> {
+    let stats = STATS.may_load(deps.storage)?.unwrap_or_default();
+    Ok(StatsResponse {
+        open: stats.open,
+        withdrawn: stats.withdrawn,
+        cancelled: stats.cancelled,
+        locked: Totals { native: stats.locked.native, cw20: cw20_coins(stats.locked.cw20) },
+        released: Totals { native: stats.released.native, cw20: cw20_coins(stats.released.cw20) },
+    })
+}
+
+fn cw20_coins(tokens: Vec<Cw20CoinVerified>) -> Vec<Cw20Coin> {
+    tokens
+        .into_iter()
+        .map(|token| Cw20Coin { address: token.address.to_string(), amount: token.amount })
+        .collect()
+}
+
+fn query_contributions(
+    deps: Deps,
+    id: String,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ContributionsResponse> {
+    let start_after = start_after
+        .map(|addr| deps.api.addr_validate(&addr))
+        .transpose()?;
+    let page = contributions(deps.storage, &id, start_after.as_ref(), page_limit(limit))?;
+    Ok(ContributionsResponse {
+        contributions: page
+            .into_iter()
+            .map(|(address, amount)| Contribution { address: address.to_string(), amount })
+            .collect(),
+    })
+}
+
+fn query_history(
+    deps: Deps,
+    id: Option<String>,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<HistoryResponse> {
+    let page = archived_escrows(deps.storage, id.as_deref(), start_after, page_limit(limit))?;
+    Ok(HistoryResponse {
+        escrows: page
+            .into_iter()
+            .map(|(index, record)| ArchiveRecord {
+                index,
+                id: record.id,
+                user_a: record.user_a.to_string(),
+                user_b: record.user_b.to_string(),
+                deposit: record.deposit,
+                outcome: record.outcome,
+                paid: record.paid,
+                closed_at: record.closed_at,
+                pruned_at: record.pruned_at,
+                reserved: record.reserved,
+            })
+            .collect(),
+    })
+}
+
+fn query_details(deps: Deps, env: Env, id: String) -> StdResult<DetailsResponse> {
+    let escrow = escrows().load(deps.storage, &id)?;
+    Ok(escrow_details(&env, id, escrow))
+}
+
+fn escrow_details(env: &Env, id: String, escrow: Escrow) -> DetailsResponse {
+    DetailsResponse {
+        id,
+        user_a: escrow.user_a.to_string(),
+        user_b: escrow.user_b.to_string(),
+        expired: escrow.is_expired(&env.block),
+        funded: escrow.is_funded(),
+        deposit: escrow.deposit,
+        lock: escrow.lock,
+        lock_kind: escrow.lock_kind,
+        status: escrow.status,
+        expires_at: escrow.expires_at,
+        arbiter: escrow.arbiter.map(|arbiter| arbiter.to_string()),
+        cancellation_fee: escrow.cancellation_fee,
+        history: escrow.history,
+        settlement: escrow.settlement,
+        milestones: escrow.milestones,
+        target: escrow.target,
+        failed_payouts: escrow.failed_payouts,
+        payout_msg: escrow.payout_msg,
+        ibc_destination: escrow.ibc_destination,
+        nonce: escrow.nonce,
+    }
+}
+
+fn page_limit(limit: Option<u32>) -> usize {
+    limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize
+}
+
+fn details_list(env: &Env, page: Vec<(String, Escrow)>) -> DetailsListResponse {
+    DetailsListResponse {
+        escrows: page
+            .into_iter()
+            .map(|(id, escrow)| escrow_details(env, id, escrow))
+            .collect(),
+    }
+}
+
+fn query_list(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ListResponse> {
+    Ok(ListResponse {
+        escrows: escrow_ids(deps.storage, start_after, page_limit(limit))?,
+    })
+}
+
+fn query_list_by_creator(
+    deps: Deps,
+    env: Env,
+    user_a: String,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<DetailsListResponse> {
+    let user_a = deps.api.addr_validate(&user_a)?;
+    let page = escrows_by_creator(deps.storage, &user_a, start_after, page_limit(limit))?;
+    Ok(details_list(&env, page))
+}
+
+fn query_list_by_recipient(
+    deps: Deps,
+    env: Env,
+    user_b: String,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<DetailsListResponse> {
+    let user_b = deps.api.addr_validate(&user_b)?;
+    let page = escrows_by_recipient(deps.storage, &user_b, start_after, page_limit(limit))?;
+    Ok(details_list(&env, page))
+}
+
+fn query_list_open(
+    deps: Deps,
+    env: Env,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<DetailsListResponse> {
+    let page = open_escrows(deps.storage, start_after, page_limit(limit))?;
+    Ok(details_list(&env, page))
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+    use cosmwasm_std::{attr, coins, CosmosMsg, IbcTimeout, ReplyOn, SubMsgResponse, Uint128};
+    use cw_utils::{Expiration, NativeBalance};
+
+    use k256::ecdsa::{signature::Signer, Signature, SigningKey};
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::escrow::Paid;
+    use crate::msg::{IbcDestinationMsg, LockKind, MilestoneMsg, StatusChange};
+
+    const ESCROW_ID: &str ="foobar";
+    const USER_A_ADDR: &str= "user_a";  
+    const USER_B_ADDR : &str = "user_b";
+    const FEE_COLLECTOR_ADDR: &str = "fee_collector";
+    const ARBITER_ADDR: &str = "arbiter";
+    const REQUIRED_NATIVE_TOKEN_DENOM: &str = "token";
+    const REQUIRED_CW20_TOKEN_ADDR: &str = "the_cw20_token";
+    const REQUIRED_TOKEN_AMOUNT: u128 =  100;
+    const LOCK_A: &str = "0330347c5cb0f1627bdd2e7b082504a443b2bf50ad2e3efbb4e754ebd687c78c24";
+    const SECRET_A: &str =  "27874aa2b70ce7281c94413c36d44fac6fa6a1198f2c529188c4dd4f7a4e1870"; 
+
+    /// messages returns the messages sent, without their reply ids
+    fn messages(res: &Response) -> Vec<CosmosMsg> {
+        res.messages.iter().map(|m| m.msg.clone()).collect()
+    }
+
+    fn history(statuses: &[EscrowStatus]) -> Vec<StatusChange> {
+        statuses
+            .iter()
+            .map(|&status| StatusChange { status, time: mock_env().block.time })
+            .collect()
+    }
+
+    fn get_instantiate_msg() -> (MessageInfo, InstantiateMsg) {
+        let instantiate_msg = InstantiateMsg {
+            fee_collector: FEE_COLLECTOR_ADDR.to_string(),
+            fee_bps: 0,
+            allowed_native: vec![REQUIRED_NATIVE_TOKEN_DENOM.to_string()],
+            allowed_cw20: vec![REQUIRED_CW20_TOKEN_ADDR.to_string()],
+        };
+        let info = mock_info(&String::from("anyone"), &[]);
+        (info, instantiate_msg)
+    }
+
+    fn get_create_msg(
+        sender_addr: String, 
+        escrow_id: String,
+        user_b_addr: String,
+        lock: String) -> (MessageInfo, ExecuteMsg) {
+
+        get_create_msg_with_expiry(sender_addr, escrow_id, user_b_addr, lock, None)
+    }
+
+    fn get_create_msg_with_expiry(
+        sender_addr: String, 
+        escrow_id: String,
+        user_b_addr: String,
+        lock: String,
+        expires_at: Option<Expiration>) -> (MessageInfo, ExecuteMsg) {
+
+        let create_msg = CreateMsg {
+            id: Some(escrow_id),
+            user_b: user_b_addr,
+            lock,
+            lock_kind: LockKind::K256,
+            expires_at,
+            arbiter: None,
+            expected_amount: None,
+            cancellation_fee: None,
+            milestones: vec![],
+            target: None,
+            payout_msg: None,
+            ibc_destination: None,
+        };
+        let msg = ExecuteMsg::Create(create_msg.clone());
+        let balance = coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM);
+        let info = mock_info(&sender_addr, &balance);
+        (info, msg)
+    }
+
+    fn get_receive_create_msg(
+        sender_addr: String,
+        escrow_id: String,
+        user_b_addr: String,
+        lock: String,
+        required_token_addr: String,
+        required_token_amount: u128 ) -> (MessageInfo, ExecuteMsg){
+
+        let create = CreateMsg {
+            id: Some(escrow_id),
             user_b: user_b_addr,
-            lock: lock,
+            lock,
+            lock_kind: LockKind::K256,
+            expires_at: None,
+            arbiter: None,
+            expected_amount: None,
+            cancellation_fee: None,
+            milestones: vec![],
+            target: None,
+            payout_msg: None,
+            ibc_destination: None,
+        };
+        let receive = Cw20ReceiveMsg {
+            sender: sender_addr,
+            amount: Uint128::new(required_token_amount),
+            msg: to_binary(&ReceiveMsg::Create(create.clone())).unwrap(),
+        };
+        let info = mock_info(&required_token_addr, &[]);
+        let msg = ExecuteMsg::Receive(receive.clone());
+        (info, msg)
+    }
+
+    fn get_withdraw_msg(
+        sender_addr: String,
+        escrow_id: String,
+        secret: String,
+    ) -> (MessageInfo, ExecuteMsg) {
+        let withdraw = WithdrawMsg {
+            id: escrow_id,
+            secret: Some(secret),
+            signature: None,
+            milestone: None,
+            payout_msg: None,
+        };
+        let balance = coins(100, "tokens");
+        let info = mock_info(&sender_addr,  &balance);
+        let msg = ExecuteMsg::Withdraw(withdraw.clone());
+        (info, msg)
+    }
+
+    fn get_cancel_msg(
+        sender_addr: String,
+        escrow_id: String,
+    ) -> (MessageInfo, ExecuteMsg) {
+        let info = mock_info(&sender_addr, &[]);
+        let msg = ExecuteMsg::Cancel{id: escrow_id};
+        (info, msg)
+    }
+
+    #[test]
+    fn withdraw_happy_native() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+
+        // create an escrow
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+
+        let res = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("action", "create"), res.attributes[0]);
+
+        // ensure the escrow is in the expected state
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(
+            details,
+            DetailsResponse {
+                id: ESCROW_ID.to_string(),
+                user_a: USER_A_ADDR.to_string(),
+                user_b: USER_B_ADDR.to_string(),
+                deposit: Balance::Native(NativeBalance(coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM))),
+                lock: LOCK_A.to_string(),
+                lock_kind: LockKind::K256,
+                status: EscrowStatus::Created,
+                history: history(&[EscrowStatus::Created]),
+                expires_at: None,
+                expired: false,
+                arbiter: None,
+                cancellation_fee: None,
+                settlement: None,
+                milestones: vec![],
+                target: None,
+                funded: true,
+                failed_payouts: vec![],
+                payout_msg: None,
+                ibc_destination: None,
+                nonce: 1,
+            }
+        );
+
+        // withdraw
+        let (info, withdraw_msg) = get_withdraw_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            SECRET_A.to_string(),
+        );  
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), withdraw_msg.clone()).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(("action", "withdraw"), res.attributes[0]);
+        assert_eq!(("id", ESCROW_ID.to_string()), res.attributes[1]);
+    
+        // ensure the escrow is closed
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(
+            details,
+            DetailsResponse {
+                id: ESCROW_ID.to_string(),
+                user_a: USER_A_ADDR.to_string(),
+                user_b: USER_B_ADDR.to_string(),
+                deposit: Balance::Native(NativeBalance(coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM))),
+                lock: LOCK_A.to_string(),
+                lock_kind: LockKind::K256,
+                status: EscrowStatus::Completed,
+                history: history(&[EscrowStatus::Created, EscrowStatus::Completed]),
+                expires_at: None,
+                expired: false,
+                arbiter: None,
+                cancellation_fee: None,
+                settlement: None,
+                milestones: vec![],
+                target: None,
+                funded: true,
+                failed_payouts: vec![],
+                payout_msg: None,
+                ibc_destination: None,
+                nonce: 1,
+            }
+        );
+
+        // withdraw when escrow closed
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), withdraw_msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Closed{}));
+    }
+
+    #[test]
+    fn withdraw_happy_cw20() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+
+        // create an escrow
+        let (info, receive_create_msg) = get_receive_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+            REQUIRED_CW20_TOKEN_ADDR.to_string(),
+            REQUIRED_TOKEN_AMOUNT,
+        );
+
+        let res = execute(deps.as_mut(), mock_env(), info, receive_create_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("action", "create"), res.attributes[0]);
+
+        // ensure the escrow is in the expected state
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(
+            details,
+            DetailsResponse {
+                id: ESCROW_ID.to_string(),
+                user_a: USER_A_ADDR.to_string(),
+                user_b: USER_B_ADDR.to_string(),
+                deposit: Balance::Cw20(
+                    Cw20CoinVerified{
+                        address:Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR),
+                        amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
+                    },
+                ),
+                lock: LOCK_A.to_string(),
+                lock_kind: LockKind::K256,
+                status: EscrowStatus::Created,
+                history: history(&[EscrowStatus::Created]),
+                expires_at: None,
+                expired: false,
+                arbiter: None,
+                cancellation_fee: None,
+                settlement: None,
+                milestones: vec![],
+                target: None,
+                funded: true,
+                failed_payouts: vec![],
+                payout_msg: None,
+                ibc_destination: None,
+                nonce: 1,
+            }
+        );
+
+        // withdraw
+        let (info, withdraw_msg) = get_withdraw_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            SECRET_A.to_string(),
+        );  
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), withdraw_msg.clone()).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(("action", "withdraw"), res.attributes[0]);
+        assert_eq!(("id", ESCROW_ID.to_string()), res.attributes[1]);
+    
+        // ensure the escrow is closed
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(
+            details,
+            DetailsResponse {
+                id: ESCROW_ID.to_string(),
+                user_a: USER_A_ADDR.to_string(),
+                user_b: USER_B_ADDR.to_string(),
+                deposit: Balance::Cw20(
+                    Cw20CoinVerified{
+                        address:Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR),
+                        amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
+                    },
+                ),
+                lock: LOCK_A.to_string(),
+                lock_kind: LockKind::K256,
+                status: EscrowStatus::Completed,
+                history: history(&[EscrowStatus::Created, EscrowStatus::Completed]),
+                expires_at: None,
+                expired: false,
+                arbiter: None,
+                cancellation_fee: None,
+                settlement: None,
+                milestones: vec![],
+                target: None,
+                funded: true,
+                failed_payouts: vec![],
+                payout_msg: None,
+                ibc_destination: None,
+                nonce: 1,
+            }
+        );
+
+        // withdraw when escrow closed
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), withdraw_msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Closed{}));
+    }
+
+    #[test]
+    fn cancel_happy_native() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+
+        // create an escrow
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+     
+        // cancel
+        let (info, cancel_msg) = get_cancel_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+        );  
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), cancel_msg.clone()).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(("action", "cancel"), res.attributes[0]);
+        assert_eq!(("id", ESCROW_ID.to_string()), res.attributes[1]);
+    
+        // ensure the escrow is closed
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(
+            details,
+            DetailsResponse {
+                id: ESCROW_ID.to_string(),
+                user_a: USER_A_ADDR.to_string(),
+                user_b: USER_B_ADDR.to_string(),
+                deposit: Balance::Native(NativeBalance(coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM))),
+                lock: LOCK_A.to_string(),
+                lock_kind: LockKind::K256,
+                status: EscrowStatus::Cancelled,
+                history: history(&[EscrowStatus::Created, EscrowStatus::Cancelled]),
+                expires_at: None,
+                expired: false,
+                arbiter: None,
+                cancellation_fee: None,
+                settlement: None,
+                milestones: vec![],
+                target: None,
+                funded: true,
+                failed_payouts: vec![],
+                payout_msg: None,
+                ibc_destination: None,
+                nonce: 1,
+            }
+        );
+
+         // cancel when escrow closed
+         let err = execute(deps.as_mut(), mock_env(), info.clone(), cancel_msg.clone()).unwrap_err();
+         assert!(matches!(err, ContractError::Closed{}));
+    }
+
+    #[test]
+    fn cancel_happy_cw20() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+
+        // create an escrow
+        let (info, receive_create_msg) = get_receive_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+            REQUIRED_CW20_TOKEN_ADDR.to_string(),
+            REQUIRED_TOKEN_AMOUNT,
+        );
+        let _ = execute(deps.as_mut(), mock_env(), info, receive_create_msg).unwrap();
+     
+        // cancel
+        let (info, cancel_msg) = get_cancel_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+        );  
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), cancel_msg.clone()).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(("action", "cancel"), res.attributes[0]);
+        assert_eq!(("id", ESCROW_ID.to_string()), res.attributes[1]);
+    
+        // ensure the escrow is closed
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(
+            details,
+            DetailsResponse {
+                id: ESCROW_ID.to_string(),
+                user_a: USER_A_ADDR.to_string(),
+                user_b: USER_B_ADDR.to_string(),
+                deposit: Balance::Cw20(
+                    Cw20CoinVerified{
+                        address:Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR),
+                        amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
+                    },
+                ),
+                lock: LOCK_A.to_string(),
+                lock_kind: LockKind::K256,
+                status: EscrowStatus::Cancelled,
+                history: history(&[EscrowStatus::Created, EscrowStatus::Cancelled]),
+                expires_at: None,
+                expired: false,
+                arbiter: None,
+                cancellation_fee: None,
+                settlement: None,
+                milestones: vec![],
+                target: None,
+                funded: true,
+                failed_payouts: vec![],
+                payout_msg: None,
+                ibc_destination: None,
+                nonce: 1,
+            }
+        );
+
+         // cancel when escrow closed
+         let err = execute(deps.as_mut(), mock_env(), info.clone(), cancel_msg.clone()).unwrap_err();
+         assert!(matches!(err, ContractError::Closed{}));
+    }
+
+    #[test]
+    fn cancel_wrong_user() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+
+        // create an escrow
+        let (info, receive_create_msg) = get_receive_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+            REQUIRED_CW20_TOKEN_ADDR.to_string(),
+            REQUIRED_TOKEN_AMOUNT,
+        );
+        let _ = execute(deps.as_mut(), mock_env(), info, receive_create_msg).unwrap();
+     
+        // cancel
+        let (info, cancel_msg) = get_cancel_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+        );  
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), cancel_msg.clone()).unwrap_err();
+         assert!(matches!(err, ContractError::InvalidUser{}));
+        
+        // ensure the escrow is still open
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(
+            details,
+            DetailsResponse {
+                id: ESCROW_ID.to_string(),
+                user_a: USER_A_ADDR.to_string(),
+                user_b: USER_B_ADDR.to_string(),
+                deposit: Balance::Cw20(
+                    Cw20CoinVerified{
+                        address:Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR),
+                        amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
+                    },
+                ),
+                lock: LOCK_A.to_string(),
+                lock_kind: LockKind::K256,
+                status: EscrowStatus::Created,
+                history: history(&[EscrowStatus::Created]),
+                expires_at: None,
+                expired: false,
+                arbiter: None,
+                cancellation_fee: None,
+                settlement: None,
+                milestones: vec![],
+                target: None,
+                funded: true,
+                failed_payouts: vec![],
+                payout_msg: None,
+                ibc_destination: None,
+                nonce: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn cancel_before_expiry_requires_both_users() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // create an escrow that expires in 10 blocks
+        let env = mock_env();
+        let (info, create_msg) = get_create_msg_with_expiry(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+            Some(Expiration::AtHeight(env.block.height + 10)),
+        );
+        let _ = execute(deps.as_mut(), env.clone(), info, create_msg).unwrap();
+
+        // a third party cannot cancel
+        let (info, cancel_msg) = get_cancel_msg(
+            "anyone".to_string(),
+            ESCROW_ID.to_string(),
+        );
+        let err = execute(deps.as_mut(), env.clone(), info, cancel_msg).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::NotAParty{})));
+
+        // the creator alone only approves the cancellation
+        let (info, cancel_msg) = get_cancel_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+        );
+        let res = execute(deps.as_mut(), env.clone(), info, cancel_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("action", "approve_cancel"), res.attributes[0]);
+
+        let details = query_details(deps.as_ref(), env.clone(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(EscrowStatus::Created, details.status);
+        assert!(!details.expired);
+        assert_eq!(Some(Expiration::AtHeight(env.block.height + 10)), details.expires_at);
+
+        // the recipient agrees, and the deposit is refunded
+        let (info, cancel_msg) = get_cancel_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+        );
+        let res = execute(deps.as_mut(), env.clone(), info, cancel_msg).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(("action", "cancel"), res.attributes[0]);
+        assert_eq!(
+            messages(&res)[0],
+            CosmosMsg::from(BankMsg::Send {
+                to_address: USER_A_ADDR.to_string(),
+                amount: coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+            })
+        );
+
+        let details = query_details(deps.as_ref(), env, ESCROW_ID.to_string()).unwrap();
+        assert_eq!(EscrowStatus::Cancelled, details.status);
+    }
+
+    #[test]
+    fn refund_after_expiry() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // create an escrow that expires in 10 blocks
+        let mut env = mock_env();
+        let (info, create_msg) = get_create_msg_with_expiry(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+            Some(Expiration::AtHeight(env.block.height + 10)),
+        );
+        let _ = execute(deps.as_mut(), env.clone(), info, create_msg).unwrap();
+
+        env.block.height += 10;
+
+        let details = query_details(deps.as_ref(), env.clone(), ESCROW_ID.to_string()).unwrap();
+        assert!(details.expired);
+
+        // the recipient can no longer withdraw
+        let (info, withdraw_msg) = get_withdraw_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            SECRET_A.to_string(),
+        );
+        let err = execute(deps.as_mut(), env.clone(), info, withdraw_msg).unwrap_err();
+        assert!(matches!(err, ContractError::Expired{}));
+
+        // anyone can trigger the refund to the creator
+        let (info, cancel_msg) = get_cancel_msg(
+            "anyone".to_string(),
+            ESCROW_ID.to_string(),
+        );
+        let res = execute(deps.as_mut(), env.clone(), info, cancel_msg).unwrap();
+        assert_eq!(("action", "cancel"), res.attributes[0]);
+        assert_eq!(
+            messages(&res)[0],
+            CosmosMsg::from(BankMsg::Send {
+                to_address: USER_A_ADDR.to_string(),
+                amount: coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+            })
+        );
+    }
+
+    #[test]
+    fn create_already_expired() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        let env = mock_env();
+        let (info, create_msg) = get_create_msg_with_expiry(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+            Some(Expiration::AtTime(env.block.time)),
+        );
+        let err = execute(deps.as_mut(), env, info, create_msg).unwrap_err();
+        assert!(matches!(err, ContractError::Expired{}));
+    }
+
+    #[test]
+    fn instantiate_invalid_fee() {
+        let mut deps = mock_dependencies();
+
+        let (info, mut instantiate_msg) = get_instantiate_msg();
+        instantiate_msg.fee_bps = MAX_FEE_BPS + 1;
+        let err = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidFee{}));
+    }
+
+    #[test]
+    fn withdraw_with_fee_native() {
+        let mut deps = mock_dependencies();
+
+        // instantiate with a 2.5% fee
+        let (info, mut instantiate_msg) = get_instantiate_msg();
+        instantiate_msg.fee_bps = 250;
+        instantiate_msg.allowed_native = vec!["atom".to_string(), "osmo".to_string()];
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        let config = query_config(deps.as_ref()).unwrap();
+        assert_eq!(
+            config,
+            ConfigResponse {
+                admin: "anyone".to_string(),
+                fee_collector: FEE_COLLECTOR_ADDR.to_string(),
+                fee_bps: 250,
+                allowed_native: vec!["atom".to_string(), "osmo".to_string()],
+                allowed_cw20: vec![REQUIRED_CW20_TOKEN_ADDR.to_string()],
+                pending_admin: None,
+                paused: false,
+                relayers: vec![],
+            }
+        );
+
+        // create an escrow with two native coins
+        let (_, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        let info = mock_info(
+            USER_A_ADDR,
+            &[Coin::new(1000, "atom"), Coin::new(39, "osmo")],
+        );
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+
+        // withdraw
+        let (info, withdraw_msg) = get_withdraw_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            SECRET_A.to_string(),
+        );
+        let res = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap();
+        assert_eq!(2, res.messages.len());
+
+        // the fee on 39osmo rounds down to 0, so the whole amount goes to user_b
+        assert_eq!(
+            messages(&res)[0],
+            CosmosMsg::from(BankMsg::Send {
+                to_address: USER_B_ADDR.to_string(),
+                amount: vec![Coin::new(975, "atom"), Coin::new(39, "osmo")],
+            })
+        );
+        assert_eq!(
+            messages(&res)[1],
+            CosmosMsg::from(BankMsg::Send {
+                to_address: FEE_COLLECTOR_ADDR.to_string(),
+                amount: vec![Coin::new(25, "atom")],
+            })
+        );
+    }
+
+    #[test]
+    fn withdraw_with_fee_cw20() {
+        let mut deps = mock_dependencies();
+
+        // instantiate with a 3.33% fee
+        let (info, mut instantiate_msg) = get_instantiate_msg();
+        instantiate_msg.fee_bps = 333;
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        let (info, receive_create_msg) = get_receive_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+            REQUIRED_CW20_TOKEN_ADDR.to_string(),
+            REQUIRED_TOKEN_AMOUNT,
+        );
+        let _ = execute(deps.as_mut(), mock_env(), info, receive_create_msg).unwrap();
+
+        // withdraw
+        let (info, withdraw_msg) = get_withdraw_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            SECRET_A.to_string(),
+        );
+        let res = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap();
+        assert_eq!(2, res.messages.len());
+
+        // 3.33% of 100 is rounded down to 3
+        assert_eq!(
+            messages(&res)[0],
+            CosmosMsg::from(WasmMsg::Execute {
+                contract_addr: REQUIRED_CW20_TOKEN_ADDR.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: USER_B_ADDR.to_string(),
+                    amount: Uint128::new(97),
+                }).unwrap(),
+                funds: vec![],
+            })
+        );
+        assert_eq!(
+            messages(&res)[1],
+            CosmosMsg::from(WasmMsg::Execute {
+                contract_addr: REQUIRED_CW20_TOKEN_ADDR.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: FEE_COLLECTOR_ADDR.to_string(),
+                    amount: Uint128::new(3),
+                }).unwrap(),
+                funds: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn split_fee_keeps_dust() {
+        let deposit = Balance::Native(NativeBalance(vec![
+            Coin::new(1, "atom"),
+            Coin::new(10_001, "osmo"),
+        ]));
+
+        let (fee, rest) = split_fee(&deposit, 5000);
+        assert_eq!(fee, Balance::Native(NativeBalance(vec![Coin::new(5000, "osmo")])));
+        assert_eq!(
+            rest,
+            Balance::Native(NativeBalance(vec![Coin::new(1, "atom"), Coin::new(5001, "osmo")]))
+        );
+
+        let (fee, rest) = split_fee(&deposit, MAX_FEE_BPS);
+        assert_eq!(fee, deposit);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn pro_rata_keeps_dust() {
+        let user_a = Addr::unchecked(USER_A_ADDR);
+        let contributions = vec![
+            (Addr::unchecked("rider1"), Uint128::new(1)),
+            (user_a.clone(), Uint128::new(1)),
+            (Addr::unchecked("rider2"), Uint128::new(1)),
+        ];
+
+        let shares = pro_rata(Uint128::new(100), contributions, &user_a);
+        assert_eq!(
+            shares,
+            vec![
+                (Addr::unchecked("rider1"), Uint128::new(33)),
+                (user_a, Uint128::new(34)),
+                (Addr::unchecked("rider2"), Uint128::new(33)),
+            ]
+        );
+    }
+
+    #[test]
+    fn dispute_and_resolve() {
+        let mut deps = mock_dependencies();
+
+        // instantiate with a 10% fee
+        let (info, mut instantiate_msg) = get_instantiate_msg();
+        instantiate_msg.fee_bps = 1000;
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // create an escrow with an arbiter
+        let (info, mut create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.arbiter = Some(ARBITER_ADDR.to_string());
+        }
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+
+        // the arbiter cannot resolve before a dispute is opened
+        let resolve_msg = ExecuteMsg::Resolve {
+            id: ESCROW_ID.to_string(),
+            to_user_a: Balance::Native(NativeBalance(coins(40, REQUIRED_NATIVE_TOKEN_DENOM))),
+            to_user_b: Balance::Native(NativeBalance(coins(60, REQUIRED_NATIVE_TOKEN_DENOM))),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(ARBITER_ADDR, &[]), resolve_msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::NotDisputed{}));
+
+        // only the users can open a dispute
+        let dispute_msg = ExecuteMsg::Dispute { id: ESCROW_ID.to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(ARBITER_ADDR, &[]), dispute_msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::NotAParty{})));
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), dispute_msg.clone()).unwrap();
+        assert_eq!(("action", "dispute"), res.attributes[0]);
+
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(EscrowStatus::Disputed, details.status);
+        assert_eq!(Some(ARBITER_ADDR.to_string()), details.arbiter);
+
+        // the escrow is frozen
+        let (info, withdraw_msg) = get_withdraw_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            SECRET_A.to_string(),
+        );
+        let err = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap_err();
+        assert!(matches!(err, ContractError::Disputed{}));
+
+        let (info, cancel_msg) = get_cancel_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+        );
+        let err = execute(deps.as_mut(), mock_env(), info, cancel_msg).unwrap_err();
+        assert!(matches!(err, ContractError::Disputed{}));
+
+        // only the arbiter can resolve
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), resolve_msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::NotArbiter{}));
+
+        // the split must match the deposit
+        let invalid_resolve_msg = ExecuteMsg::Resolve {
+            id: ESCROW_ID.to_string(),
+            to_user_a: Balance::Native(NativeBalance(coins(40, REQUIRED_NATIVE_TOKEN_DENOM))),
+            to_user_b: Balance::Native(NativeBalance(coins(40, REQUIRED_NATIVE_TOKEN_DENOM))),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(ARBITER_ADDR, &[]), invalid_resolve_msg).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::InvalidSplit{})));
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info(ARBITER_ADDR, &[]), resolve_msg).unwrap();
+        assert_eq!(("action", "resolve"), res.attributes[0]);
+        assert_eq!(3, res.messages.len());
+        assert_eq!(
+            messages(&res)[0],
+            CosmosMsg::from(BankMsg::Send {
+                to_address: USER_A_ADDR.to_string(),
+                amount: coins(40, REQUIRED_NATIVE_TOKEN_DENOM),
+            })
+        );
+        assert_eq!(
+            messages(&res)[1],
+            CosmosMsg::from(BankMsg::Send {
+                to_address: USER_B_ADDR.to_string(),
+                amount: coins(54, REQUIRED_NATIVE_TOKEN_DENOM),
+            })
+        );
+        assert_eq!(
+            messages(&res)[2],
+            CosmosMsg::from(BankMsg::Send {
+                to_address: FEE_COLLECTOR_ADDR.to_string(),
+                amount: coins(6, REQUIRED_NATIVE_TOKEN_DENOM),
+            })
+        );
+
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(EscrowStatus::Completed, details.status);
+    }
+
+    #[test]
+    fn withdraw_with_signature() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // create an escrow
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+
+        let key = SigningKey::from_bytes(&hex::decode(SECRET_A).unwrap()).unwrap();
+        let sign = |contract: &str, id: &str, recipient: &str| {
+            let payload = withdraw_payload(
+                &Addr::unchecked(contract),
+                id,
+                1,
+                &Addr::unchecked(recipient),
+                None,
+                None,
+            ).unwrap();
+            let signature: Signature = key.sign(&payload);
+            hex::encode(signature.as_ref())
+        };
+        let contract_addr = mock_env().contract.address.to_string();
+
+        // neither a secret nor a signature
+        let withdraw_msg = ExecuteMsg::Withdraw(WithdrawMsg {
+            id: ESCROW_ID.to_string(),
+            secret: None,
+            signature: None,
+            milestone: None,
+            payout_msg: None,
+        });
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), withdraw_msg).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::NoSecret{})));
+
+        // signatures for another contract, escrow or recipient are rejected
+        for signature in [
+            sign("other_contract", ESCROW_ID, USER_B_ADDR),
+            sign(&contract_addr, "other_id", USER_B_ADDR),
+            sign(&contract_addr, ESCROW_ID, USER_A_ADDR),
+        ] {
+            let withdraw_msg = ExecuteMsg::Withdraw(WithdrawMsg {
+                id: ESCROW_ID.to_string(),
+                secret: None,
+                signature: Some(signature),
+                milestone: None,
+                payout_msg: None,
+            });
+            let err = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), withdraw_msg).unwrap_err();
+            assert!(matches!(err, ContractError::Escrow(EscrowError::InvalidSignature{})));
+        }
+
+        // correct signature
+        let withdraw_msg = ExecuteMsg::Withdraw(WithdrawMsg {
+            id: ESCROW_ID.to_string(),
+            secret: None,
+            signature: Some(sign(&contract_addr, ESCROW_ID, USER_B_ADDR)),
+            milestone: None,
+            payout_msg: None,
+        });
+        let res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), withdraw_msg).unwrap();
+        assert_eq!(("action", "withdraw"), res.attributes[0]);
+        assert_eq!(
+            messages(&res)[0],
+            CosmosMsg::from(BankMsg::Send {
+                to_address: USER_B_ADDR.to_string(),
+                amount: coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+            })
+        );
+    }
+
+    #[test]
+    fn withdraw_hash_lock() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // create an escrow locked by the hash of a preimage
+        let preimage = hex::encode(b"atomic swap");
+        let digest = hex::encode(Sha256::digest(b"atomic swap"));
+        let (info, mut create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            digest,
+        );
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.lock_kind = LockKind::Sha256;
+        }
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(LockKind::Sha256, details.lock_kind);
+
+        // the private key of a k256 lock does not open a hash lock
+        let (info, withdraw_msg) = get_withdraw_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            SECRET_A.to_string(),
+        );
+        let err = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::InvalidSecret{})));
+
+        // reveal the preimage
+        let (info, withdraw_msg) = get_withdraw_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            preimage,
+        );
+        let res = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap();
+        assert_eq!(("action", "withdraw"), res.attributes[0]);
+        assert_eq!(1, res.messages.len());
+    }
+
+    #[test]
+    fn create_invalid_lock() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // a typo in the lock is caught before the funds are locked
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.replace('c', "x"),
+        );
+        let err = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::InvalidLockEncoding{})));
+    }
+
+    #[test]
+    fn create_invalid_id() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        for id in ["", "ab", "a_very_long_escrow_id", " ride", "ride\n", "   ", "ri\u{0}de", "123"] {
+            let (info, create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                id.to_string(),
+                USER_B_ADDR.to_string(),
+                LOCK_A.to_string(),
+            );
+            let err = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap_err();
+            assert!(matches!(err, ContractError::InvalidId{..}), "{:?}", id);
+        }
+
+        // 20 bytes of multi-byte characters is fine
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            "ride ✓✓✓✓✓".to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+    }
+
+    #[test]
+    fn create_token_not_allowed() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        let (_, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        let info = mock_info(
+            USER_A_ADDR,
+            &[Coin::new(100, REQUIRED_NATIVE_TOKEN_DENOM), Coin::new(100, "other")],
+        );
+        let err = execute(deps.as_mut(), mock_env(), info, create_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::TokenNotAllowed { token: "other".to_string() }, err);
+
+        let (info, receive_msg) = get_receive_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+            "other_cw20_token".to_string(),
+            REQUIRED_TOKEN_AMOUNT,
+        );
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), receive_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::TokenNotAllowed { token: "other_cw20_token".to_string() }, err);
+
+        // only the admin can update the allowlist
+        let update_msg = ExecuteMsg::UpdateAllowlist {
+            allowed_native: vec![],
+            allowed_cw20: vec!["other_cw20_token".to_string()],
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), update_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
+        execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), update_msg).unwrap();
+
+        execute(deps.as_mut(), mock_env(), info, receive_msg).unwrap();
+        let info = mock_info(USER_A_ADDR, &coins(100, REQUIRED_NATIVE_TOKEN_DENOM));
+        let err = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap_err();
+        assert_eq!(ContractError::TokenNotAllowed { token: REQUIRED_NATIVE_TOKEN_DENOM.to_string() }, err);
+    }
+
+    #[test]
+    fn create_expected_amount() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        let (info, mut create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.expected_amount = Some(Uint128::new(REQUIRED_TOKEN_AMOUNT + 1));
+        }
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), create_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::Escrow(EscrowError::InvalidDeposit {}), err);
+
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.expected_amount = Some(Uint128::new(REQUIRED_TOKEN_AMOUNT));
+        }
+        execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+    }
+
+    #[test]
+    fn top_up() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // create a native and a cw20 escrow
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        let (info, receive_msg) = get_receive_create_msg(
+            USER_A_ADDR.to_string(),
+            "cw20_ride".to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+            REQUIRED_CW20_TOKEN_ADDR.to_string(),
+            REQUIRED_TOKEN_AMOUNT,
+        );
+        execute(deps.as_mut(), mock_env(), info, receive_msg).unwrap();
+
+        // only the creator can top up
+        let top_up_msg = ExecuteMsg::TopUp { id: ESCROW_ID.to_string() };
+        let info = mock_info(USER_B_ADDR, &coins(20, REQUIRED_NATIVE_TOKEN_DENOM));
+        let err = execute(deps.as_mut(), mock_env(), info, top_up_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::Escrow(EscrowError::NotCreator {}), err);
+
+        let info = mock_info(USER_A_ADDR, &coins(20, REQUIRED_NATIVE_TOKEN_DENOM));
+        let res = execute(deps.as_mut(), mock_env(), info, top_up_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(res.attributes[2], attr("deposit", "120token"));
+        let data: TopUpResponse = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(Balance::from(coins(120, REQUIRED_NATIVE_TOKEN_DENOM)), data.deposit);
+
+        // a cw20 deposit can only be topped up with the same token
+        let top_up = Cw20ReceiveMsg {
+            sender: USER_A_ADDR.to_string(),
+            amount: Uint128::new(30),
+            msg: to_binary(&ReceiveMsg::TopUp { id: ESCROW_ID.to_string() }).unwrap(),
+        };
+        let info = mock_info(REQUIRED_CW20_TOKEN_ADDR, &[]);
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), ExecuteMsg::Receive(top_up.clone())).unwrap_err();
+        assert_eq!(ContractError::Escrow(EscrowError::MixedDeposit {}), err);
+
+        let top_up = Cw20ReceiveMsg {
+            msg: to_binary(&ReceiveMsg::TopUp { id: "cw20_ride".to_string() }).unwrap(),
+            ..top_up
+        };
+        let res = execute(deps.as_mut(), mock_env(), info, ExecuteMsg::Receive(top_up)).unwrap();
+        assert_eq!(res.attributes[2], attr("deposit", format!("130{}", REQUIRED_CW20_TOKEN_ADDR)));
+
+        let details = query_details(deps.as_ref(), mock_env(), "cw20_ride".to_string()).unwrap();
+        assert_eq!(
+            Balance::Cw20(Cw20CoinVerified {
+                address: Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR),
+                amount: Uint128::new(130),
+            }),
+            details.deposit
+        );
+    }
+
+    #[test]
+    fn refund_by_recipient() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+
+        // only the recipient can refund
+        let refund_msg = ExecuteMsg::Refund { id: ESCROW_ID.to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), refund_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::NotRecipient {}, err);
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), refund_msg.clone()).unwrap();
+        assert_eq!(("action", "refund"), res.attributes[0]);
+        assert_eq!(
+            messages(&res),
+            vec![CosmosMsg::from(BankMsg::Send {
+                to_address: USER_A_ADDR.to_string(),
+                amount: coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+            })]
+        );
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), refund_msg).unwrap_err();
+        assert_eq!(ContractError::Closed {}, err);
+    }
+
+    #[test]
+    fn mutual_settlement() {
+        let mut deps = mock_dependencies();
+
+        // instantiate with a 10% fee
+        let (info, mut instantiate_msg) = get_instantiate_msg();
+        instantiate_msg.fee_bps = 1000;
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+
+        let settle_msg = ExecuteMsg::ApproveSettlement {
+            id: ESCROW_ID.to_string(),
+            to_user_a: Balance::from(coins(60, REQUIRED_NATIVE_TOKEN_DENOM)),
+            to_user_b: Balance::from(coins(40, REQUIRED_NATIVE_TOKEN_DENOM)),
+        };
+
+        // the driver proposes a partial payout
+        let res = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), settle_msg.clone()).unwrap();
+        assert_eq!(0, res.messages.len());
+        assert_eq!(("action", "approve_settlement"), res.attributes[0]);
+
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        let settlement = details.settlement.unwrap();
+        assert_eq!(vec![Addr::unchecked(USER_B_ADDR)], settlement.approvals);
+        assert_eq!(EscrowStatus::Created, details.status);
+
+        // the rider agrees, and the split is paid out
+        let res = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), settle_msg).unwrap();
+        assert_eq!(("action", "settle"), res.attributes[0]);
+        assert_eq!(
+            messages(&res),
+            vec![
+                CosmosMsg::from(BankMsg::Send {
+                    to_address: USER_A_ADDR.to_string(),
+                    amount: coins(60, REQUIRED_NATIVE_TOKEN_DENOM),
+                }),
+                CosmosMsg::from(BankMsg::Send {
+                    to_address: USER_B_ADDR.to_string(),
+                    amount: coins(36, REQUIRED_NATIVE_TOKEN_DENOM),
+                }),
+                CosmosMsg::from(BankMsg::Send {
+                    to_address: FEE_COLLECTOR_ADDR.to_string(),
+                    amount: coins(4, REQUIRED_NATIVE_TOKEN_DENOM),
+                }),
+            ]
+        );
+
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(EscrowStatus::Cancelled, details.status);
+    }
+
+    #[test]
+    fn ride_lifecycle() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        let res = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        assert_eq!(
+            res.events,
+            vec![Event::new("escrow_status")
+                .add_attribute("id", ESCROW_ID)
+                .add_attribute("status", "created")]
+        );
+
+        // the ride has to be accepted before it starts
+        let start_msg = ExecuteMsg::Start { id: ESCROW_ID.to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), start_msg.clone()).unwrap_err();
+        assert_eq!(
+            ContractError::Escrow(EscrowError::InvalidTransition {
+                from: "created".to_string(),
+                to: "started".to_string(),
+            }),
+            err
+        );
+
+        // only the driver accepts
+        let accept_msg = ExecuteMsg::Accept { id: ESCROW_ID.to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), accept_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::NotRecipient {}, err);
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(60);
+        let accepted_at = env.block.time;
+        let res = execute(deps.as_mut(), env.clone(), mock_info(USER_B_ADDR, &[]), accept_msg).unwrap();
+        assert_eq!(("action", "accept"), res.attributes[0]);
+        assert_eq!(res.events[0].attributes[1], attr("status", "accepted"));
+
+        env.block.time = env.block.time.plus_seconds(300);
+        let started_at = env.block.time;
+        let res = execute(deps.as_mut(), env.clone(), mock_info(USER_B_ADDR, &[]), start_msg).unwrap();
+        assert_eq!(("action", "start"), res.attributes[0]);
+
+        // once started, the rider cannot cancel alone
+        let (info, cancel_msg) = get_cancel_msg(USER_A_ADDR.to_string(), ESCROW_ID.to_string());
+        let res = execute(deps.as_mut(), env.clone(), info, cancel_msg).unwrap();
+        assert_eq!(("action", "approve_cancel"), res.attributes[0]);
+        assert_eq!(0, res.messages.len());
+
+        // the ride is completed by the withdraw
+        env.block.time = env.block.time.plus_seconds(900);
+        let (info, withdraw_msg) = get_withdraw_msg(
+            USER_B_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            SECRET_A.to_string(),
+        );
+        let res = execute(deps.as_mut(), env.clone(), info, withdraw_msg).unwrap();
+        assert_eq!(res.events[0].attributes[1], attr("status", "completed"));
+
+        let details = query_details(deps.as_ref(), env.clone(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(EscrowStatus::Completed, details.status);
+        assert_eq!(
+            details.history,
+            vec![
+                StatusChange { status: EscrowStatus::Created, time: mock_env().block.time },
+                StatusChange { status: EscrowStatus::Accepted, time: accepted_at },
+                StatusChange { status: EscrowStatus::Started, time: started_at },
+                StatusChange { status: EscrowStatus::Completed, time: env.block.time },
+            ]
+        );
+    }
+
+    #[test]
+    fn cancel_after_accept_pays_fee() {
+        let mut deps = mock_dependencies();
+
+        // instantiate with a 10% platform fee
+        let (info, mut instantiate_msg) = get_instantiate_msg();
+        instantiate_msg.fee_bps = 1000;
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // two rides with a 30% cancellation fee
+        for id in ["ride1", "ride2"] {
+            let (info, mut create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                id.to_string(),
+                USER_B_ADDR.to_string(),
+                LOCK_A.to_string(),
+            );
+            if let ExecuteMsg::Create(ref mut msg) = create_msg {
+                msg.cancellation_fee = Some(CancellationFee::Bps(3_000));
+            }
+            execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        }
+
+        // cancelling before the driver accepted is free
+        let (info, cancel_msg) = get_cancel_msg(USER_A_ADDR.to_string(), "ride1".to_string());
+        let res = execute(deps.as_mut(), mock_env(), info, cancel_msg).unwrap();
+        assert_eq!(
+            messages(&res),
+            vec![CosmosMsg::from(BankMsg::Send {
+                to_address: USER_A_ADDR.to_string(),
+                amount: coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+            })]
+        );
+
+        // after acceptance, the driver gets the fee, minus the platform fee
+        let accept_msg = ExecuteMsg::Accept { id: "ride2".to_string() };
+        execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), accept_msg).unwrap();
+        let (info, cancel_msg) = get_cancel_msg(USER_A_ADDR.to_string(), "ride2".to_string());
+        let res = execute(deps.as_mut(), mock_env(), info, cancel_msg).unwrap();
+        assert_eq!(
+            messages(&res),
+            vec![
+                CosmosMsg::from(BankMsg::Send {
+                    to_address: USER_A_ADDR.to_string(),
+                    amount: coins(70, REQUIRED_NATIVE_TOKEN_DENOM),
+                }),
+                CosmosMsg::from(BankMsg::Send {
+                    to_address: USER_B_ADDR.to_string(),
+                    amount: coins(27, REQUIRED_NATIVE_TOKEN_DENOM),
+                }),
+                CosmosMsg::from(BankMsg::Send {
+                    to_address: FEE_COLLECTOR_ADDR.to_string(),
+                    amount: coins(3, REQUIRED_NATIVE_TOKEN_DENOM),
+                }),
+            ]
+        );
+        // and the amounts are kept for the archive
+        let escrow = escrows().load(&deps.storage, "ride2").unwrap();
+        assert_eq!(
+            Paid {
+                to_user_a: Balance::from(coins(70, REQUIRED_NATIVE_TOKEN_DENOM)),
+                to_user_b: Balance::from(coins(27, REQUIRED_NATIVE_TOKEN_DENOM)),
+                fee: Balance::from(coins(3, REQUIRED_NATIVE_TOKEN_DENOM)),
+            },
+            escrow.paid
+        );
+    }
+
+    #[test]
+    fn failed_batch_item_leaves_no_state() {
+        let mut deps = mock_dependencies();
+
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // the second item fails after creating its escrow
+        let res = run_batch(deps.as_mut(), "batch_create", vec!["ride1", "ride2"], true, |mut deps, id| {
+            let (info, create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                id.to_string(),
+                USER_B_ADDR.to_string(),
+                LOCK_A.to_string(),
+            );
+            let msg = match create_msg {
+                ExecuteMsg::Create(msg) => msg,
+                _ => unreachable!(),
+            };
+            let res = execute_create(deps.branch(), mock_env(), msg, Balance::from(info.funds), &info.sender);
+            match id {
+                "ride2" => (Some(id.to_string()), Err(ContractError::Closed {})),
+                _ => (Some(id.to_string()), res),
+            }
+        })
+        .unwrap();
+        assert_eq!(res.attributes[1..], [attr("succeeded", "1"), attr("failed", "1")]);
+
+        // its escrow, indexes and counters are dropped with it
+        assert_eq!(vec!["ride1".to_string()], query_list(deps.as_ref(), None, None).unwrap().escrows);
+        assert_eq!(1, query_stats(deps.as_ref()).unwrap().open);
+        assert_eq!(1, NONCE_COUNT.load(&deps.storage).unwrap());
+        let page = escrows_by_creator(&deps.storage, &Addr::unchecked(USER_A_ADDR), None, 10).unwrap();
+        assert_eq!(1, page.len());
+    }
+
+    #[test]
+    fn batches() {
+        let mut deps = mock_dependencies();
+
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        let item = |id: Option<&str>, amount: u128| {
+            let (_, create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                String::new(),
+                USER_B_ADDR.to_string(),
+                LOCK_A.to_string(),
+            );
+            match create_msg {
+                ExecuteMsg::Create(mut escrow) => {
+                    escrow.id = id.map(str::to_string);
+                    BatchCreateItem { amount: Uint128::new(amount), escrow }
+                }
+                _ => unreachable!(),
+            }
         };
-        let receive = Cw20ReceiveMsg {
-            sender: sender_addr,
-            amount: Uint128::new(required_token_amount),
-            msg: to_binary(&ReceiveMsg::Create(create.clone())).unwrap(),
+        let batch_create = |skip_failures: bool| ExecuteMsg::BatchCreate {
+            items: vec![item(Some("ride1"), 100), item(Some("ride1"), 50), item(None, 30)],
+            skip_failures,
         };
-        let info = mock_info(&required_token_addr, &[]);
-        let msg = ExecuteMsg::Receive(receive.clone());
-        return (info, msg);
+
+        // the deposit must match the items
+        let info = mock_info(USER_A_ADDR, &coins(100, REQUIRED_NATIVE_TOKEN_DENOM));
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), batch_create(false)).unwrap_err();
+        assert_eq!(ContractError::InvalidBatchDeposit {}, err);
+        let overflow = ExecuteMsg::BatchCreate {
+            items: vec![item(Some("ride1"), u128::MAX), item(Some("ride2"), 101)],
+            skip_failures: false,
+        };
+        let err = execute(deps.as_mut(), mock_env(), info, overflow).unwrap_err();
+        assert_eq!(ContractError::InvalidBatchDeposit {}, err);
+
+        // by default, a failed item fails the batch. The mock storage is not
+        // reverted, so the batch is then retried on a fresh contract.
+        let info = mock_info(USER_A_ADDR, &coins(180, REQUIRED_NATIVE_TOKEN_DENOM));
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), batch_create(false)).unwrap_err();
+        assert_eq!(ContractError::AlreadyInUse {}, err);
+
+        let mut deps = mock_dependencies();
+        let (instantiate_info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), instantiate_info, instantiate_msg).unwrap();
+
+        // or it is skipped and its deposit refunded
+        let res = execute(deps.as_mut(), mock_env(), info, batch_create(true)).unwrap();
+        assert_eq!(res.attributes[1], attr("succeeded", "2"));
+        assert_eq!(res.attributes[2], attr("failed", "1"));
+        let data: BatchResponse = from_binary(res.data.as_ref().unwrap()).unwrap();
+        assert_eq!(
+            data.results,
+            vec![
+                BatchResult { id: Some("ride1".to_string()), error: None },
+                BatchResult { id: Some("ride1".to_string()), error: Some("Escrow id already in use".to_string()) },
+                BatchResult { id: Some("1".to_string()), error: None },
+            ]
+        );
+        assert_eq!(
+            messages(&res),
+            vec![CosmosMsg::from(BankMsg::Send {
+                to_address: USER_A_ADDR.to_string(),
+                amount: coins(50, REQUIRED_NATIVE_TOKEN_DENOM),
+            })]
+        );
+        let details = query_details(deps.as_ref(), mock_env(), "1".to_string()).unwrap();
+        assert_eq!(Balance::from(coins(30, REQUIRED_NATIVE_TOKEN_DENOM)), details.deposit);
+
+        // withdraw one escrow, skipping the unknown one
+        let withdraw = |id: &str| WithdrawMsg {
+            id: id.to_string(),
+            secret: Some(SECRET_A.to_string()),
+            signature: None,
+            milestone: None,
+            payout_msg: None,
+        };
+        let batch_withdraw = ExecuteMsg::BatchWithdraw {
+            items: vec![withdraw("ride1"), withdraw("unknown")],
+            skip_failures: true,
+        };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("relayer", &[]), batch_withdraw).unwrap();
+        assert_eq!(
+            messages(&res),
+            vec![CosmosMsg::from(BankMsg::Send {
+                to_address: USER_B_ADDR.to_string(),
+                amount: coins(100, REQUIRED_NATIVE_TOKEN_DENOM),
+            })]
+        );
+        let data: BatchResponse = from_binary(res.data.as_ref().unwrap()).unwrap();
+        assert_eq!(None, data.results[0].error);
+        assert!(data.results[1].error.is_some());
+
+        // cancelling a withdrawn escrow fails the whole batch
+        let batch_cancel = |skip_failures: bool| ExecuteMsg::BatchCancel {
+            ids: vec!["ride1".to_string(), "1".to_string()],
+            skip_failures,
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), batch_cancel(false)).unwrap_err();
+        assert_eq!(ContractError::Closed {}, err);
+        let details = query_details(deps.as_ref(), mock_env(), "1".to_string()).unwrap();
+        assert_eq!(EscrowStatus::Created, details.status);
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), batch_cancel(true)).unwrap();
+        assert_eq!(res.attributes[1], attr("succeeded", "1"));
+        assert_eq!(res.events[0].ty, "batch_item");
+        assert_eq!(res.events[0].attributes[0], attr("action", "cancel"));
+        let details = query_details(deps.as_ref(), mock_env(), "1".to_string()).unwrap();
+        assert_eq!(EscrowStatus::Cancelled, details.status);
     }
 
-    fn get_withdraw_msg(
-        sender_addr: String,
-        escrow_id: String,
-        secret: String,
-    ) -> (MessageInfo, ExecuteMsg) {
-        let withdraw = WithdrawMsg {
-            id: escrow_id,
-            secret: secret,
+    #[test]
+    fn failed_payout_is_retried() {
+        let mut deps = mock_dependencies();
+
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+
+        // payouts only report back on error
+        let (info, withdraw_msg) = get_withdraw_msg(USER_B_ADDR.to_string(), ESCROW_ID.to_string(), SECRET_A.to_string());
+        let res = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(ReplyOn::Error, res.messages[0].reply_on);
+        let payout = res.messages[0].clone();
+
+        // the transfer is rejected, the escrow keeps it
+        let failure = Reply {
+            id: payout.id,
+            result: SubMsgResult::Err("recipient is blocked".to_string()),
         };
-        let balance = coins(100, "tokens");
-        let info = mock_info(&sender_addr,  &balance);
-        let msg = ExecuteMsg::Withdraw(withdraw.clone());
-        return (info, msg);
+        let res = reply(deps.as_mut(), mock_env(), failure.clone()).unwrap();
+        assert_eq!(res.attributes[2], attr("payout_error", "recipient is blocked"));
+        assert_eq!(res.events[0].attributes[1], attr("status", "payout_failed"));
+
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(EscrowStatus::PayoutFailed, details.status);
+        assert_eq!(vec![payout.msg.clone()], details.failed_payouts);
+
+        // a closed escrow cannot be cancelled meanwhile
+        let (info, cancel_msg) = get_cancel_msg(USER_A_ADDR.to_string(), ESCROW_ID.to_string());
+        let err = execute(deps.as_mut(), mock_env(), info, cancel_msg).unwrap_err();
+        assert_eq!(ContractError::Closed {}, err);
+
+        // the reply is only handled once
+        reply(deps.as_mut(), mock_env(), failure).unwrap_err();
+
+        // anyone can send the payout again, which completes the escrow
+        let retry_msg = ExecuteMsg::RetryPayout { id: ESCROW_ID.to_string() };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), retry_msg.clone()).unwrap();
+        assert_eq!(vec![payout.msg], messages(&res));
+        assert_ne!(payout.id, res.messages[0].id);
+        let success = Reply {
+            id: res.messages[0].id,
+            result: SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }),
+        };
+        reply(deps.as_mut(), mock_env(), success).unwrap();
+
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(EscrowStatus::Completed, details.status);
+        assert!(details.failed_payouts.is_empty());
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), retry_msg).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::NoFailedPayout{})));
     }
 
-    fn get_cancel_msg(
-        sender_addr: String,
-        escrow_id: String,
-    ) -> (MessageInfo, ExecuteMsg) {
-        let info = mock_info(&sender_addr, &[]);
-        let msg = ExecuteMsg::Cancel{id: escrow_id};
-        return (info, msg);
+    #[test]
+    fn ledger_payouts() {
+        let mut deps = mock_dependencies();
+
+        // instantiate with a 10% platform fee
+        let (info, mut instantiate_msg) = get_instantiate_msg();
+        instantiate_msg.fee_bps = 1000;
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // the driver opts in to the ledger
+        let mode_msg = ExecuteMsg::SetPayoutMode { mode: PayoutMode::Ledger };
+        let res = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), mode_msg).unwrap();
+        assert_eq!(res.attributes[2], attr("mode", "ledger"));
+
+        // completing two rides transfers nothing to the driver
+        for id in ["ride1", "ride2"] {
+            let (info, create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                id.to_string(),
+                USER_B_ADDR.to_string(),
+                LOCK_A.to_string(),
+            );
+            execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+            let (info, withdraw_msg) = get_withdraw_msg(USER_B_ADDR.to_string(), id.to_string(), SECRET_A.to_string());
+            let res = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap();
+            assert_eq!(
+                messages(&res),
+                vec![CosmosMsg::from(BankMsg::Send {
+                    to_address: FEE_COLLECTOR_ADDR.to_string(),
+                    amount: coins(10, REQUIRED_NATIVE_TOKEN_DENOM),
+                })]
+            );
+        }
+
+        let balance = query_balance(deps.as_ref(), USER_B_ADDR.to_string()).unwrap();
+        assert_eq!(PayoutMode::Ledger, balance.payout_mode);
+        assert_eq!(coins(180, REQUIRED_NATIVE_TOKEN_DENOM), balance.native);
+
+        // only the credited tokens can be claimed
+        let claim_msg = ExecuteMsg::Claim {
+            tokens: vec![Denom::Cw20(Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR))],
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), claim_msg).unwrap_err();
+        assert_eq!(ContractError::NothingToClaim {}, err);
+
+        // everything is swept in one transfer
+        let claim_msg = ExecuteMsg::Claim { tokens: vec![] };
+        let res = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), claim_msg.clone()).unwrap();
+        assert_eq!(
+            messages(&res),
+            vec![CosmosMsg::from(BankMsg::Send {
+                to_address: USER_B_ADDR.to_string(),
+                amount: coins(180, REQUIRED_NATIVE_TOKEN_DENOM),
+            })]
+        );
+        let balance = query_balance(deps.as_ref(), USER_B_ADDR.to_string()).unwrap();
+        assert!(balance.native.is_empty());
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), claim_msg).unwrap_err();
+        assert_eq!(ContractError::NothingToClaim {}, err);
+    }
+
+    #[test]
+    fn shared_fare() {
+        let mut deps = mock_dependencies();
+
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // user_a opens a fare of 200 with their share of 100
+        let (info, mut create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.target = Some(Uint128::new(200));
+        }
+        execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+
+        // it cannot be withdrawn before it is fully funded
+        let (info, withdraw_msg) = get_withdraw_msg(USER_B_ADDR.to_string(), ESCROW_ID.to_string(), SECRET_A.to_string());
+        let err = execute(deps.as_mut(), mock_env(), info, withdraw_msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::NotFunded{})));
+
+        // two riders contribute, without exceeding the target
+        let contribute = ExecuteMsg::Contribute { id: ESCROW_ID.to_string() };
+        let info = mock_info("rider1", &coins(60, REQUIRED_NATIVE_TOKEN_DENOM));
+        let res = execute(deps.as_mut(), mock_env(), info, contribute.clone()).unwrap();
+        assert_eq!(res.attributes[3], attr("amount", "60"));
+        assert_eq!(res.attributes[4], attr("funded", "false"));
+        let info = mock_info("rider2", &coins(50, REQUIRED_NATIVE_TOKEN_DENOM));
+        let err = execute(deps.as_mut(), mock_env(), info, contribute.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::Overfunded{})));
+        let info = mock_info("rider2", &coins(40, REQUIRED_NATIVE_TOKEN_DENOM));
+        let res = execute(deps.as_mut(), mock_env(), info, contribute).unwrap();
+        assert_eq!(res.attributes[4], attr("funded", "true"));
+
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(Balance::from(coins(200, REQUIRED_NATIVE_TOKEN_DENOM)), details.deposit);
+        assert!(details.funded);
+
+        let res = query_contributions(deps.as_ref(), ESCROW_ID.to_string(), Some("rider1".to_string()), None).unwrap();
+        assert_eq!(
+            res.contributions,
+            vec![
+                Contribution { address: "rider2".to_string(), amount: Uint128::new(40) },
+                Contribution { address: USER_A_ADDR.to_string(), amount: Uint128::new(100) },
+            ]
+        );
+
+        // cancelling refunds every rider their share
+        let (info, cancel_msg) = get_cancel_msg(USER_A_ADDR.to_string(), ESCROW_ID.to_string());
+        let res = execute(deps.as_mut(), mock_env(), info, cancel_msg).unwrap();
+        let refund = |to: &str, amount: u128| CosmosMsg::from(BankMsg::Send {
+            to_address: to.to_string(),
+            amount: coins(amount, REQUIRED_NATIVE_TOKEN_DENOM),
+        });
+        assert_eq!(
+            messages(&res),
+            vec![refund("rider1", 60), refund("rider2", 40), refund(USER_A_ADDR, 100)]
+        );
+    }
+
+    #[test]
+    fn milestones_release_in_order() {
+        let mut deps = mock_dependencies();
+
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // two legs: 60 on a k256 lock, then 40 on a hash lock
+        let preimage = hex::encode(b"second drop-off");
+        let leg = |amount: u128, lock: String, lock_kind: LockKind| MilestoneMsg {
+            amount: Uint128::new(amount),
+            lock,
+            lock_kind,
+        };
+        let (info, mut create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+
+        // the milestones must add up to the deposit
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.milestones = vec![leg(60, LOCK_A.to_string(), LockKind::K256)];
+        }
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), create_msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::InvalidMilestones{})));
+
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.milestones.push(leg(40, hex::encode(Sha256::digest(hex::decode(&preimage).unwrap())), LockKind::Sha256));
+        }
+        execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+
+        let withdraw = |milestone: Option<u32>, secret: &str| ExecuteMsg::Withdraw(WithdrawMsg {
+            id: ESCROW_ID.to_string(),
+            secret: Some(secret.to_string()),
+            signature: None,
+            milestone,
+            payout_msg: None,
+        });
+        let info = mock_info(USER_B_ADDR, &[]);
+
+        // a milestone is required, and only the next one can be released
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), withdraw(None, SECRET_A)).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::MilestoneRequired{})));
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), withdraw(Some(1), &preimage)).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::InvalidMilestone{})));
+
+        // the first leg pays its tranche only, the escrow stays open
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), withdraw(Some(0), SECRET_A)).unwrap();
+        assert_eq!(res.attributes[2], attr("milestone", "0"));
+        assert!(res.events.is_empty());
+        assert_eq!(
+            messages(&res),
+            vec![CosmosMsg::from(BankMsg::Send {
+                to_address: USER_B_ADDR.to_string(),
+                amount: coins(60, REQUIRED_NATIVE_TOKEN_DENOM),
+            })]
+        );
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(EscrowStatus::Created, details.status);
+        assert_eq!(
+            vec![true, false],
+            details.milestones.iter().map(|m| m.released).collect::<Vec<_>>()
+        );
+
+        // the same leg cannot be released twice
+        let err = execute(deps.as_mut(), mock_env(), info, withdraw(Some(0), SECRET_A)).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::InvalidMilestone{})));
+
+        // cancelling refunds the unreleased remainder
+        let (info, cancel_msg) = get_cancel_msg(USER_A_ADDR.to_string(), ESCROW_ID.to_string());
+        let res = execute(deps.as_mut(), mock_env(), info, cancel_msg).unwrap();
+        assert_eq!(
+            messages(&res),
+            vec![CosmosMsg::from(BankMsg::Send {
+                to_address: USER_A_ADDR.to_string(),
+                amount: coins(40, REQUIRED_NATIVE_TOKEN_DENOM),
+            })]
+        );
+        let escrow = escrows().load(&deps.storage, ESCROW_ID).unwrap();
+        assert_eq!(Balance::from(coins(40, REQUIRED_NATIVE_TOKEN_DENOM)), escrow.paid.to_user_a);
+        assert_eq!(Balance::from(coins(60, REQUIRED_NATIVE_TOKEN_DENOM)), escrow.paid.to_user_b);
+    }
+
+    #[test]
+    fn create_assigned_id() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // an escrow stored under a numeric id before ids were validated
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        let escrow = escrows().load(&deps.storage, ESCROW_ID).unwrap();
+        escrows().save(&mut deps.storage, "2", &escrow).unwrap();
+
+        for expected in ["1", "3"] {
+            let (info, mut create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                ESCROW_ID.to_string(),
+                USER_B_ADDR.to_string(),
+                LOCK_A.to_string(),
+            );
+            if let ExecuteMsg::Create(ref mut msg) = create_msg {
+                msg.id = None;
+            }
+            let res = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+            assert_eq!(res.attributes[1], attr("id", expected));
+            let data: CreateResponse = from_binary(&res.data.unwrap()).unwrap();
+            assert_eq!(expected, data.id);
+            assert!(escrows().has(&deps.storage, expected));
+        }
+    }
+
+    #[test]
+    fn list_queries() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // user_a creates three escrows, two with user_b and one with someone else
+        for (id, user_b) in [("ride1", USER_B_ADDR), ("ride2", "user_c"), ("ride3", USER_B_ADDR)] {
+            let (info, create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                id.to_string(),
+                user_b.to_string(),
+                LOCK_A.to_string(),
+            );
+            let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        }
+
+        // user_a cancels the first one
+        let (info, cancel_msg) = get_cancel_msg(USER_A_ADDR.to_string(), "ride1".to_string());
+        let _ = execute(deps.as_mut(), mock_env(), info, cancel_msg).unwrap();
+
+        let ids = |res: DetailsListResponse| res.escrows.into_iter().map(|d| d.id).collect::<Vec<_>>();
+
+        let res = query_list_by_creator(deps.as_ref(), mock_env(), USER_A_ADDR.to_string(), None, None).unwrap();
+        assert_eq!(vec!["ride1", "ride2", "ride3"], ids(res));
+
+        let res = query_list_by_creator(deps.as_ref(), mock_env(), USER_A_ADDR.to_string(), Some("ride1".to_string()), Some(1)).unwrap();
+        assert_eq!(vec!["ride2"], ids(res));
+
+        let res = query_list_by_recipient(deps.as_ref(), mock_env(), USER_B_ADDR.to_string(), None, None).unwrap();
+        assert_eq!(vec!["ride1", "ride3"], ids(res));
+
+        let res = query_list_open(deps.as_ref(), mock_env(), None, None).unwrap();
+        assert_eq!(vec!["ride2", "ride3"], ids(res));
+
+        let res = query_list(deps.as_ref(), Some("ride2".to_string()), None).unwrap();
+        assert_eq!(vec!["ride3".to_string()], res.escrows);
+    }
+
+    #[test]
+    fn payout_msg_notifies_contract() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        let hook = to_binary(&"ride_revenue").unwrap();
+
+        // native coins are attached to an execute of the payout_msg of the escrow
+        let (info, mut create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            "vault".to_string(),
+            LOCK_A.to_string(),
+        );
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.payout_msg = Some(hook.clone());
+        }
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        let (info, withdraw_msg) = get_withdraw_msg("vault".to_string(), ESCROW_ID.to_string(), SECRET_A.to_string());
+        let res = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap();
+        assert_eq!(
+            messages(&res),
+            vec![CosmosMsg::from(WasmMsg::Execute {
+                contract_addr: "vault".to_string(),
+                msg: hook.clone(),
+                funds: coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+            })]
+        );
+
+        // cw20 tokens are sent with the payout_msg of the withdraw
+        let (info, receive_msg) = get_receive_create_msg(
+            USER_A_ADDR.to_string(),
+            "ride2".to_string(),
+            "vault".to_string(),
+            LOCK_A.to_string(),
+            REQUIRED_CW20_TOKEN_ADDR.to_string(),
+            REQUIRED_TOKEN_AMOUNT,
+        );
+        let _ = execute(deps.as_mut(), mock_env(), info, receive_msg).unwrap();
+
+        // a relayed signature only covers the payout_msg it was made with
+        let key = SigningKey::from_bytes(&hex::decode(SECRET_A).unwrap()).unwrap();
+        let nonce = query_details(deps.as_ref(), mock_env(), "ride2".to_string()).unwrap().nonce;
+        let payload = withdraw_payload(
+            &mock_env().contract.address,
+            "ride2",
+            nonce,
+            &Addr::unchecked("vault"),
+            None,
+            None,
+        ).unwrap();
+        let signature: Signature = key.sign(&payload);
+        let withdraw = |signature: &Signature| ExecuteMsg::Withdraw(WithdrawMsg {
+            id: "ride2".to_string(),
+            secret: None,
+            signature: Some(hex::encode(signature.as_ref())),
+            milestone: None,
+            payout_msg: Some(hook.clone()),
+        });
+        let err = execute(deps.as_mut(), mock_env(), mock_info("relayer", &[]), withdraw(&signature)).unwrap_err();
+        assert!(matches!(err, ContractError::Escrow(EscrowError::InvalidSignature {})));
+
+        let payload = withdraw_payload(
+            &mock_env().contract.address,
+            "ride2",
+            nonce,
+            &Addr::unchecked("vault"),
+            None,
+            Some(&hook),
+        ).unwrap();
+        let signature: Signature = key.sign(&payload);
+        let res = execute(deps.as_mut(), mock_env(), mock_info("relayer", &[]), withdraw(&signature)).unwrap();
+        assert_eq!(
+            messages(&res),
+            vec![CosmosMsg::from(WasmMsg::Execute {
+                contract_addr: REQUIRED_CW20_TOKEN_ADDR.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Send {
+                    contract: "vault".to_string(),
+                    amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
+                    msg: hook.clone(),
+                }).unwrap(),
+                funds: vec![],
+            })]
+        );
+        // with the secret, a payout_msg is only taken from the recipient, so
+        // that whoever saw the secret cannot front-run it with a failing hook
+        for (id, sender) in ["ride3", "ride4"].into_iter().zip(["mallory", "vault"]) {
+            let (info, create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                id.to_string(),
+                "vault".to_string(),
+                LOCK_A.to_string(),
+            );
+            let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+            let withdraw_msg = ExecuteMsg::Withdraw(WithdrawMsg {
+                id: id.to_string(),
+                secret: Some(SECRET_A.to_string()),
+                signature: None,
+                milestone: None,
+                payout_msg: Some(to_binary(&"reject").unwrap()),
+            });
+            let res = execute(deps.as_mut(), mock_env(), mock_info(sender, &[]), withdraw_msg).unwrap();
+            let expected = match sender {
+                "mallory" => CosmosMsg::from(BankMsg::Send {
+                    to_address: "vault".to_string(),
+                    amount: coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+                }),
+                _ => CosmosMsg::from(WasmMsg::Execute {
+                    contract_addr: "vault".to_string(),
+                    msg: to_binary(&"reject").unwrap(),
+                    funds: coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+                }),
+            };
+            assert_eq!(vec![expected], messages(&res));
+        }
+
+        // a payout_msg that would not reach the recipient is refused
+        let destination = IbcDestinationMsg {
+            channel_id: "channel-0".to_string(),
+            remote_address: "osmo1vault".to_string(),
+            timeout_seconds: 600,
+            fallback: None,
+        };
+        for (id, ibc_destination) in [("ride5", Some(destination)), ("ride6", None)] {
+            let (info, mut create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                id.to_string(),
+                "vault".to_string(),
+                LOCK_A.to_string(),
+            );
+            if let ExecuteMsg::Create(ref mut msg) = create_msg {
+                msg.ibc_destination = ibc_destination;
+            }
+            let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        }
+        let withdraw = |id: &str| ExecuteMsg::Withdraw(WithdrawMsg {
+            id: id.to_string(),
+            secret: Some(SECRET_A.to_string()),
+            signature: None,
+            milestone: None,
+            payout_msg: Some(hook.clone()),
+        });
+        let err = execute(deps.as_mut(), mock_env(), mock_info("vault", &[]), withdraw("ride5")).unwrap_err();
+        assert_eq!(ContractError::PayoutMsgNotDelivered {}, err);
+        let mode_msg = ExecuteMsg::SetPayoutMode { mode: PayoutMode::Ledger };
+        let _ = execute(deps.as_mut(), mock_env(), mock_info("vault", &[]), mode_msg).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("vault", &[]), withdraw("ride6")).unwrap_err();
+        assert_eq!(ContractError::PayoutMsgNotDelivered {}, err);
+    }
+
+    #[test]
+    fn admin_transfer_and_pause() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract, "anyone" is the admin
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        // the admin role is transferred in two steps
+        let transfer = ExecuteMsg::TransferAdmin { admin: "new_admin".to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), transfer.clone()).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
+        let res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), transfer).unwrap();
+        assert_eq!(
+            res.events,
+            vec![Event::new("escrow_admin").add_attributes(vec![
+                ("action", "transfer_admin"),
+                ("admin", "anyone"),
+                ("pending_admin", "new_admin"),
+            ])]
+        );
+        let config = query_config(deps.as_ref()).unwrap();
+        assert_eq!(("anyone", Some("new_admin".to_string())), (config.admin.as_str(), config.pending_admin));
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), ExecuteMsg::AcceptAdmin {}).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
+        let _ = execute(deps.as_mut(), mock_env(), mock_info("new_admin", &[]), ExecuteMsg::AcceptAdmin {}).unwrap();
+        let config = query_config(deps.as_ref()).unwrap();
+        assert_eq!(("new_admin", None), (config.admin.as_str(), config.pending_admin));
+
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            ESCROW_ID.to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+
+        // only the current admin can pause
+        let err = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), ExecuteMsg::Pause {}).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
+        let res = execute(deps.as_mut(), mock_env(), mock_info("new_admin", &[]), ExecuteMsg::Pause {}).unwrap();
+        assert_eq!(res.events[0].attributes[0], attr("action", "pause"));
+        assert!(query_config(deps.as_ref()).unwrap().paused);
+
+        // no new deposits while paused
+        let (info, create_msg) = get_create_msg(
+            USER_A_ADDR.to_string(),
+            "ride2".to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+        );
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), create_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::Paused {}, err);
+        let (cw20_info, receive_msg) = get_receive_create_msg(
+            USER_A_ADDR.to_string(),
+            "ride3".to_string(),
+            USER_B_ADDR.to_string(),
+            LOCK_A.to_string(),
+            REQUIRED_CW20_TOKEN_ADDR.to_string(),
+            REQUIRED_TOKEN_AMOUNT,
+        );
+        let err = execute(deps.as_mut(), mock_env(), cw20_info, receive_msg).unwrap_err();
+        assert_eq!(ContractError::Paused {}, err);
+        let top_up = ExecuteMsg::TopUp { id: ESCROW_ID.to_string() };
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), top_up).unwrap_err();
+        assert_eq!(ContractError::Paused {}, err);
+
+        // but the existing escrow can still be withdrawn
+        let (withdraw_info, withdraw_msg) = get_withdraw_msg(USER_B_ADDR.to_string(), ESCROW_ID.to_string(), SECRET_A.to_string());
+        let _ = execute(deps.as_mut(), mock_env(), withdraw_info, withdraw_msg).unwrap();
+
+        let _ = execute(deps.as_mut(), mock_env(), mock_info("new_admin", &[]), ExecuteMsg::Unpause {}).unwrap();
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+    }
+
+    #[test]
+    fn stats_follow_escrows() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract
+        let (info, instantiate_msg) = get_instantiate_msg();
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+
+        for id in ["ride1", "ride2", "ride3"] {
+            let (info, create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                id.to_string(),
+                USER_B_ADDR.to_string(),
+                LOCK_A.to_string(),
+            );
+            let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        }
+
+        // ride1 is cancelled, ride2 withdrawn and ride3 topped up
+        let (info, cancel_msg) = get_cancel_msg(USER_A_ADDR.to_string(), "ride1".to_string());
+        let _ = execute(deps.as_mut(), mock_env(), info, cancel_msg).unwrap();
+        let (info, withdraw_msg) = get_withdraw_msg(USER_B_ADDR.to_string(), "ride2".to_string(), SECRET_A.to_string());
+        let _ = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap();
+        let info = mock_info(USER_A_ADDR, &coins(50, REQUIRED_NATIVE_TOKEN_DENOM));
+        let _ = execute(deps.as_mut(), mock_env(), info, ExecuteMsg::TopUp { id: "ride3".to_string() }).unwrap();
+
+        let stats = query_stats(deps.as_ref()).unwrap();
+        assert_eq!(
+            stats,
+            StatsResponse {
+                open: 1,
+                withdrawn: 1,
+                cancelled: 1,
+                locked: Totals { native: coins(150, REQUIRED_NATIVE_TOKEN_DENOM), cw20: vec![] },
+                released: Totals { native: coins(200, REQUIRED_NATIVE_TOKEN_DENOM), cw20: vec![] },
+            }
+        );
+    }
+
+    #[test]
+    fn prune_settled_escrows() {
+        let mut deps = mock_dependencies();
+
+        // instantiate an empty contract with a 10% platform fee, "anyone" is
+        // the admin
+        let (info, mut instantiate_msg) = get_instantiate_msg();
+        instantiate_msg.fee_bps = 1000;
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
+        let admin = mock_info("anyone", &[]);
+
+        let create = |deps: DepsMut, id: &str| {
+            let (info, create_msg) = get_create_msg(
+                USER_A_ADDR.to_string(),
+                id.to_string(),
+                USER_B_ADDR.to_string(),
+                LOCK_A.to_string(),
+            );
+            execute(deps, mock_env(), info, create_msg)
+        };
+        for id in ["ride1", "ride2", "ride3"] {
+            create(deps.as_mut(), id).unwrap();
+        }
+
+        // ride1 is cancelled, ride2 is withdrawn later on, ride3 stays open
+        let (info, cancel_msg) = get_cancel_msg(USER_A_ADDR.to_string(), "ride1".to_string());
+        let _ = execute(deps.as_mut(), mock_env(), info, cancel_msg).unwrap();
+        let mut later = mock_env();
+        later.block.time = later.block.time.plus_seconds(100);
+        let (info, withdraw_msg) = get_withdraw_msg(USER_B_ADDR.to_string(), "ride2".to_string(), SECRET_A.to_string());
+        let _ = execute(deps.as_mut(), later.clone(), info, withdraw_msg).unwrap();
+
+        let prune = |ids: &[&str], before: Option<Timestamp>, reuse_ids: bool| ExecuteMsg::Prune {
+            ids: ids.iter().map(|id| id.to_string()).collect(),
+            before,
+            limit: None,
+            reuse_ids,
+        };
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), prune(&["ride1"], None, false)).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
+        let err = execute(deps.as_mut(), mock_env(), admin.clone(), prune(&[], None, false)).unwrap_err();
+        assert_eq!(ContractError::InvalidPrune {}, err);
+        let err = execute(deps.as_mut(), mock_env(), admin.clone(), prune(&["ride3"], None, false)).unwrap_err();
+        assert_eq!(ContractError::NotSettled { id: "ride3".to_string() }, err);
+
+        // only ride1 was closed before the withdraw
+        let before = mock_env().block.time.plus_seconds(50);
+        let res = execute(deps.as_mut(), later.clone(), admin.clone(), prune(&[], Some(before), false)).unwrap();
+        assert_eq!(res.attributes, vec![attr("action", "prune"), attr("pruned", "1")]);
+        assert_eq!(res.events[0].ty, "escrow_admin");
+        assert_eq!(res.events[1], Event::new("escrow_pruned").add_attribute("id", "ride1"));
+        let res = query_list(deps.as_ref(), None, None).unwrap();
+        assert_eq!(vec!["ride2".to_string(), "ride3".to_string()], res.escrows);
+
+        let res = execute(deps.as_mut(), later.clone(), admin, prune(&["ride2"], None, true)).unwrap();
+        assert_eq!(res.attributes[1], attr("pruned", "1"));
+        assert!(query_details(deps.as_ref(), mock_env(), "ride2".to_string()).is_err());
+
+        let res = query_history(deps.as_ref(), None, None, None).unwrap();
+        assert_eq!(
+            res.escrows,
+            vec![
+                ArchiveRecord {
+                    index: 1,
+                    id: "ride1".to_string(),
+                    user_a: USER_A_ADDR.to_string(),
+                    user_b: USER_B_ADDR.to_string(),
+                    deposit: Balance::from(coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM)),
+                    outcome: EscrowStatus::Cancelled,
+                    paid: Paid {
+                        to_user_a: Balance::from(coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM)),
+                        to_user_b: Balance::default(),
+                        fee: Balance::default(),
+                    },
+                    closed_at: mock_env().block.time,
+                    pruned_at: later.block.height,
+                    reserved: true,
+                },
+                ArchiveRecord {
+                    index: 2,
+                    id: "ride2".to_string(),
+                    user_a: USER_A_ADDR.to_string(),
+                    user_b: USER_B_ADDR.to_string(),
+                    deposit: Balance::from(coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM)),
+                    outcome: EscrowStatus::Completed,
+                    paid: Paid {
+                        to_user_a: Balance::default(),
+                        to_user_b: Balance::from(coins(90, REQUIRED_NATIVE_TOKEN_DENOM)),
+                        fee: Balance::from(coins(10, REQUIRED_NATIVE_TOKEN_DENOM)),
+                    },
+                    closed_at: later.block.time,
+                    pruned_at: later.block.height,
+                    reserved: false,
+                },
+            ]
+        );
+        let res = query_history(deps.as_ref(), Some("ride2".to_string()), None, None).unwrap();
+        assert_eq!(vec![2], res.escrows.iter().map(|r| r.index).collect::<Vec<_>>());
+
+        // the id of ride1 stays taken, the one of ride2 can be reused
+        let err = create(deps.as_mut(), "ride1").unwrap_err();
+        assert_eq!(ContractError::AlreadyInUse {}, err);
+        create(deps.as_mut(), "ride2").unwrap();
     }
 
     #[test]
-    fn withdraw_happy_native() {
+    fn migrate_checks_contract_and_version() {
         let mut deps = mock_dependencies();
+        let (info, msg) = get_instantiate_msg();
+        instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
 
-        // instantiate an empty contract
-        let (info, instantiate_msg) = get_instantiate_msg();
-        let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
-        assert_eq!(0, res.messages.len());
-
-        // create an escrow
-        let (info, create_msg) = get_create_msg(
-            USER_A_ADDR.to_string(),
-            ESCROW_ID.to_string(),
-            USER_B_ADDR.to_string(),
-            LOCK_A.to_string(),
-        );
+        let msg = MigrateMsg {
+            admin: None,
+            fee_collector: None,
+            fee_bps: None,
+            allowed_native: vec![],
+            allowed_cw20: vec![],
+        };
 
-        let res = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("action", "create"), res.attributes[0]);
+        // same version is a noop
+        let res = migrate(deps.as_mut(), mock_env(), msg.clone()).unwrap();
+        assert_eq!(res.attributes[2], attr("to_version", CONTRACT_VERSION));
 
-        // ensure the escrow is in the expected state
-        let details = query_details(deps.as_ref(), ESCROW_ID.to_string()).unwrap();
+        // cannot downgrade
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "99.0.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), msg.clone()).unwrap_err();
         assert_eq!(
-            details,
-            DetailsResponse {
-                id: ESCROW_ID.to_string(),
-                user_a: USER_A_ADDR.to_string(),
-                user_b: USER_B_ADDR.to_string(),
-                deposit: Balance::Native(NativeBalance(coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM))),
-                lock: LOCK_A.to_string(),
-                closed: false,
-            }
+            ContractError::CannotMigrate {
+                previous_contract: CONTRACT_NAME.to_string(),
+                previous_version: "99.0.0".to_string(),
+            },
+            err
         );
 
-        // withdraw
-        let (info, withdraw_msg) = get_withdraw_msg(
-            USER_B_ADDR.to_string(),
-            ESCROW_ID.to_string(),
-            SECRET_A.to_string(),
-        );  
-        let res = execute(deps.as_mut(), mock_env(), info.clone(), withdraw_msg.clone()).unwrap();
-        assert_eq!(1, res.messages.len());
-        assert_eq!(("action", "withdraw"), res.attributes[0]);
-        assert_eq!(("id", ESCROW_ID.to_string()), res.attributes[1]);
-    
-        // ensure the escrow is closed
-        let details = query_details(deps.as_ref(), ESCROW_ID.to_string()).unwrap();
+        // cannot migrate from another contract
+        set_contract_version(&mut deps.storage, "crates.io:cw20-base", "0.1.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), msg).unwrap_err();
         assert_eq!(
-            details,
-            DetailsResponse {
-                id: ESCROW_ID.to_string(),
-                user_a: USER_A_ADDR.to_string(),
-                user_b: USER_B_ADDR.to_string(),
-                deposit: Balance::Native(NativeBalance(coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM))),
-                lock: LOCK_A.to_string(),
-                closed: true,
-            }
+            ContractError::CannotMigrate {
+                previous_contract: "crates.io:cw20-base".to_string(),
+                previous_version: "0.1.0".to_string(),
+            },
+            err
         );
-
-        // withdraw when escrow closed
-        let err = execute(deps.as_mut(), mock_env(), info.clone(), withdraw_msg.clone()).unwrap_err();
-        assert!(matches!(err, ContractError::Closed{}));
     }
 
     #[test]
-    fn withdraw_happy_cw20() {
+    fn ibc_withdraw_refunds_on_timeout() {
         let mut deps = mock_dependencies();
 
         // instantiate an empty contract
         let (info, instantiate_msg) = get_instantiate_msg();
-        let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
-        assert_eq!(0, res.messages.len());
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
 
-        // create an escrow
-        let (info, receive_create_msg) = get_receive_create_msg(
+        let destination = IbcDestinationMsg {
+            channel_id: "channel-0".to_string(),
+            remote_address: "osmo1driver".to_string(),
+            timeout_seconds: 600,
+            fallback: Some("fallback".to_string()),
+        };
+
+        // an IBC destination needs a channel, an address and a timeout
+        let (info, mut create_msg) = get_create_msg(
             USER_A_ADDR.to_string(),
             ESCROW_ID.to_string(),
             USER_B_ADDR.to_string(),
             LOCK_A.to_string(),
-            REQUIRED_CW20_TOKEN_ADDR.to_string(),
-            REQUIRED_TOKEN_AMOUNT,
         );
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.ibc_destination = Some(IbcDestinationMsg { timeout_seconds: 0, ..destination.clone() });
+        }
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), create_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::Escrow(EscrowError::InvalidIbcDestination {}), err);
+        // of at most a week
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.ibc_destination = Some(IbcDestinationMsg { timeout_seconds: u64::MAX, ..destination.clone() });
+        }
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), create_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::Escrow(EscrowError::InvalidIbcDestination {}), err);
 
-        let res = execute(deps.as_mut(), mock_env(), info, receive_create_msg).unwrap();
-        assert_eq!(0, res.messages.len());
-        assert_eq!(("action", "create"), res.attributes[0]);
+        // the withdraw is transferred to the remote address
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.ibc_destination = Some(destination);
+        }
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(Addr::unchecked("fallback"), details.ibc_destination.unwrap().fallback);
 
-        // ensure the escrow is in the expected state
-        let details = query_details(deps.as_ref(), ESCROW_ID.to_string()).unwrap();
-        assert_eq!(
-            details,
-            DetailsResponse {
-                id: ESCROW_ID.to_string(),
-                user_a: USER_A_ADDR.to_string(),
-                user_b: USER_B_ADDR.to_string(),
-                deposit: Balance::Cw20(
-                    Cw20CoinVerified{
-                        address:Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR),
-                        amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
-                    },
-                ),
-                lock: LOCK_A.to_string(),
-                closed: false,
-            }
+        let (info, withdraw_msg) = get_withdraw_msg(USER_B_ADDR.to_string(), ESCROW_ID.to_string(), SECRET_A.to_string());
+        let res = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap();
+        let transfer = transfer_msg(
+            &mock_env().contract.address,
+            "channel-0",
+            "osmo1driver",
+            &Coin::new(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+            &IbcTimeout::with_timestamp(mock_env().block.time.plus_seconds(600)),
         );
+        assert_eq!(vec![transfer.clone()], messages(&res));
 
-        // withdraw
-        let (info, withdraw_msg) = get_withdraw_msg(
-            USER_B_ADDR.to_string(),
-            ESCROW_ID.to_string(),
-            SECRET_A.to_string(),
-        );  
-        let res = execute(deps.as_mut(), mock_env(), info.clone(), withdraw_msg.clone()).unwrap();
-        assert_eq!(1, res.messages.len());
-        assert_eq!(("action", "withdraw"), res.attributes[0]);
-        assert_eq!(("id", ESCROW_ID.to_string()), res.attributes[1]);
-    
-        // ensure the escrow is closed
-        let details = query_details(deps.as_ref(), ESCROW_ID.to_string()).unwrap();
-        assert_eq!(
-            details,
-            DetailsResponse {
-                id: ESCROW_ID.to_string(),
-                user_a: USER_A_ADDR.to_string(),
-                user_b: USER_B_ADDR.to_string(),
-                deposit: Balance::Cw20(
-                    Cw20CoinVerified{
-                        address:Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR),
-                        amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
-                    },
-                ),
-                lock: LOCK_A.to_string(),
-                closed: true,
+        // the transfer asks ibc-hooks to report its outcome to the contract
+        let memo = format!(r#"{{"ibc_callback":"{}"}}"#, mock_env().contract.address);
+        match transfer {
+            CosmosMsg::Stargate { type_url, value } => {
+                assert_eq!("/ibc.applications.transfer.v1.MsgTransfer", type_url);
+                assert!(value.ends_with(&[&[0x42, memo.len() as u8], memo.as_bytes()].concat()));
             }
+            _ => panic!("not a MsgTransfer"),
+        }
+        assert_eq!(ReplyOn::Always, res.messages[0].reply_on);
+
+        // the reply records the sequence of the transfer
+        let sent = Reply {
+            id: res.messages[0].id,
+            result: SubMsgResult::Ok(SubMsgResponse { events: vec![], data: Some(Binary::from(vec![0x08, 0x07])) }),
+        };
+        let res = reply(deps.as_mut(), mock_env(), sent).unwrap();
+        assert_eq!(res.attributes[3], attr("sequence", "7"));
+        let record = ibc_transfers().load(&deps.storage, ("channel-0", 7)).unwrap();
+        assert_eq!(Addr::unchecked("fallback"), record.fallback);
+
+        // the escrow cannot be pruned while the transfer is in flight
+        let prune = |ids: Vec<String>, before: Option<Timestamp>| ExecuteMsg::Prune {
+            ids,
+            before,
+            limit: None,
+            reuse_ids: true,
+        };
+        let later = mock_env().block.time.plus_seconds(1);
+        let err = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), prune(vec![ESCROW_ID.to_string()], None)).unwrap_err();
+        assert_eq!(ContractError::NotSettled { id: ESCROW_ID.to_string() }, err);
+        let res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), prune(vec![], Some(later))).unwrap();
+        assert_eq!(res.attributes[1], attr("pruned", "0"));
+
+        // the coins that came back on timeout are paid to the fallback
+        let timeout = SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcTimeout {
+            channel: "channel-0".to_string(),
+            sequence: 7,
+        });
+        let res = sudo(deps.as_mut(), mock_env(), timeout.clone()).unwrap();
+        assert_eq!(res.attributes[4], attr("outcome", "timed_out"));
+        assert_eq!(
+            messages(&res),
+            vec![CosmosMsg::from(BankMsg::Send {
+                to_address: "fallback".to_string(),
+                amount: coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+            })]
         );
 
-        // withdraw when escrow closed
-        let err = execute(deps.as_mut(), mock_env(), info.clone(), withdraw_msg.clone()).unwrap_err();
-        assert!(matches!(err, ContractError::Closed{}));
+        // the transfer is only settled once
+        sudo(deps.as_mut(), mock_env(), timeout).unwrap_err();
+
+        // after which the escrow can be pruned
+        let res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), prune(vec![], Some(later))).unwrap();
+        assert_eq!(res.attributes[1], attr("pruned", "1"));
+
+        // an acknowledged transfer sends nothing more
+        ibc_transfers().save(deps.as_mut().storage, ("channel-0", 8), &record).unwrap();
+        let ack = SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcAck {
+            channel: "channel-0".to_string(),
+            sequence: 8,
+            ack: "eyJyZXN1bHQiOiJBUT09In0=".to_string(),
+            success: true,
+        });
+        let res = sudo(deps.as_mut(), mock_env(), ack).unwrap();
+        assert!(res.messages.is_empty());
+        assert!(!ibc_transfers().has(&deps.storage, ("channel-0", 8)));
     }
 
     #[test]
-    fn cancel_happy_native() {
+    fn create_from_allowance() {
         let mut deps = mock_dependencies();
 
-        // instantiate an empty contract
+        // instantiate an empty contract, "anyone" is the admin
         let (info, instantiate_msg) = get_instantiate_msg();
-        let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
-        assert_eq!(0, res.messages.len());
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
 
-        // create an escrow
-        let (info, create_msg) = get_create_msg(
+        let create_msg = match get_create_msg(
             USER_A_ADDR.to_string(),
             ESCROW_ID.to_string(),
             USER_B_ADDR.to_string(),
             LOCK_A.to_string(),
+        ) {
+            (_, ExecuteMsg::Create(msg)) => msg,
+            _ => unreachable!(),
+        };
+        let from_allowance = |token: &str| ExecuteMsg::CreateFromAllowance {
+            token: token.to_string(),
+            amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
+            owner: USER_A_ADDR.to_string(),
+            msg: create_msg.clone(),
+        };
+
+        // only relayers, approved by the admin, can pull allowances
+        let err = execute(deps.as_mut(), mock_env(), mock_info("backend", &[]), from_allowance(REQUIRED_CW20_TOKEN_ADDR)).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
+        let update = ExecuteMsg::UpdateRelayers { relayers: vec!["backend".to_string()] };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("backend", &[]), update.clone()).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
+        let res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), update).unwrap();
+        assert_eq!(res.events[0].attributes[2], attr("relayers", "backend"));
+        assert_eq!(vec!["backend".to_string()], query_config(deps.as_ref()).unwrap().relayers);
+
+        // the token must be allowed
+        let err = execute(deps.as_mut(), mock_env(), mock_info("backend", &[]), from_allowance("other_token")).unwrap_err();
+        assert_eq!(ContractError::TokenNotAllowed { token: "other_token".to_string() }, err);
+
+        // and the relayer approved by the owner
+        let err = execute(deps.as_mut(), mock_env(), mock_info("backend", &[]), from_allowance(REQUIRED_CW20_TOKEN_ADDR)).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
+        let approve = |relayer: &str| ExecuteMsg::ApproveRelayer { relayer: relayer.to_string() };
+        let _ = execute(deps.as_mut(), mock_env(), mock_info(USER_B_ADDR, &[]), approve("backend")).unwrap();
+        let _ = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), approve("other_relayer")).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("backend", &[]), from_allowance(REQUIRED_CW20_TOKEN_ADDR)).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
+        let res = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), approve("backend")).unwrap();
+        assert_eq!(
+            res.attributes,
+            vec![attr("action", "approve_relayer"), attr("owner", USER_A_ADDR), attr("relayer", "backend")]
         );
-        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
-     
-        // cancel
-        let (info, cancel_msg) = get_cancel_msg(
-            USER_A_ADDR.to_string(),
-            ESCROW_ID.to_string(),
-        );  
-        let res = execute(deps.as_mut(), mock_env(), info.clone(), cancel_msg.clone()).unwrap();
-        assert_eq!(1, res.messages.len());
-        assert_eq!(("action", "cancel"), res.attributes[0]);
-        assert_eq!(("id", ESCROW_ID.to_string()), res.attributes[1]);
-    
-        // ensure the escrow is closed
-        let details = query_details(deps.as_ref(), ESCROW_ID.to_string()).unwrap();
+
+        // the deposit is pulled from the owner first
+        let res = execute(deps.as_mut(), mock_env(), mock_info("backend", &[]), from_allowance(REQUIRED_CW20_TOKEN_ADDR)).unwrap();
         assert_eq!(
-            details,
-            DetailsResponse {
-                id: ESCROW_ID.to_string(),
-                user_a: USER_A_ADDR.to_string(),
-                user_b: USER_B_ADDR.to_string(),
-                deposit: Balance::Native(NativeBalance(coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM))),
-                lock: LOCK_A.to_string(),
-                closed: true,
-            }
+            res.messages,
+            vec![SubMsg::reply_on_success(
+                WasmMsg::Execute {
+                    contract_addr: REQUIRED_CW20_TOKEN_ADDR.to_string(),
+                    msg: to_binary(&Cw20ExecuteMsg::TransferFrom {
+                        owner: USER_A_ADDR.to_string(),
+                        recipient: mock_env().contract.address.to_string(),
+                        amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
+                    }).unwrap(),
+                    funds: vec![],
+                },
+                ALLOWANCE_REPLY_ID,
+            )]
         );
+        assert!(query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).is_err());
 
-         // cancel when escrow closed
-         let err = execute(deps.as_mut(), mock_env(), info.clone(), cancel_msg.clone()).unwrap_err();
-         assert!(matches!(err, ContractError::Closed{}));
+        // then the reply creates the escrow, owned by the owner
+        let transferred = Reply {
+            id: ALLOWANCE_REPLY_ID,
+            result: SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }),
+        };
+        let res = reply(deps.as_mut(), mock_env(), transferred.clone()).unwrap();
+        assert_eq!(res.attributes, vec![attr("action", "create"), attr("id", ESCROW_ID)]);
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(USER_A_ADDR, details.user_a);
+        assert_eq!(
+            Balance::Cw20(Cw20CoinVerified {
+                address: Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR),
+                amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
+            }),
+            details.deposit
+        );
+        assert_eq!(1, query_stats(deps.as_ref()).unwrap().open);
+
+        // the pending escrow is only created once
+        reply(deps.as_mut(), mock_env(), transferred).unwrap_err();
+
+        // until the owner revokes the approval
+        let revoke = ExecuteMsg::RevokeRelayer { relayer: "backend".to_string() };
+        let _ = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), revoke).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("backend", &[]), from_allowance(REQUIRED_CW20_TOKEN_ADDR)).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
     }
 
     #[test]
-    fn cancel_happy_cw20() {
+    fn recover_payouts_that_keep_failing() {
         let mut deps = mock_dependencies();
 
         // instantiate an empty contract
         let (info, instantiate_msg) = get_instantiate_msg();
-        let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
-        assert_eq!(0, res.messages.len());
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
 
-        // create an escrow
-        let (info, receive_create_msg) = get_receive_create_msg(
+        // the recipient is a contract that rejects every payout_msg
+        let hook = to_binary(&"ride_revenue").unwrap();
+        let (info, mut create_msg) = get_create_msg(
             USER_A_ADDR.to_string(),
             ESCROW_ID.to_string(),
-            USER_B_ADDR.to_string(),
+            "vault".to_string(),
             LOCK_A.to_string(),
-            REQUIRED_CW20_TOKEN_ADDR.to_string(),
-            REQUIRED_TOKEN_AMOUNT,
         );
-        let _ = execute(deps.as_mut(), mock_env(), info, receive_create_msg).unwrap();
-     
-        // cancel
-        let (info, cancel_msg) = get_cancel_msg(
-            USER_A_ADDR.to_string(),
-            ESCROW_ID.to_string(),
-        );  
-        let res = execute(deps.as_mut(), mock_env(), info.clone(), cancel_msg.clone()).unwrap();
-        assert_eq!(1, res.messages.len());
-        assert_eq!(("action", "cancel"), res.attributes[0]);
-        assert_eq!(("id", ESCROW_ID.to_string()), res.attributes[1]);
-    
-        // ensure the escrow is closed
-        let details = query_details(deps.as_ref(), ESCROW_ID.to_string()).unwrap();
+        if let ExecuteMsg::Create(ref mut msg) = create_msg {
+            msg.payout_msg = Some(hook);
+        }
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        let (info, withdraw_msg) = get_withdraw_msg("vault".to_string(), ESCROW_ID.to_string(), SECRET_A.to_string());
+        let mut res = execute(deps.as_mut(), mock_env(), info, withdraw_msg).unwrap();
+
+        // retrying sends the same payout, which is rejected again
+        let retry_msg = ExecuteMsg::RetryPayout { id: ESCROW_ID.to_string() };
+        for _ in 0..2 {
+            let failure = Reply {
+                id: res.messages[0].id,
+                result: SubMsgResult::Err("hook rejected".to_string()),
+            };
+            reply(deps.as_mut(), mock_env(), failure).unwrap();
+            let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+            assert_eq!(EscrowStatus::PayoutFailed, details.status);
+            res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), retry_msg.clone()).unwrap();
+        }
+        let failure = Reply {
+            id: res.messages[0].id,
+            result: SubMsgResult::Err("hook rejected".to_string()),
+        };
+        reply(deps.as_mut(), mock_env(), failure).unwrap();
+
+        // only the recipient can recover its payouts
+        let recover_msg = ExecuteMsg::RecoverPayouts { id: ESCROW_ID.to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER_A_ADDR, &[]), recover_msg.clone()).unwrap_err();
+        assert_eq!(ContractError::Unauthorized {}, err);
+
+        // which credits them to its claimable balance and completes the escrow
+        let res = execute(deps.as_mut(), mock_env(), mock_info("vault", &[]), recover_msg.clone()).unwrap();
+        assert!(res.messages.is_empty());
+        assert_eq!(res.events[0].attributes[1], attr("status", "completed"));
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(EscrowStatus::Completed, details.status);
+        assert!(details.failed_payouts.is_empty());
+        let balance = query_balance(deps.as_ref(), "vault".to_string()).unwrap();
+        assert_eq!(coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM), balance.native);
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("vault", &[]), recover_msg).unwrap_err();
+        assert_eq!(ContractError::Escrow(EscrowError::NoFailedPayout {}), err);
+
+        // the claim is a plain transfer
+        let claim_msg = ExecuteMsg::Claim { tokens: vec![] };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("vault", &[]), claim_msg).unwrap();
         assert_eq!(
-            details,
-            DetailsResponse {
-                id: ESCROW_ID.to_string(),
-                user_a: USER_A_ADDR.to_string(),
-                user_b: USER_B_ADDR.to_string(),
-                deposit: Balance::Cw20(
-                    Cw20CoinVerified{
-                        address:Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR),
-                        amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
-                    },
-                ),
-                lock: LOCK_A.to_string(),
-                closed: true,
-            }
+            messages(&res),
+            vec![CosmosMsg::from(BankMsg::Send {
+                to_address: "vault".to_string(),
+                amount: coins(REQUIRED_TOKEN_AMOUNT, REQUIRED_NATIVE_TOKEN_DENOM),
+            })]
         );
-
-         // cancel when escrow closed
-         let err = execute(deps.as_mut(), mock_env(), info.clone(), cancel_msg.clone()).unwrap_err();
-         assert!(matches!(err, ContractError::Closed{}));
     }
 
     #[test]
-    fn cancel_wrong_user() {
+    fn signature_not_replayed_on_reused_id() {
         let mut deps = mock_dependencies();
 
-        // instantiate an empty contract
+        // instantiate an empty contract, "anyone" is the admin
         let (info, instantiate_msg) = get_instantiate_msg();
-        let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
-        assert_eq!(0, res.messages.len());
+        let _ = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
 
-        // create an escrow
-        let (info, receive_create_msg) = get_receive_create_msg(
+        let key = SigningKey::from_bytes(&hex::decode(SECRET_A).unwrap()).unwrap();
+        let withdraw = |nonce: u64| {
+            let payload = withdraw_payload(
+                &mock_env().contract.address,
+                ESCROW_ID,
+                nonce,
+                &Addr::unchecked(USER_B_ADDR),
+                None,
+                None,
+            ).unwrap();
+            let signature: Signature = key.sign(&payload);
+            ExecuteMsg::Withdraw(WithdrawMsg {
+                id: ESCROW_ID.to_string(),
+                secret: None,
+                signature: Some(hex::encode(signature.as_ref())),
+                milestone: None,
+                payout_msg: None,
+            })
+        };
+        let (info, create_msg) = get_create_msg(
             USER_A_ADDR.to_string(),
             ESCROW_ID.to_string(),
             USER_B_ADDR.to_string(),
             LOCK_A.to_string(),
-            REQUIRED_CW20_TOKEN_ADDR.to_string(),
-            REQUIRED_TOKEN_AMOUNT,
         );
-        let _ = execute(deps.as_mut(), mock_env(), info, receive_create_msg).unwrap();
-     
-        // cancel
-        let (info, cancel_msg) = get_cancel_msg(
-            USER_B_ADDR.to_string(),
-            ESCROW_ID.to_string(),
-        );  
-        let err = execute(deps.as_mut(), mock_env(), info.clone(), cancel_msg.clone()).unwrap_err();
-         assert!(matches!(err, ContractError::InvalidUser{}));
-        
-        // ensure the escrow is still open
-        let details = query_details(deps.as_ref(), ESCROW_ID.to_string()).unwrap();
-        assert_eq!(
-            details,
-            DetailsResponse {
-                id: ESCROW_ID.to_string(),
-                user_a: USER_A_ADDR.to_string(),
-                user_b: USER_B_ADDR.to_string(),
-                deposit: Balance::Cw20(
-                    Cw20CoinVerified{
-                        address:Addr::unchecked(REQUIRED_CW20_TOKEN_ADDR),
-                        amount: Uint128::new(REQUIRED_TOKEN_AMOUNT),
-                    },
-                ),
-                lock: LOCK_A.to_string(),
-                closed: false,
-            }
-        );
-    }
 
-}
\ No newline at end of file
+        // a first escrow is withdrawn with a signature, then pruned
+        let _ = execute(deps.as_mut(), mock_env(), info.clone(), create_msg.clone()).unwrap();
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(1, details.nonce);
+        let _ = execute(deps.as_mut(), mock_env(), mock_info("relayer", &[]), withdraw(1)).unwrap();
+        let prune_msg = ExecuteMsg::Prune {
+            ids: vec![ESCROW_ID.to_string()],
+            before: None,
+            limit: None,
+            reuse_ids: true,
+        };
+        let _ = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), prune_msg).unwrap();
+
+        // the escrow created again with the same id, lock and recipient has
+        // another nonce, so the first signature cannot withdraw it
+        let _ = execute(deps.as_mut(), mock_env(), info, create_msg).unwrap();
+        let details = query_details(deps.as_ref(), mock_env(), ESCROW_ID.to_string()).unwrap();
+        assert_eq!(2, details.nonce);
+        let err = execute(deps.as_mut(), mock_env(), mock_info("relayer", &[]), withdraw(1)).unwrap_err();
+        assert_eq!(ContractError::Escrow(EscrowError::InvalidSignature {}), err);
+        let _ = execute(deps.as_mut(), mock_env(), mock_info("relayer", &[]), withdraw(2)).unwrap();
+    }
+}
@@ -12,12 +12,77 @@ pub enum ContractError {
     #[error("Escrow id already in use")]
     AlreadyInUse {},
 
+    #[error("Invalid escrow id: {reason}")]
+    InvalidId { reason: String },
+
     #[error("Escrow is closed")]
     Closed {},
 
     #[error("Only escrow creator can cancel")]
     InvalidUser {},
 
+    #[error("Only escrow recipient can refund, accept or start")]
+    NotRecipient {},
+
+    #[error("Escrow is expired")]
+    Expired {},
+
+    #[error("Fee must be at most 10000 basis points")]
+    InvalidFee {},
+
+    #[error("Escrow is disputed")]
+    Disputed {},
+
+    #[error("Escrow is not disputed")]
+    NotDisputed {},
+
+    #[error("Only the escrow arbiter can resolve a dispute")]
+    NotArbiter {},
+
+    #[error("Cannot migrate from {previous_contract} {previous_version}")]
+    CannotMigrate {
+        previous_contract: String,
+        previous_version: String,
+    },
+
+    #[error("Migrating from {version} requires an admin and a fee_collector")]
+    MissingConfig { version: String },
+
+    #[error("Unauthorized")]
+    Unauthorized {},
+
+    #[error("Contract is paused, no new deposits are accepted")]
+    Paused {},
+
+    #[error("payout_msg cannot be delivered to a recipient paid over IBC or in the ledger payout mode")]
+    PayoutMsgNotDelivered {},
+
+    #[error("IBC transfer reply has no sequence")]
+    MissingIbcSequence {},
+
+    #[error("Prune either a list of ids or the escrows closed before a time")]
+    InvalidPrune {},
+
+    #[error("Escrow {id} is not settled and cannot be pruned")]
+    NotSettled { id: String },
+
+    #[error("Nothing to claim")]
+    NothingToClaim {},
+
+    #[error("Batch deposit must be a single native coin matching the sum of the items")]
+    InvalidBatchDeposit {},
+
+    #[error("Token {token} is not accepted as deposit")]
+    TokenNotAllowed { token: String },
+
+    #[error("Semver parsing error: {0}")]
+    SemVer(String),
+}
+
+impl From<semver::Error> for ContractError {
+    fn from(err: semver::Error) -> Self {
+        Self::SemVer(err.to_string())
+    }
 }
 
 #[derive(Error, Debug, PartialEq)]
@@ -28,9 +93,93 @@ pub enum EscrowError {
     #[error("Match required deposit")]
     InvalidDeposit {},
 
+    #[error("Top up must be in the same token as the deposit")]
+    MixedDeposit {},
+
+    #[error("Only escrow creator can top up")]
+    NotCreator {},
+
+    #[error("Cancellation fee must be at most 10000 basis points, or fixed on a single-token deposit")]
+    InvalidCancellationFee {},
+
+    #[error("Escrow cannot go from {from} to {to}")]
+    InvalidTransition { from: String, to: String },
+
     #[error("account lock is not set")]
     NoLock {},
 
     #[error("Invalid Secret")]
     InvalidSecret {},
+
+    #[error("Secret is not valid hex")]
+    InvalidSecretEncoding {},
+
+    #[error("Secret must be a 32 bytes private key")]
+    InvalidSecretLength {},
+
+    #[error("Secret is not a valid private key")]
+    InvalidSecretScalar {},
+
+    #[error("Lock is not valid hex")]
+    InvalidLockEncoding {},
+
+    #[error("Lock is not a valid SEC1 public key")]
+    InvalidLockPoint {},
+
+    #[error("Lock must be a 32 bytes SHA-256 digest")]
+    InvalidLockDigest {},
+
+    #[error("Invalid Signature")]
+    InvalidSignature {},
+
+    #[error("Milestones must be non-zero amounts of the deposit token adding up to the deposit")]
+    InvalidMilestones {},
+
+    #[error("Escrow is released by milestone")]
+    MilestoneRequired {},
+
+    #[error("Only the next milestone can be released")]
+    InvalidMilestone {},
+
+    #[error("Escrow with milestones cannot be topped up")]
+    MilestoneTopUp {},
+
+    #[error("Target must be a single token amount, at least the deposit")]
+    InvalidTarget {},
+
+    #[error("Escrow is not a shared fare")]
+    NotShared {},
+
+    #[error("Shared fares are funded with contribute")]
+    SharedTopUp {},
+
+    #[error("Contribution exceeds the target")]
+    Overfunded {},
+
+    #[error("Shared fare has not reached its target")]
+    NotFunded {},
+
+    #[error("Escrow has no failed payout")]
+    NoFailedPayout {},
+
+    #[error("IBC destination needs a channel, a remote address and a timeout of at most a week, and no payout_msg")]
+    InvalidIbcDestination {},
+
+    #[error("IBC transfer timeout out of range")]
+    IbcTimeoutOverflow {},
+
+    #[error("Provide either a secret or a signature")]
+    NoSecret {},
+
+    #[error("Only k256 locks can be opened with a signature")]
+    SignatureNotSupported {},
+
+    #[error("Only the escrow users can do this")]
+    NotAParty {},
+
+    #[error("Escrow has no arbiter")]
+    NoArbiter {},
+
+    #[error("Payouts must add up to the deposit")]
+    InvalidSplit {},
 }
\ No newline at end of file
@@ -4,13 +4,133 @@ use serde::{Deserialize, Serialize};
 use k256::{
     ecdsa::SigningKey,              
     elliptic_curve::sec1::ToEncodedPoint,
+    PublicKey,
 };
+use sha2::{Digest, Sha256};
 
-use cosmwasm_std::Addr;
+use std::fmt;
+
+use cosmwasm_std::{Addr, Api, Binary, BlockInfo, CosmosMsg, IbcTimeout, Timestamp, Uint128};
 use cw20::Balance;
+use cw_utils::Expiration;
 
+use crate::balance::{add_balances, normalize_balance, single_amount, with_amount};
 use crate::error::EscrowError;
 
+/// LockKind tells how the lock of an escrow is opened
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LockKind {
+    /// the lock is a hex-encoded secp256k1 public key. The escrow is opened
+    /// with the corresponding private key, or a signature by that key.
+    #[default]
+    K256,
+    /// the lock is a hex-encoded SHA-256 digest. The escrow is opened by
+    /// revealing the hex-encoded preimage, as in hash time-locked contracts.
+    Sha256,
+}
+
+/// EscrowStatus follows the ride through its lifecycle
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EscrowStatus {
+    /// the deposit is locked, waiting for the driver
+    #[default]
+    Created,
+    /// the driver (user_b) acknowledged the ride
+    Accepted,
+    /// the ride is in progress
+    Started,
+    /// the deposit was paid out to the driver, or split by the arbiter
+    Completed,
+    /// the deposit was refunded to the rider, or split by both users
+    Cancelled,
+    /// the escrow is frozen until the arbiter resolves the dispute
+    Disputed,
+    /// a payout was rejected, e.g. by a paused cw20 token. The funds stay
+    /// in the contract until the payout is retried.
+    PayoutFailed,
+}
+
+impl EscrowStatus {
+    /// is_closed returns true once the deposit has been paid out, or its
+    /// payout waits for a retry
+    pub fn is_closed(self) -> bool {
+        matches!(
+            self,
+            EscrowStatus::Completed | EscrowStatus::Cancelled | EscrowStatus::PayoutFailed
+        )
+    }
+
+    /// can_become tells whether the status can move to next
+    pub fn can_become(self, next: EscrowStatus) -> bool {
+        use EscrowStatus::*;
+        matches!(
+            (self, next),
+            (Created, Accepted)
+                | (Accepted, Started)
+                | (Created | Accepted | Started, Completed | Cancelled | Disputed)
+                | (Disputed, Completed)
+                | (Created | Accepted | Started | Completed | Cancelled, PayoutFailed)
+        )
+    }
+}
+
+impl fmt::Display for EscrowStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self {
+            EscrowStatus::Created => "created",
+            EscrowStatus::Accepted => "accepted",
+            EscrowStatus::Started => "started",
+            EscrowStatus::Completed => "completed",
+            EscrowStatus::Cancelled => "cancelled",
+            EscrowStatus::Disputed => "disputed",
+            EscrowStatus::PayoutFailed => "payout_failed",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// CancellationFee compensates the driver when the rider cancels after the
+/// ride was accepted
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CancellationFee {
+    /// a fixed amount of the deposit token, capped at the deposit. Only
+    /// allowed on single-token deposits.
+    Fixed(Uint128),
+    /// a share of each coin of the deposit, in basis points
+    Bps(u16),
+}
+
+impl CancellationFee {
+    /// amount_of returns the fee due on an amount of the deposit
+    pub fn amount_of(&self, amount: Uint128) -> Uint128 {
+        match self {
+            CancellationFee::Fixed(fee) => (*fee).min(amount),
+            CancellationFee::Bps(bps) => amount.multiply_ratio(*bps, MAX_BPS),
+        }
+    }
+}
+
+/// Paid tallies the funds an escrow paid out to each party
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug, Default)]
+pub struct Paid {
+    /// refunded to user_a, or to the contributors of a shared fare
+    pub to_user_a: Balance,
+    /// paid to user_b, after the platform fee
+    pub to_user_b: Balance,
+    /// platform fee taken out of the share of user_b
+    pub fee: Balance,
+}
+
+/// StatusChange records when the escrow reached a status
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct StatusChange {
+    pub status: EscrowStatus,
+    pub time: Timestamp,
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
 pub struct Escrow {
     /// user_a creates the escrow
@@ -22,76 +142,696 @@ pub struct Escrow {
     /// lock is the public key that guards the deposit. 
     /// the corresponding private key is necessary to withdraw.
     pub lock: String,
-    /// close indicates whether the escrow is closed and already settled
-    /// if this value is true, it is assumed that all payouts have already
+    /// lock_kind tells how the lock is opened
+    #[serde(default)]
+    pub lock_kind: LockKind,
+    /// status tells where the ride is in its lifecycle. Once it is
+    /// completed or cancelled, it is assumed that all payouts have already
     /// been settled
-    pub closed: bool,
+    pub status: EscrowStatus,
+    /// history lists every status reached, with the time of the transition
+    #[serde(default)]
+    pub history: Vec<StatusChange>,
+    /// expires_at is an optional deadline. Before it is reached, the escrow
+    /// can only be withdrawn or cancelled with the approval of both users.
+    /// After it is reached, anyone can trigger a refund to user_a.
+    pub expires_at: Option<Expiration>,
+    /// cancel_approvals lists the users who agreed to cancel the escrow
+    /// before its expiry
+    #[serde(default)]
+    pub cancel_approvals: Vec<Addr>,
+    /// arbiter is an optional third party who can settle disputes
+    pub arbiter: Option<Addr>,
+    /// cancellation_fee is paid to user_b out of the deposit when the
+    /// escrow is cancelled after user_b accepted it
+    #[serde(default)]
+    pub cancellation_fee: Option<CancellationFee>,
+    /// settlement is a split of the deposit proposed to cancel the escrow
+    /// early. It is paid out once both users approved it.
+    #[serde(default)]
+    pub settlement: Option<Settlement>,
+    /// milestones split the deposit into ordered tranches, each released
+    /// with its own lock. When empty, the whole deposit is released at once
+    /// with the lock of the escrow.
+    #[serde(default)]
+    pub milestones: Vec<Milestone>,
+    /// target is the amount a shared fare must reach before it can be
+    /// withdrawn. The deposit then grows with the contributions of several
+    /// riders, and refunds are split among them pro rata.
+    #[serde(default)]
+    pub target: Option<Uint128>,
+    /// failed_payouts are the transfers rejected by their recipient or
+    /// token, kept to be retried
+    #[serde(default)]
+    pub failed_payouts: Vec<CosmosMsg>,
+    /// payout_msg is sent along with the payouts to user_b, which must then
+    /// be a contract: cw20 tokens are paid with Send and this message, and
+    /// native coins with an Execute of this message
+    #[serde(default)]
+    pub payout_msg: Option<Binary>,
+    /// ibc_destination pays the native coins withdrawn to user_b on
+    /// another chain
+    #[serde(default)]
+    pub ibc_destination: Option<IbcDestination>,
+    /// nonce is unique to the escrow, even when its id is reused after a
+    /// prune, and is signed in the WithdrawPayload so that a signature
+    /// cannot be replayed on a later escrow. Escrows migrated from v0.2.0
+    /// have 0.
+    #[serde(default)]
+    pub nonce: u64,
+    /// paid tallies what the escrow paid out so far, kept in its archive
+    /// record. Escrows closed before v0.3.0 paid nothing here.
+    #[serde(default)]
+    pub paid: Paid,
+}
+
+/// IbcDestination is where a withdraw is transferred over IBC
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct IbcDestination {
+    /// channel from this chain to the chain of user_b
+    pub channel_id: String,
+    /// address of user_b on the other chain
+    pub remote_address: String,
+    /// seconds the transfer has to complete before it times out
+    pub timeout_seconds: u64,
+    /// fallback is paid on this chain if the transfer fails or times out
+    pub fallback: Addr,
+}
+
+/// the longest timeout of an IBC transfer, one week
+pub const MAX_IBC_TIMEOUT_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+impl IbcDestination {
+    /// timeout of a transfer sent at now
+    pub fn timeout(&self, now: Timestamp) -> Result<IbcTimeout, EscrowError> {
+        let nanos = self
+            .timeout_seconds
+            .checked_mul(1_000_000_000)
+            .and_then(|timeout| now.nanos().checked_add(timeout))
+            .ok_or(EscrowError::IbcTimeoutOverflow {})?;
+        Ok(IbcTimeout::with_timestamp(Timestamp::from_nanos(nanos)))
+    }
+}
+
+/// Milestone is a tranche of the deposit, paid to user_b on its own lock,
+/// e.g. one leg of a trip with several drop-offs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct Milestone {
+    /// amount of the deposit token released by this milestone
+    pub amount: Uint128,
+    /// lock guarding this tranche
+    pub lock: String,
+    /// lock_kind tells how the lock is opened
+    pub lock_kind: LockKind,
+    /// released is true once the tranche was paid to user_b
+    pub released: bool,
+}
+
+/// Settlement splits the deposit between the two users
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct Settlement {
+    pub to_user_a: Balance,
+    pub to_user_b: Balance,
+    /// approvals lists the users who agreed to this split
+    pub approvals: Vec<Addr>,
 }
 
 impl Escrow {
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
         user_a: Addr,
         user_b: Addr,
         deposit: Balance,
         lock: &str,
+        lock_kind: LockKind,
+        expires_at: Option<Expiration>,
+        arbiter: Option<Addr>,
+        created_at: Timestamp,
     ) -> Result<Self,EscrowError> {
         
         if deposit.is_empty() {
             return Err(EscrowError::EmptyDeposit {});
         }
 
+        validate_lock(lock, lock_kind)?;
+
         Ok(Escrow{
             user_a,
             user_b,
             deposit,
             lock: lock.to_string(),
-            closed: false,
+            lock_kind,
+            status: EscrowStatus::Created,
+            history: vec![StatusChange { status: EscrowStatus::Created, time: created_at }],
+            expires_at,
+            cancel_approvals: vec![],
+            arbiter,
+            cancellation_fee: None,
+            settlement: None,
+            milestones: vec![],
+            target: None,
+            failed_payouts: vec![],
+            payout_msg: None,
+            ibc_destination: None,
+            nonce: 0,
+            paid: Paid::default(),
         })
     }
 
-    /// check secret against lock
+    /// with_target makes the escrow a shared fare, funded by contributions
+    /// until the deposit reaches target. The deposit of the creator is the
+    /// first contribution, in the single token of the fare.
+    pub fn with_target(mut self, target: Option<Uint128>) -> Result<Self, EscrowError> {
+        if let Some(target) = target {
+            match single_amount(&self.deposit) {
+                Some(amount) if amount <= target => {}
+                _ => return Err(EscrowError::InvalidTarget {}),
+            }
+        }
+        self.target = target;
+        Ok(self)
+    }
+
+    /// with_nonce sets the nonce signed by withdraws
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    /// with_payout_msg notifies user_b, a contract, of every payout with msg
+    pub fn with_payout_msg(mut self, msg: Option<Binary>) -> Self {
+        self.payout_msg = msg;
+        self
+    }
+
+    /// with_ibc_destination pays the native coins withdrawn to user_b over
+    /// IBC. It cannot be combined with a payout_msg, which is meant for a
+    /// contract on this chain.
+    pub fn with_ibc_destination(mut self, destination: Option<IbcDestination>) -> Result<Self, EscrowError> {
+        if let Some(destination) = &destination {
+            if destination.channel_id.is_empty()
+                || destination.remote_address.is_empty()
+                || destination.timeout_seconds == 0
+                || destination.timeout_seconds > MAX_IBC_TIMEOUT_SECONDS
+                || self.payout_msg.is_some()
+            {
+                return Err(EscrowError::InvalidIbcDestination {});
+            }
+        }
+        self.ibc_destination = destination;
+        Ok(self)
+    }
+
+    /// with_milestones splits the deposit into tranches released one after
+    /// the other. The deposit must be a single token, and the amounts of the
+    /// milestones must add up to it, or to the target of a shared fare.
+    pub fn with_milestones(mut self, milestones: Vec<Milestone>) -> Result<Self, EscrowError> {
+        if milestones.is_empty() {
+            return Ok(self);
+        }
+        let deposit = single_amount(&self.deposit).ok_or(EscrowError::InvalidMilestones {})?;
+        let deposit = self.target.unwrap_or(deposit);
+
+        let mut total = Uint128::zero();
+        for milestone in &milestones {
+            if milestone.amount.is_zero() || milestone.released {
+                return Err(EscrowError::InvalidMilestones {});
+            }
+            validate_lock(&milestone.lock, milestone.lock_kind)?;
+            total = total.checked_add(milestone.amount)
+                .map_err(|_| EscrowError::InvalidMilestones {})?;
+        }
+        if total != deposit {
+            return Err(EscrowError::InvalidMilestones {});
+        }
+
+        self.milestones = milestones;
+        Ok(self)
+    }
+
+    /// with_cancellation_fee sets the fee due to user_b if the escrow is
+    /// cancelled after it was accepted. A fixed fee needs a single-token
+    /// deposit, as its amount would be charged in every coin.
+    pub fn with_cancellation_fee(mut self, fee: Option<CancellationFee>) -> Result<Self, EscrowError> {
+        match fee {
+            Some(CancellationFee::Bps(bps)) if bps > MAX_BPS => {
+                return Err(EscrowError::InvalidCancellationFee {});
+            }
+            Some(CancellationFee::Fixed(_)) if single_amount(&self.deposit).is_none() => {
+                return Err(EscrowError::InvalidCancellationFee {});
+            }
+            _ => {}
+        }
+        self.cancellation_fee = fee;
+        Ok(self)
+    }
+
+    /// cancellation_fee_due returns the fee owed to user_b if the escrow is
+    /// cancelled now, that is once user_b accepted the ride
+    pub fn cancellation_fee_due(&self) -> Option<&CancellationFee> {
+        match self.status {
+            EscrowStatus::Accepted | EscrowStatus::Started => self.cancellation_fee.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// is_expired returns true if the escrow has an expiry and it has been
+    /// reached at the given block
+    pub fn is_expired(&self, block: &BlockInfo) -> bool {
+        match &self.expires_at {
+            Some(expiration) => expiration.is_expired(block),
+            None => false,
+        }
+    }
+
+    /// approve_cancel records that user agrees to cancel the escrow
+    /// Returns an EscrowError:NotAParty if user is neither user_a nor user_b
+    pub fn approve_cancel(&mut self, user: &Addr) -> Result<(), EscrowError> {
+        if user != self.user_a && user != self.user_b {
+            return Err(EscrowError::NotAParty {});
+        }
+        if !self.cancel_approvals.contains(user) {
+            self.cancel_approvals.push(user.clone());
+        }
+        Ok(())
+    }
+
+    /// top_up adds funds to the deposit. Only user_a can top up, in the
+    /// same token as the deposit. This drops any proposed settlement.
+    pub fn top_up(&mut self, user: &Addr, funds: &Balance) -> Result<(), EscrowError> {
+        if *user != self.user_a {
+            return Err(EscrowError::NotCreator {});
+        }
+        // the tranches would no longer add up to the deposit
+        if !self.milestones.is_empty() {
+            return Err(EscrowError::MilestoneTopUp {});
+        }
+        if self.target.is_some() {
+            return Err(EscrowError::SharedTopUp {});
+        }
+        if funds.is_empty() {
+            return Err(EscrowError::EmptyDeposit {});
+        }
+        let deposit = add_balances(&self.deposit, funds)
+            .ok_or(EscrowError::MixedDeposit {})?;
+        // a fixed cancellation fee only applies to a single token
+        if matches!(self.cancellation_fee, Some(CancellationFee::Fixed(_))) && single_amount(&deposit).is_none() {
+            return Err(EscrowError::MixedDeposit {});
+        }
+        self.deposit = deposit;
+        // a proposed split no longer adds up to the deposit
+        self.settlement = None;
+        Ok(())
+    }
+
+    /// contribute adds funds of any rider to a shared fare, in its token and
+    /// up to its target. Returns the amount contributed. This drops any
+    /// proposed settlement.
+    pub fn contribute(&mut self, funds: &Balance) -> Result<Uint128, EscrowError> {
+        let target = self.target.ok_or(EscrowError::NotShared {})?;
+        if funds.is_empty() {
+            return Err(EscrowError::EmptyDeposit {});
+        }
+        let deposit = add_balances(&self.deposit, funds)
+            .ok_or(EscrowError::MixedDeposit {})?;
+        match single_amount(&deposit) {
+            Some(amount) if amount <= target => {}
+            Some(_) => return Err(EscrowError::Overfunded {}),
+            None => return Err(EscrowError::MixedDeposit {}),
+        }
+        self.deposit = deposit;
+        self.settlement = None;
+        Ok(single_amount(&normalize_balance(funds)).unwrap_or_default())
+    }
+
+    /// is_funded returns true once a shared fare reached its target. Other
+    /// escrows are funded on creation.
+    pub fn is_funded(&self) -> bool {
+        match self.target {
+            Some(target) => single_amount(&self.deposit) == Some(target),
+            None => true,
+        }
+    }
+
+    /// approve_settlement records that user agrees to split the deposit.
+    /// Proposing a different split replaces the previous one and its
+    /// approvals. Returns true once both users approved the same split.
+    pub fn approve_settlement(
+        &mut self,
+        user: &Addr,
+        to_user_a: Balance,
+        to_user_b: Balance,
+    ) -> Result<bool, EscrowError> {
+        if *user != self.user_a && *user != self.user_b {
+            return Err(EscrowError::NotAParty {});
+        }
+        self.check_split(&to_user_a, &to_user_b)?;
+
+        let settlement = match self.settlement.take() {
+            Some(s) if s.to_user_a == to_user_a && s.to_user_b == to_user_b => s,
+            _ => Settlement { to_user_a, to_user_b, approvals: vec![] },
+        };
+        let settlement = self.settlement.insert(settlement);
+        if !settlement.approvals.contains(user) {
+            settlement.approvals.push(user.clone());
+        }
+        Ok(settlement.approvals.contains(&self.user_a) && settlement.approvals.contains(&self.user_b))
+    }
+
+    /// cancel_agreed returns true once both users approved the cancellation
+    pub fn cancel_agreed(&self) -> bool {
+        self.cancel_approvals.contains(&self.user_a) && self.cancel_approvals.contains(&self.user_b)
+    }
+
+    /// check_milestone verifies that milestone designates what a withdrawal
+    /// releases: nothing for a plain escrow, otherwise the first tranche
+    /// not yet released, so that the legs are paid in order
+    pub fn check_milestone(&self, milestone: Option<u32>) -> Result<(), EscrowError> {
+        match (milestone, self.next_milestone()) {
+            (None, _) if self.milestones.is_empty() => Ok(()),
+            (None, _) => Err(EscrowError::MilestoneRequired {}),
+            (Some(index), Some(next)) if index as usize == next => Ok(()),
+            _ => Err(EscrowError::InvalidMilestone {}),
+        }
+    }
+
+    /// next_milestone returns the index of the first tranche not released
+    fn next_milestone(&self) -> Option<usize> {
+        self.milestones.iter().position(|m| !m.released)
+    }
+
+    /// release marks the milestone as paid and returns its tranche of the
+    /// deposit. Without a milestone, the whole deposit is released. This
+    /// drops any proposed settlement.
+    pub fn release(&mut self, milestone: Option<u32>) -> Result<Balance, EscrowError> {
+        self.check_milestone(milestone)?;
+        self.settlement = None;
+        match milestone {
+            Some(index) => {
+                let milestone = &mut self.milestones[index as usize];
+                milestone.released = true;
+                Ok(with_amount(&self.deposit, milestone.amount))
+            }
+            None => Ok(self.deposit.clone()),
+        }
+    }
+
+    /// is_fully_released returns true once every milestone was paid out
+    pub fn is_fully_released(&self) -> bool {
+        self.next_milestone().is_none()
+    }
+
+    /// remaining returns the part of the deposit not released yet
+    pub fn remaining(&self) -> Balance {
+        if self.milestones.is_empty() {
+            return self.deposit.clone();
+        }
+        let amount = self.milestones
+            .iter()
+            .filter(|m| !m.released)
+            .map(|m| m.amount)
+            .sum();
+        with_amount(&self.deposit, amount)
+    }
+
+    /// lock_of returns the lock guarding the deposit, or the given milestone
+    fn lock_of(&self, milestone: Option<u32>) -> Result<(&str, LockKind), EscrowError> {
+        match milestone {
+            None => Ok((&self.lock, self.lock_kind)),
+            Some(index) => self.milestones
+                .get(index as usize)
+                .map(|m| (m.lock.as_str(), m.lock_kind))
+                .ok_or(EscrowError::InvalidMilestone {}),
+        }
+    }
+
+    /// check secret against the lock of the escrow, or of the milestone
     /// Returns an EscrowError:InvalidSecret if the secret is invalid
-    pub fn unlock(&mut self, secret:&str) -> Result<(), EscrowError> {        
-        let private_key = hex::decode(secret);
-        if private_key.is_err() {
-            return Err(EscrowError::InvalidSecret {  });
+    pub fn unlock(&self, milestone: Option<u32>, secret:&str) -> Result<(), EscrowError> {
+        let (lock, lock_kind) = self.lock_of(milestone)?;
+        match lock_kind {
+            LockKind::K256 => unlock_k256(lock, secret),
+            LockKind::Sha256 => unlock_sha256(lock, secret),
         }
+    }
 
-        let recomputed_public_key = SigningKey::from_bytes(&private_key.unwrap())
-        .unwrap()
-        .verifying_key()
-        .to_encoded_point(true)
-        .as_bytes()
-        .to_vec();
+    /// verify_signature checks that signature is a valid signature of
+    /// payload by the private key corresponding to the lock of the escrow,
+    /// or of the milestone
+    /// Returns an EscrowError:InvalidSignature if the signature is invalid
+    pub fn verify_signature(
+        &self,
+        api: &dyn Api,
+        milestone: Option<u32>,
+        payload: &[u8],
+        signature: &str,
+    ) -> Result<(), EscrowError> {
+        let (lock, lock_kind) = self.lock_of(milestone)?;
+        if lock_kind != LockKind::K256 {
+            return Err(EscrowError::SignatureNotSupported {});
+        }
+
+        let signature = hex::decode(signature)
+            .map_err(|_| EscrowError::InvalidSignature {})?;
+        let public_key = hex::decode(lock)
+            .map_err(|_| EscrowError::InvalidSignature {})?;
 
-        let recomputed_public_key_str = hex::encode(recomputed_public_key);
+        let hash = Sha256::digest(payload);
 
-        if recomputed_public_key_str == self.lock {
-            return Ok(());
+        match api.secp256k1_verify(&hash, &signature, &public_key) {
+            Ok(true) => Ok(()),
+            _ => Err(EscrowError::InvalidSignature {}),
         }
+    }
+
+    /// dispute freezes the escrow until the arbiter resolves it
+    /// Returns an EscrowError:NotAParty if user is neither user_a nor user_b
+    /// and an EscrowError:NoArbiter if nobody can resolve the dispute
+    pub fn dispute(&mut self, user: &Addr, time: Timestamp) -> Result<(), EscrowError> {
+        if user != self.user_a && user != self.user_b {
+            return Err(EscrowError::NotAParty {});
+        }
+        if self.arbiter.is_none() {
+            return Err(EscrowError::NoArbiter {});
+        }
+        self.set_status(EscrowStatus::Disputed, time)
+    }
+
+    /// check_split verifies that the payouts to both users add up exactly
+    /// to the part of the deposit not released yet
+    pub fn check_split(&self, to_user_a: &Balance, to_user_b: &Balance) -> Result<(), EscrowError> {
+        match add_balances(to_user_a, to_user_b) {
+            Some(total) if total == normalize_balance(&self.remaining()) => Ok(()),
+            _ => Err(EscrowError::InvalidSplit {}),
+        }
+    }
+
+    /// is_closed returns true once the escrow is completed or cancelled
+    pub fn is_closed(&self) -> bool {
+        self.status.is_closed()
+    }
+
+    /// is_disputed returns true while the escrow waits for the arbiter
+    pub fn is_disputed(&self) -> bool {
+        self.status == EscrowStatus::Disputed
+    }
 
-        return Err(EscrowError::InvalidSecret { });
+    /// is_settled returns true once the escrow is completed or cancelled and
+    /// none of its payouts is waiting for a retry. IBC transfers in flight
+    /// are tracked apart, see state::has_ibc_transfers.
+    pub fn is_settled(&self) -> bool {
+        matches!(self.status, EscrowStatus::Completed | EscrowStatus::Cancelled)
     }
 
-    /// close sets the closed flag to true 
-    /// we can only close if the payout has already been computed
-    /// which indireclty ensures that the escrow is in a closeable 
-    /// state
-    pub fn close(&mut self) {
-        self.closed = true;
+    /// closed_at returns the time the escrow reached its current status, if
+    /// it is closed
+    pub fn closed_at(&self) -> Option<Timestamp> {
+        if !self.is_closed() {
+            return None;
+        }
+        self.history
+            .iter()
+            .rev()
+            .find(|change| change.status == self.status)
+            .map(|change| change.time)
+    }
+
+    /// payout_failed keeps a rejected transfer to retry it later, and marks
+    /// the escrow as waiting for the retry
+    pub fn payout_failed(&mut self, msg: CosmosMsg, time: Timestamp) -> Result<(), EscrowError> {
+        if self.status != EscrowStatus::PayoutFailed {
+            self.set_status(EscrowStatus::PayoutFailed, time)?;
+        }
+        self.failed_payouts.push(msg);
+        Ok(())
+    }
+
+    /// retry_payouts returns the failed transfers to send again, and puts
+    /// the escrow back in the status it had before the payout failed
+    /// Returns an EscrowError:NoFailedPayout unless a payout failed
+    pub fn retry_payouts(&mut self, time: Timestamp) -> Result<Vec<CosmosMsg>, EscrowError> {
+        self.take_failed_payouts(|_| true, time)
+    }
+
+    /// take_failed_payouts removes the failed transfers matching take, to
+    /// pay them some other way. Once none is left, the escrow is put back in
+    /// the status it had before the payout failed.
+    /// Returns an EscrowError:NoFailedPayout unless a payout failed
+    pub fn take_failed_payouts(
+        &mut self,
+        take: impl Fn(&CosmosMsg) -> bool,
+        time: Timestamp,
+    ) -> Result<Vec<CosmosMsg>, EscrowError> {
+        if self.status != EscrowStatus::PayoutFailed {
+            return Err(EscrowError::NoFailedPayout {});
+        }
+        let (taken, kept) = std::mem::take(&mut self.failed_payouts)
+            .into_iter()
+            .partition(|msg| take(msg));
+        self.failed_payouts = kept;
+        if !self.failed_payouts.is_empty() {
+            return Ok(taken);
+        }
+
+        // the status reached before the first failure of the last batch
+        let status = self.history
+            .iter()
+            .rev()
+            .map(|change| change.status)
+            .find(|status| *status != EscrowStatus::PayoutFailed)
+            .unwrap_or_default();
+        self.status = status;
+        self.history.push(StatusChange { status, time });
+        Ok(taken)
+    }
+
+    /// record_paid adds funds paid out by the escrow to its tally. They are
+    /// all parts of the deposit, so they add up.
+    pub fn record_paid(&mut self, to_user_a: &Balance, to_user_b: &Balance, fee: &Balance) -> Result<(), EscrowError> {
+        let add = |total: &Balance, paid: &Balance| add_balances(total, paid).ok_or(EscrowError::InvalidDeposit {});
+        self.paid = Paid {
+            to_user_a: add(&self.paid.to_user_a, to_user_a)?,
+            to_user_b: add(&self.paid.to_user_b, to_user_b)?,
+            fee: add(&self.paid.fee, fee)?,
+        };
+        Ok(())
+    }
+
+    /// set_status moves the escrow to the next status of the lifecycle and
+    /// records the time of the transition. Closing the escrow (completed or
+    /// cancelled) is only done once the payout has been computed.
+    pub fn set_status(&mut self, status: EscrowStatus, time: Timestamp) -> Result<(), EscrowError> {
+        if !self.status.can_become(status) {
+            return Err(EscrowError::InvalidTransition {
+                from: self.status.to_string(),
+                to: status.to_string(),
+            });
+        }
+        self.status = status;
+        self.history.push(StatusChange { status, time });
+        Ok(())
     }
 }
 
+/// check_deposit_amount fails unless the deposit is a single coin of the
+/// expected amount
+pub fn check_deposit_amount(deposit: &Balance, expected: Uint128) -> Result<(), EscrowError> {
+    if single_amount(deposit) != Some(expected) {
+        return Err(EscrowError::InvalidDeposit {});
+    }
+    Ok(())
+}
+
+/// basis points in a whole (100%)
+const MAX_BPS: u16 = 10_000;
+
+/// length in bytes of a secp256k1 private key
+const PRIVATE_KEY_LENGTH: usize = 32;
+
+/// check that the secret is the private key of the lock
+fn unlock_k256(lock: &str, secret: &str) -> Result<(), EscrowError> {
+    let private_key = hex::decode(secret)
+        .map_err(|_| EscrowError::InvalidSecretEncoding {})?;
+
+    if private_key.len() != PRIVATE_KEY_LENGTH {
+        return Err(EscrowError::InvalidSecretLength {});
+    }
+
+    // fails if the scalar is zero or not lower than the curve order
+    let signing_key = SigningKey::from_bytes(&private_key)
+        .map_err(|_| EscrowError::InvalidSecretScalar {})?;
+
+    let recomputed_public_key = signing_key
+        .verifying_key()
+        .to_encoded_point(true);
+
+    // the lock was validated on creation, and may be compressed or not
+    let lock = parse_public_key(lock)?.to_encoded_point(true);
+
+    if recomputed_public_key == lock {
+        return Ok(());
+    }
+
+    Err(EscrowError::InvalidSecret { })
+}
+
+/// check that the secret is a preimage of the lock
+fn unlock_sha256(lock: &str, secret: &str) -> Result<(), EscrowError> {
+    let preimage = hex::decode(secret)
+        .map_err(|_| EscrowError::InvalidSecretEncoding {})?;
+    let digest = hex::decode(lock)
+        .map_err(|_| EscrowError::InvalidLockEncoding {})?;
+
+    if Sha256::digest(preimage).as_slice() == digest.as_slice() {
+        return Ok(());
+    }
+
+    Err(EscrowError::InvalidSecret { })
+}
+
+/// validate_lock checks that the lock is well-formed for its kind, so that
+/// a typo cannot lock funds that nobody can withdraw
+pub fn validate_lock(lock: &str, lock_kind: LockKind) -> Result<(), EscrowError> {
+    match lock_kind {
+        LockKind::K256 => parse_public_key(lock).map(|_| ()),
+        LockKind::Sha256 => {
+            let digest = hex::decode(lock)
+                .map_err(|_| EscrowError::InvalidLockEncoding {})?;
+            if digest.len() != Sha256::output_size() {
+                return Err(EscrowError::InvalidLockDigest {});
+            }
+            Ok(())
+        }
+    }
+}
+
+/// parse_public_key decodes a hex-encoded SEC1 public key, compressed or
+/// uncompressed. Other encodings (identity, compact, hybrid) are rejected
+/// since they cannot be used to verify signatures.
+fn parse_public_key(lock: &str) -> Result<PublicKey, EscrowError> {
+    let bytes = hex::decode(lock)
+        .map_err(|_| EscrowError::InvalidLockEncoding {})?;
+    match (bytes.len(), bytes.first()) {
+        (33, Some(0x02 | 0x03)) | (65, Some(0x04)) => {}
+        _ => return Err(EscrowError::InvalidLockPoint {}),
+    }
+    PublicKey::from_sec1_bytes(&bytes)
+        .map_err(|_| EscrowError::InvalidLockPoint {})
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use cosmwasm_std::Uint128;
+    use cosmwasm_std::testing::{mock_env, MockApi};
+    use cosmwasm_std::{coin, coins};
+    use k256::ecdsa::{signature::Signer, Signature};
     use cw20::Cw20CoinVerified;
 
     const  DUMMY_LOCK: &str = "0330347c5cb0f1627bdd2e7b082504a443b2bf50ad2e3efbb4e754ebd687c78c24";
     const  DUMMY_SECRET: &str =  "27874aa2b70ce7281c94413c36d44fac6fa6a1198f2c529188c4dd4f7a4e1870";   
     static DUMMY_SECRET_INCORRECT: &str = "27874aa2b70ce7281c94413c36d44fac6fa6a1198f2c529188c4dd4f7a4exxxx";
+    const  DUMMY_SECRET_OTHER: &str = "27874aa2b70ce7281c94413c36d44fac6fa6a1198f2c529188c4dd4f7a4e1871";
     
     #[test]
     fn escrow_create_happy() {     
@@ -106,6 +846,10 @@ mod tests {
             Addr::unchecked("user_b"),
             deposit.clone(),
             DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
         ).unwrap();
         
     
@@ -126,10 +870,241 @@ mod tests {
             Addr::unchecked("user_b"),
             empty_deposit,
             DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
         ).unwrap_err();
         assert!(matches!(err, EscrowError::EmptyDeposit{}));
     }
 
+    #[test]
+    fn escrow_top_up() {
+        let mut escrow = Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            Balance::from(coins(100, "token")),
+            DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
+        ).unwrap();
+
+        let err = escrow.top_up(&Addr::unchecked("user_b"), &Balance::from(coins(10, "token"))).unwrap_err();
+        assert!(matches!(err, EscrowError::NotCreator{}));
+
+        let err = escrow.top_up(&Addr::unchecked("user_a"), &Balance::from(vec![])).unwrap_err();
+        assert!(matches!(err, EscrowError::EmptyDeposit{}));
+
+        let token = Balance::Cw20(Cw20CoinVerified {
+            address: Addr::unchecked("coin_address"),
+            amount: Uint128::new(10),
+        });
+        let err = escrow.top_up(&Addr::unchecked("user_a"), &token).unwrap_err();
+        assert!(matches!(err, EscrowError::MixedDeposit{}));
+
+        escrow.top_up(&Addr::unchecked("user_a"), &Balance::from(coins(10, "token"))).unwrap();
+        escrow.top_up(&Addr::unchecked("user_a"), &Balance::from(coins(5, "other"))).unwrap();
+        assert_eq!(
+            Balance::from(vec![coin(5, "other"), coin(110, "token")]),
+            escrow.deposit
+        );
+    }
+
+    #[test]
+    fn escrow_milestones() {
+        let create = |deposit: Balance| Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            deposit,
+            DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
+        ).unwrap();
+        let milestone = |amount: u128, lock: &str| Milestone {
+            amount: Uint128::new(amount),
+            lock: lock.to_string(),
+            lock_kind: LockKind::K256,
+            released: false,
+        };
+
+        // the deposit must be a single token, matched by the milestones
+        let err = create(Balance::from(vec![coin(50, "other"), coin(100, "token")]))
+            .with_milestones(vec![milestone(100, DUMMY_LOCK)])
+            .unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidMilestones{}));
+        let err = create(Balance::from(coins(100, "token")))
+            .with_milestones(vec![milestone(100, DUMMY_LOCK), milestone(0, DUMMY_LOCK)])
+            .unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidMilestones{}));
+        let err = create(Balance::from(coins(100, "token")))
+            .with_milestones(vec![milestone(70, DUMMY_LOCK), milestone(30, "LOCK")])
+            .unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidLockEncoding{}));
+
+        let mut escrow = create(Balance::from(coins(100, "token")))
+            .with_milestones(vec![milestone(70, DUMMY_LOCK), milestone(30, DUMMY_LOCK)])
+            .unwrap();
+        let err = escrow.top_up(&Addr::unchecked("user_a"), &Balance::from(coins(10, "token"))).unwrap_err();
+        assert!(matches!(err, EscrowError::MilestoneTopUp{}));
+
+        assert_eq!(Balance::from(coins(70, "token")), escrow.release(Some(0)).unwrap());
+        assert_eq!(Balance::from(coins(30, "token")), escrow.remaining());
+        assert!(!escrow.is_fully_released());
+
+        assert_eq!(Balance::from(coins(30, "token")), escrow.release(Some(1)).unwrap());
+        assert!(escrow.is_fully_released());
+        assert!(escrow.remaining().is_empty());
+    }
+
+    #[test]
+    fn escrow_contribute() {
+        let create = || Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            Balance::from(coins(40, "token")),
+            DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
+        ).unwrap();
+
+        let err = create().contribute(&Balance::from(coins(10, "token"))).unwrap_err();
+        assert!(matches!(err, EscrowError::NotShared{}));
+        let err = create().with_target(Some(Uint128::new(30))).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidTarget{}));
+
+        let mut escrow = create().with_target(Some(Uint128::new(100))).unwrap();
+        assert!(!escrow.is_funded());
+
+        let err = escrow.contribute(&Balance::from(coins(10, "other"))).unwrap_err();
+        assert!(matches!(err, EscrowError::MixedDeposit{}));
+        let err = escrow.top_up(&Addr::unchecked("user_a"), &Balance::from(coins(10, "token"))).unwrap_err();
+        assert!(matches!(err, EscrowError::SharedTopUp{}));
+        let err = escrow.contribute(&Balance::from(coins(61, "token"))).unwrap_err();
+        assert!(matches!(err, EscrowError::Overfunded{}));
+
+        assert_eq!(Uint128::new(60), escrow.contribute(&Balance::from(coins(60, "token"))).unwrap());
+        assert!(escrow.is_funded());
+    }
+
+    #[test]
+    fn escrow_approve_settlement() {
+        let mut escrow = Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            Balance::from(coins(100, "token")),
+            DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
+        ).unwrap();
+        let user_a = Addr::unchecked("user_a");
+        let user_b = Addr::unchecked("user_b");
+        let split = |a: u128, b: u128| (Balance::from(coins(a, "token")), Balance::from(coins(b, "token")));
+
+        let (to_a, to_b) = split(30, 70);
+        let err = escrow.approve_settlement(&Addr::unchecked("other"), to_a.clone(), to_b.clone()).unwrap_err();
+        assert!(matches!(err, EscrowError::NotAParty{}));
+
+        let (bad_a, bad_b) = split(30, 60);
+        let err = escrow.approve_settlement(&user_a, bad_a, bad_b).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidSplit{}));
+
+        assert!(!escrow.approve_settlement(&user_a, to_a, to_b).unwrap());
+
+        // a counter proposal replaces the approvals
+        let (to_a, to_b) = split(50, 50);
+        assert!(!escrow.approve_settlement(&user_b, to_a.clone(), to_b.clone()).unwrap());
+        assert_eq!(vec![user_b.clone()], escrow.settlement.as_ref().unwrap().approvals);
+
+        assert!(escrow.approve_settlement(&user_a, to_a, to_b).unwrap());
+    }
+
+    #[test]
+    fn status_transitions() {
+        use EscrowStatus::*;
+        assert!(Created.can_become(Accepted));
+        assert!(Accepted.can_become(Started));
+        assert!(Started.can_become(Completed));
+        assert!(Created.can_become(Cancelled));
+        assert!(Disputed.can_become(Completed));
+
+        assert!(!Created.can_become(Started));
+        assert!(!Started.can_become(Accepted));
+        assert!(!Disputed.can_become(Cancelled));
+        assert!(!Completed.can_become(Cancelled));
+        assert!(!Cancelled.can_become(Disputed));
+
+        assert!(Completed.is_closed());
+        assert!(Cancelled.is_closed());
+        assert!(!Disputed.is_closed());
+    }
+
+    #[test]
+    fn cancellation_fee() {
+        let deposit = || Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            Balance::from(coins(100, "token")),
+            DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
+        ).unwrap();
+
+        let err = deposit().with_cancellation_fee(Some(CancellationFee::Bps(10_001))).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidCancellationFee{}));
+
+        let mut e = deposit().with_cancellation_fee(Some(CancellationFee::Bps(2_500))).unwrap();
+        assert_eq!(None, e.cancellation_fee_due());
+        e.set_status(EscrowStatus::Accepted, Timestamp::from_seconds(1)).unwrap();
+        assert_eq!(Some(&CancellationFee::Bps(2_500)), e.cancellation_fee_due());
+
+        assert_eq!(Uint128::new(25), CancellationFee::Bps(2_500).amount_of(Uint128::new(101)));
+        assert_eq!(Uint128::new(30), CancellationFee::Fixed(Uint128::new(30)).amount_of(Uint128::new(100)));
+        assert_eq!(Uint128::new(20), CancellationFee::Fixed(Uint128::new(30)).amount_of(Uint128::new(20)));
+
+        // a fixed fee is only allowed on a single token
+        let fixed = Some(CancellationFee::Fixed(Uint128::new(30)));
+        let mut multi = deposit();
+        multi.deposit = Balance::from(vec![coin(100, "other"), coin(100, "token")]);
+        let err = multi.with_cancellation_fee(fixed.clone()).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidCancellationFee{}));
+
+        let mut e = deposit().with_cancellation_fee(fixed).unwrap();
+        let err = e.top_up(&Addr::unchecked("user_a"), &Balance::from(coins(50, "other"))).unwrap_err();
+        assert!(matches!(err, EscrowError::MixedDeposit{}));
+        e.top_up(&Addr::unchecked("user_a"), &Balance::from(coins(50, "token"))).unwrap();
+        assert_eq!(Balance::from(coins(150, "token")), e.deposit);
+    }
+
+    #[test]
+    fn deposit_amount() {
+        let token = Cw20CoinVerified {
+            address: Addr::unchecked("coin_address"),
+            amount: Uint128::new(100),
+        };
+        let deposit = Balance::Cw20(token);
+        check_deposit_amount(&deposit, Uint128::new(100)).unwrap();
+        let err = check_deposit_amount(&deposit, Uint128::new(99)).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidDeposit{}));
+
+        let deposit = Balance::from(coins(100, "token"));
+        check_deposit_amount(&deposit, Uint128::new(100)).unwrap();
+
+        // only a single coin can match
+        let deposit = Balance::from(vec![coin(100, "token"), coin(100, "other")]);
+        let err = check_deposit_amount(&deposit, Uint128::new(100)).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidDeposit{}));
+    }
+
     #[test]
     fn escrow_unlock() {     
         let coin = Cw20CoinVerified {
@@ -138,18 +1113,295 @@ mod tests {
         };
         let deposit = Balance::Cw20(coin);
 
-        let mut e = Escrow::create(
+        let e = Escrow::create(
             Addr::unchecked("user_a"),
             Addr::unchecked("user_b"),
             deposit.clone(),
             DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
         ).unwrap();
         
         // correct secret
-        let _ = e.unlock(DUMMY_SECRET).unwrap();
+        e.unlock(None, DUMMY_SECRET).unwrap();
 
         // invalid secret
-        let err = e.unlock(DUMMY_SECRET_INCORRECT).unwrap_err();
+        let err = e.unlock(None, DUMMY_SECRET_INCORRECT).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidSecretEncoding{}));
+
+        // another valid key
+        let err = e.unlock(None, DUMMY_SECRET_OTHER).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidSecret{}));
+    }
+
+    fn create_with_lock(lock: &str, lock_kind: LockKind) -> Result<Escrow, EscrowError> {
+        let coin = Cw20CoinVerified {
+            address: Addr::unchecked("coin_address"),
+            amount: Uint128::new(100),
+        };
+        Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            Balance::Cw20(coin),
+            lock,
+            lock_kind,
+            None,
+            None,
+            Timestamp::from_seconds(0),
+        )
+    }
+
+    #[test]
+    fn escrow_create_uncompressed_lock() {
+        let uncompressed_lock = hex::encode(
+            SigningKey::from_bytes(&hex::decode(DUMMY_SECRET).unwrap())
+                .unwrap()
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes(),
+        );
+
+        let e = create_with_lock(&uncompressed_lock, LockKind::K256).unwrap();
+        e.unlock(None, DUMMY_SECRET).unwrap();
+    }
+
+    #[test]
+    fn escrow_create_lock_not_hex() {
+        let err = create_with_lock("LOCK", LockKind::K256).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidLockEncoding{}));
+
+        let err = create_with_lock("LOCK", LockKind::Sha256).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidLockEncoding{}));
+    }
+
+    #[test]
+    fn escrow_create_lock_not_a_point() {
+        // truncated key
+        let err = create_with_lock(&DUMMY_LOCK[..64], LockKind::K256).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidLockPoint{}));
+
+        // invalid prefix
+        let lock = format!("05{}", &DUMMY_LOCK[2..]);
+        let err = create_with_lock(&lock, LockKind::K256).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidLockPoint{}));
+
+        // x coordinate not on the curve
+        let lock = format!("02{}", "00".repeat(32));
+        let err = create_with_lock(&lock, LockKind::K256).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidLockPoint{}));
+    }
+
+    #[test]
+    fn escrow_create_lock_not_a_digest() {
+        let err = create_with_lock(&"ab".repeat(31), LockKind::Sha256).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidLockDigest{}));
+    }
+
+    #[test]
+    fn escrow_unlock_secret_wrong_length() {
+        let e = create_with_lock(DUMMY_LOCK, LockKind::K256).unwrap();
+
+        let err = e.unlock(None, &DUMMY_SECRET[..62]).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidSecretLength{}));
+
+        let err = e.unlock(None, &format!("{}00", DUMMY_SECRET)).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidSecretLength{}));
+    }
+
+    #[test]
+    fn escrow_unlock_secret_out_of_range() {
+        let e = create_with_lock(DUMMY_LOCK, LockKind::K256).unwrap();
+
+        // zero
+        let err = e.unlock(None, &"00".repeat(32)).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidSecretScalar{}));
+
+        // larger than the curve order
+        let err = e.unlock(None, &"ff".repeat(32)).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidSecretScalar{}));
+    }
+
+    #[test]
+    fn escrow_expiry_and_cancel_approvals() {
+        let coin = Cw20CoinVerified {
+            address: Addr::unchecked("coin_address"),
+            amount: Uint128::new(100),
+        };
+        let env = mock_env();
+
+        let mut e = Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            Balance::Cw20(coin),
+            DUMMY_LOCK,
+            LockKind::K256,
+            Some(Expiration::AtHeight(env.block.height + 10)),
+            None,
+            Timestamp::from_seconds(0),
+        ).unwrap();
+        assert!(!e.is_expired(&env.block));
+
+        let mut later = env.block.clone();
+        later.height += 10;
+        assert!(e.is_expired(&later));
+
+        // only the two users can approve a cancellation
+        let err = e.approve_cancel(&Addr::unchecked("user_c")).unwrap_err();
+        assert!(matches!(err, EscrowError::NotAParty{}));
+
+        e.approve_cancel(&Addr::unchecked("user_a")).unwrap();
+        e.approve_cancel(&Addr::unchecked("user_a")).unwrap();
+        assert!(!e.cancel_agreed());
+
+        e.approve_cancel(&Addr::unchecked("user_b")).unwrap();
+        assert!(e.cancel_agreed());
+    }
+
+    #[test]
+    fn escrow_dispute_and_split() {
+        let coin = Cw20CoinVerified {
+            address: Addr::unchecked("coin_address"),
+            amount: Uint128::new(100),
+        };
+
+        let mut e = Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            Balance::Cw20(coin),
+            DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
+        ).unwrap();
+
+        // no arbiter to resolve the dispute
+        let err = e.dispute(&Addr::unchecked("user_a"), Timestamp::from_seconds(1)).unwrap_err();
+        assert!(matches!(err, EscrowError::NoArbiter{}));
+
+        e.arbiter = Some(Addr::unchecked("arbiter"));
+        let err = e.dispute(&Addr::unchecked("user_c"), Timestamp::from_seconds(1)).unwrap_err();
+        assert!(matches!(err, EscrowError::NotAParty{}));
+        e.dispute(&Addr::unchecked("user_b"), Timestamp::from_seconds(1)).unwrap();
+        assert!(e.is_disputed());
+
+        let part = |amount: u128| Balance::Cw20(Cw20CoinVerified {
+            address: Addr::unchecked("coin_address"),
+            amount: Uint128::new(amount),
+        });
+        e.check_split(&part(30), &part(70)).unwrap();
+        e.check_split(&part(100), &Balance::default()).unwrap();
+
+        let err = e.check_split(&part(30), &part(60)).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidSplit{}));
+    }
+
+    #[test]
+    fn escrow_verify_signature() {
+        let coin = Cw20CoinVerified {
+            address: Addr::unchecked("coin_address"),
+            amount: Uint128::new(100),
+        };
+
+        let e = Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            Balance::Cw20(coin),
+            DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
+        ).unwrap();
+
+        let api = MockApi::default();
+        let key = SigningKey::from_bytes(&hex::decode(DUMMY_SECRET).unwrap()).unwrap();
+        let signature: Signature = key.sign(b"payload");
+        let signature = hex::encode(signature.as_ref());
+
+        // correct signature
+        e.verify_signature(&api, None, b"payload", &signature).unwrap();
+
+        // signature of another payload
+        let err = e.verify_signature(&api, None, b"other payload", &signature).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidSignature{}));
+
+        // malformed signature
+        let err = e.verify_signature(&api, None, b"payload", "xxxx").unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidSignature{}));
+    }
+
+    #[test]
+    fn escrow_unlock_sha256() {
+        let coin = Cw20CoinVerified {
+            address: Addr::unchecked("coin_address"),
+            amount: Uint128::new(100),
+        };
+
+        let preimage = hex::encode(b"nride swap preimage");
+        let digest = hex::encode(Sha256::digest(b"nride swap preimage"));
+
+        let e = Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            Balance::Cw20(coin),
+            &digest,
+            LockKind::Sha256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
+        ).unwrap();
+
+        // correct preimage
+        e.unlock(None, &preimage).unwrap();
+
+        // wrong preimage
+        let err = e.unlock(None, &hex::encode(b"another preimage")).unwrap_err();
         assert!(matches!(err, EscrowError::InvalidSecret{}));
+
+        // hash locks cannot be opened with a signature
+        let err = e.verify_signature(&MockApi::default(), None, b"payload", "00").unwrap_err();
+        assert!(matches!(err, EscrowError::SignatureNotSupported{}));
+    }
+
+    #[test]
+    fn ibc_timeout_bounds() {
+        let mut destination = IbcDestination {
+            channel_id: "channel-0".to_string(),
+            remote_address: "osmo1driver".to_string(),
+            timeout_seconds: MAX_IBC_TIMEOUT_SECONDS,
+            fallback: Addr::unchecked("user_b"),
+        };
+        let e = Escrow::create(
+            Addr::unchecked("user_a"),
+            Addr::unchecked("user_b"),
+            Balance::from(coins(100, "token")),
+            DUMMY_LOCK,
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
+        ).unwrap();
+
+        // up to a week
+        let now = Timestamp::from_seconds(1_000);
+        let e = e.with_ibc_destination(Some(destination.clone())).unwrap();
+        assert_eq!(
+            IbcTimeout::with_timestamp(now.plus_seconds(MAX_IBC_TIMEOUT_SECONDS)),
+            destination.timeout(now).unwrap()
+        );
+        destination.timeout_seconds += 1;
+        let err = e.clone().with_ibc_destination(Some(destination.clone())).unwrap_err();
+        assert!(matches!(err, EscrowError::InvalidIbcDestination{}));
+
+        // a timeout past the range of timestamps is refused, not wrapped
+        destination.timeout_seconds = u64::MAX / 1_000_000_000;
+        let err = destination.timeout(now).unwrap_err();
+        assert!(matches!(err, EscrowError::IbcTimeoutOverflow{}));
+        destination.timeout_seconds = u64::MAX;
+        let err = destination.timeout(now).unwrap_err();
+        assert!(matches!(err, EscrowError::IbcTimeoutOverflow{}));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,146 @@
+use cosmwasm_std::{Addr, Binary, Coin, CosmosMsg, IbcTimeout};
+
+/// type of the ICS-20 transfer message of ibc-go
+pub const MSG_TRANSFER_TYPE_URL: &str = "/ibc.applications.transfer.v1.MsgTransfer";
+
+/// transfer_msg sends coins over IBC like IbcMsg::Transfer, but as a
+/// MsgTransfer whose memo asks the ibc-hooks middleware to report the
+/// acknowledgement or timeout to the contract. IbcMsg::Transfer cannot carry
+/// a memo.
+pub fn transfer_msg(
+    contract: &Addr,
+    channel_id: &str,
+    to_address: &str,
+    amount: &Coin,
+    timeout: &IbcTimeout,
+) -> CosmosMsg {
+    let mut coin = vec![];
+    put_string(&mut coin, 1, &amount.denom);
+    put_string(&mut coin, 2, &amount.amount.to_string());
+
+    let mut value = vec![];
+    put_string(&mut value, 1, "transfer");
+    put_string(&mut value, 2, channel_id);
+    put_bytes(&mut value, 3, &coin);
+    put_string(&mut value, 4, contract.as_str());
+    put_string(&mut value, 5, to_address);
+    if let Some(block) = timeout.block() {
+        let mut height = vec![];
+        put_uint64(&mut height, 1, block.revision);
+        put_uint64(&mut height, 2, block.height);
+        put_bytes(&mut value, 6, &height);
+    }
+    if let Some(time) = timeout.timestamp() {
+        put_uint64(&mut value, 7, time.nanos());
+    }
+    put_string(&mut value, 8, &format!(r#"{{"ibc_callback":"{}"}}"#, contract));
+
+    CosmosMsg::Stargate {
+        type_url: MSG_TRANSFER_TYPE_URL.to_string(),
+        value: Binary::from(value),
+    }
+}
+
+/// transfer_sequence reads the packet sequence from the data of an IBC
+/// transfer, a protobuf MsgTransferResponse holding it as field 1
+pub fn transfer_sequence(data: &[u8]) -> Option<u64> {
+    let (&tag, mut rest) = data.split_first()?;
+    if tag != 0x08 {
+        return None;
+    }
+    // varint, 7 bits per byte, least significant first
+    let mut sequence = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, tail) = rest.split_first()?;
+        rest = tail;
+        sequence |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(sequence);
+        }
+    }
+    None
+}
+
+// protobuf encoding of the fields of MsgTransfer. Default values are
+// omitted, as the protobuf encoders do.
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_uint64(buf: &mut Vec<u8>, field: u64, value: u64) {
+    if value != 0 {
+        put_varint(buf, field << 3);
+        put_varint(buf, value);
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    if !value.is_empty() {
+        put_varint(buf, field << 3 | 2);
+        put_varint(buf, value.len() as u64);
+        buf.extend_from_slice(value);
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, field: u64, value: &str) {
+    put_bytes(buf, field, value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cosmwasm_std::{IbcTimeoutBlock, Timestamp};
+
+    #[test]
+    fn transfer_msg_encoding() {
+        let contract = Addr::unchecked("contract");
+        let amount = Coin::new(100, "token");
+        let memo = br#"{"ibc_callback":"contract"}"#;
+
+        let timeout = IbcTimeout::with_timestamp(Timestamp::from_nanos(300));
+        let msg = transfer_msg(&contract, "channel-0", "osmo1driver", &amount, &timeout);
+        let expected = [
+            &b"\x0a\x08transfer"[..],
+            b"\x12\x09channel-0",
+            b"\x1a\x0c\x0a\x05token\x12\x03100",
+            b"\x22\x08contract",
+            b"\x2a\x0bosmo1driver",
+            b"\x38\xac\x02",
+            &[0x42, memo.len() as u8],
+            memo,
+        ]
+        .concat();
+        assert_eq!(
+            CosmosMsg::Stargate {
+                type_url: MSG_TRANSFER_TYPE_URL.to_string(),
+                value: Binary::from(expected),
+            },
+            msg
+        );
+
+        // a timeout height is sent as an embedded Height
+        let timeout = IbcTimeout::with_block(IbcTimeoutBlock { revision: 1, height: 5 });
+        let msg = transfer_msg(&contract, "channel-0", "osmo1driver", &amount, &timeout);
+        let value = match msg {
+            CosmosMsg::Stargate { value, .. } => value,
+            _ => unreachable!(),
+        };
+        let height = b"\x32\x04\x08\x01\x10\x05";
+        assert!(value.windows(height.len()).any(|w| w == height));
+    }
+
+    #[test]
+    fn sequence_from_transfer_response() {
+        assert_eq!(Some(7), transfer_sequence(&[0x08, 0x07]));
+        assert_eq!(Some(300), transfer_sequence(&[0x08, 0xac, 0x02]));
+        assert_eq!(None, transfer_sequence(&[0x08, 0x80]));
+        assert_eq!(None, transfer_sequence(&[0x10, 0x07]));
+        assert_eq!(None, transfer_sequence(&[]));
+    }
+}
@@ -2,17 +2,204 @@
 
 use cw_utils::NativeBalance;
 
-use cosmwasm_std::{coins, to_binary, Addr, Empty, Uint128};
+use cosmwasm_std::{
+    coins, to_binary, Addr, Binary, Coin, CosmosMsg, Deps, DepsMut, Empty, Env, MessageInfo, Reply,
+    Response, StdError, StdResult, SubMsgResponse, SubMsgResult, Uint128, WasmMsg,
+};
 use cw20::{Cw20Coin, Cw20Contract, Cw20ExecuteMsg, Balance, Cw20CoinVerified};
 use cw_multi_test::{App, Contract, ContractWrapper, Executor};
+use cw_storage_plus::Item;
+use serde::{Deserialize, Serialize};
 
-use crate::msg::{CreateMsg, WithdrawMsg, DetailsResponse, ExecuteMsg, InstantiateMsg, QueryMsg, ReceiveMsg};
+use crate::ibc::MSG_TRANSFER_TYPE_URL;
+use crate::migrations::v0_2::{LegacyEscrow, LEGACY_ESCROWS};
+use crate::msg::{
+    CreateMsg, WithdrawMsg, DetailsResponse, DetailsListResponse, ConfigResponse, EscrowStatus,
+    ExecuteMsg, IbcDestinationMsg, IbcLifecycleComplete, InstantiateMsg, LockKind, MigrateMsg,
+    QueryMsg, ReceiveMsg, StatusChange, SudoMsg,
+};
 
 pub fn contract_escrow() -> Box<dyn Contract<Empty>> {
     let contract = ContractWrapper::new(
         crate::contract::execute,
         crate::contract::instantiate,
         crate::contract::query,
+    )
+    .with_migrate(crate::contract::migrate)
+    .with_reply(crate::contract::reply)
+    .with_sudo(crate::contract::sudo);
+    Box::new(contract)
+}
+
+/// IbcTransferStub runs the escrow contract on a chain with the transfer
+/// module, which multi-test lacks. The MsgTransfer messages it sends are
+/// executed by a transfer contract (contract_transfer), which keeps the
+/// coins and returns the packet sequence; the test then plays the ibc-hooks
+/// middleware with sudo IbcLifecycleComplete.
+struct IbcTransferStub {
+    escrow: Box<dyn Contract<Empty>>,
+    transfer: Addr,
+}
+
+impl IbcTransferStub {
+    fn route_transfers(&self, env: &Env, mut res: Response) -> anyhow::Result<Response> {
+        for submsg in res.messages.iter_mut() {
+            let value = match &submsg.msg {
+                CosmosMsg::Stargate { type_url, value } if type_url == MSG_TRANSFER_TYPE_URL => value,
+                _ => continue,
+            };
+            let fields = protobuf_fields(value);
+            let field = |number| fields.iter().find(|(n, _)| *n == number).map(|(_, v)| *v).unwrap_or_default();
+            let token = protobuf_fields(field(3));
+            let token_field = |number| token.iter().find(|(n, _)| *n == number).map(|(_, v)| *v).unwrap_or_default();
+
+            // the memo asks ibc-hooks to report back to the escrow contract
+            let memo = format!(r#"{{"ibc_callback":"{}"}}"#, env.contract.address);
+            assert_eq!(memo.as_bytes(), field(8));
+
+            let coin = Coin::new(
+                String::from_utf8(token_field(2).to_vec())?.parse()?,
+                String::from_utf8(token_field(1).to_vec())?,
+            );
+            submsg.msg = WasmMsg::Execute {
+                contract_addr: self.transfer.to_string(),
+                msg: to_binary(&TransferMsg::Transfer {
+                    channel_id: String::from_utf8(field(2).to_vec())?,
+                    receiver: String::from_utf8(field(5).to_vec())?,
+                })?,
+                funds: vec![coin],
+            }
+            .into();
+        }
+        Ok(res)
+    }
+}
+
+impl Contract<Empty> for IbcTransferStub {
+    fn execute(&self, deps: DepsMut, env: Env, info: MessageInfo, msg: Vec<u8>) -> anyhow::Result<Response> {
+        let res = self.escrow.execute(deps, env.clone(), info, msg)?;
+        self.route_transfers(&env, res)
+    }
+
+    fn instantiate(&self, deps: DepsMut, env: Env, info: MessageInfo, msg: Vec<u8>) -> anyhow::Result<Response> {
+        self.escrow.instantiate(deps, env, info, msg)
+    }
+
+    fn query(&self, deps: Deps, env: Env, msg: Vec<u8>) -> anyhow::Result<Binary> {
+        self.escrow.query(deps, env, msg)
+    }
+
+    fn sudo(&self, deps: DepsMut, env: Env, msg: Vec<u8>) -> anyhow::Result<Response> {
+        let res = self.escrow.sudo(deps, env.clone(), msg)?;
+        self.route_transfers(&env, res)
+    }
+
+    fn reply(&self, deps: DepsMut, env: Env, mut msg: Reply) -> anyhow::Result<Response> {
+        // wasmd wraps the data of an execute in a MsgExecuteContractResponse,
+        // while the transfer module returns its MsgTransferResponse as is
+        if let SubMsgResult::Ok(SubMsgResponse { data: Some(data), .. }) = &mut msg.result {
+            if let Some((1, inner)) = protobuf_fields(data).first() {
+                *data = Binary::from(inner.to_vec());
+            }
+        }
+        let res = self.escrow.reply(deps, env.clone(), msg)?;
+        self.route_transfers(&env, res)
+    }
+
+    fn migrate(&self, deps: DepsMut, env: Env, msg: Vec<u8>) -> anyhow::Result<Response> {
+        self.escrow.migrate(deps, env, msg)
+    }
+}
+
+/// length-delimited fields of a protobuf message, by number. Varints are
+/// skipped, the other wire types are not used by MsgTransfer.
+fn protobuf_fields(mut buf: &[u8]) -> Vec<(u64, &[u8])> {
+    fn varint(buf: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = buf[0];
+            *buf = &buf[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    let mut fields = vec![];
+    while !buf.is_empty() {
+        let key = varint(&mut buf);
+        match key & 7 {
+            0 => {
+                varint(&mut buf);
+            }
+            2 => {
+                let len = varint(&mut buf) as usize;
+                fields.push((key >> 3, &buf[..len]));
+                buf = &buf[len..];
+            }
+            wire => panic!("unexpected wire type {}", wire),
+        }
+    }
+    fields
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferMsg {
+    Transfer { channel_id: String, receiver: String },
+}
+
+const TRANSFER_SEQUENCE: Item<u64> = Item::new("sequence");
+
+fn transfer_execute(deps: DepsMut, _env: Env, _info: MessageInfo, _msg: TransferMsg) -> StdResult<Response> {
+    let sequence = TRANSFER_SEQUENCE.may_load(deps.storage)?.unwrap_or_default() + 1;
+    TRANSFER_SEQUENCE.save(deps.storage, &sequence)?;
+    // a MsgTransferResponse, with the sequence as field 1
+    Ok(Response::new().set_data(Binary::from(vec![0x08, sequence as u8])))
+}
+
+fn transfer_instantiate(_deps: DepsMut, _env: Env, _info: MessageInfo, _msg: Empty) -> StdResult<Response> {
+    Ok(Response::default())
+}
+
+fn transfer_query(_deps: Deps, _env: Env, _msg: Empty) -> StdResult<Binary> {
+    Err(StdError::generic_err("no queries"))
+}
+
+/// contract_transfer stands for the transfer module: it keeps the coins
+/// sent over IBC and numbers the packets
+pub fn contract_transfer() -> Box<dyn Contract<Empty>> {
+    let contract = ContractWrapper::new(transfer_execute, transfer_instantiate, transfer_query);
+    Box::new(contract)
+}
+
+/// state written by v0.2.0 of the escrow contract
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LegacyInstantiateMsg {
+    pub escrows: Vec<(String, LegacyEscrow)>,
+}
+
+/// legacy_instantiate populates the store as v0.2.0 would have left it
+fn legacy_instantiate(
+    deps: DepsMut,
+    _env: Env,
+    _info: MessageInfo,
+    msg: LegacyInstantiateMsg,
+) -> StdResult<Response> {
+    cw2::set_contract_version(deps.storage, "crates.io:cw-nride-escrow", "0.2.0")?;
+    for (id, escrow) in msg.escrows {
+        LEGACY_ESCROWS.save(deps.storage, &id, &escrow)?;
+    }
+    Ok(Response::default())
+}
+
+pub fn contract_escrow_v0_2() -> Box<dyn Contract<Empty>> {
+    let contract = ContractWrapper::new(
+        crate::contract::execute,
+        legacy_instantiate,
+        crate::contract::query,
     );
     Box::new(contract)
 }
@@ -33,13 +220,10 @@ fn escrow_happy_path_native_tokens() {
     const T_ID: &str= "demo"; // escrow ID
     const ALICE: &str = "alice"; // alice Addr
     const BOB: &str  = "bob";   // bob Addr
-    const ALICE_INIT_BAL: u128 = 5000; // alice initial balance in cw20 token
-    const BOB_INIT_BAL: u128 = 5000; // bob initial balance in cw20 token
     
     // Escrow Params
     const ALICE_LOCK: &str = "0330347c5cb0f1627bdd2e7b082504a443b2bf50ad2e3efbb4e754ebd687c78c24";
     const ALICE_SECRET: &str  =  "27874aa2b70ce7281c94413c36d44fac6fa6a1198f2c529188c4dd4f7a4e1870";   
-    const T_DEPOSIT_AMOUNT: u128 = 1200;
 
     /*********************************************************************************** 
     Initialize the context and deploy the Escrow contract from the "owner" account
@@ -88,7 +272,12 @@ fn escrow_happy_path_native_tokens() {
         .instantiate_contract(
             escrow_id,
             owner.clone(),
-            &InstantiateMsg {},
+            &InstantiateMsg {
+                fee_collector: owner.to_string(),
+                fee_bps: 0,
+                allowed_native: vec!["btc".to_string()],
+                allowed_cw20: vec![],
+            },
             &[],
             "Escrow",
             None,
@@ -98,9 +287,18 @@ fn escrow_happy_path_native_tokens() {
     // create escrow with native token
     // prepare the inner escrow msg    
     let create_msg = ExecuteMsg::Create(CreateMsg {
-        id: T_ID.to_string(),
+        id: Some(T_ID.to_string()),
         user_b: BOB.to_string(),
         lock: String::from(ALICE_LOCK),
+        lock_kind: LockKind::K256,
+        expires_at: None,
+        arbiter: None,
+        expected_amount: None,
+        cancellation_fee: None,
+        milestones: vec![],
+        target: None,
+        payout_msg: None,
+        ibc_destination: None,
     });
 
     // send the TX from alice's account    
@@ -111,7 +309,9 @@ fn escrow_happy_path_native_tokens() {
         &coins(10, "btc"),
     )
     .unwrap();
-    assert_eq!(2, res.events.len());
+    assert_eq!(3, res.events.len());
+    assert_eq!(res.events[2].ty.as_str(), "wasm-escrow_status");
+    let created_at = router.block_info().time;
 
     assert_eq!(
         router.wrap()
@@ -153,7 +353,21 @@ fn escrow_happy_path_native_tokens() {
             user_b: BOB.to_string(),
             deposit:Balance::Native(NativeBalance(coins(10, "btc"))),
             lock: ALICE_LOCK.to_string(),
-            closed: false,
+            lock_kind: LockKind::K256,
+            status: EscrowStatus::Created,
+            history: vec![StatusChange { status: EscrowStatus::Created, time: created_at }],
+            expires_at: None,
+            expired: false,
+            arbiter: None,
+            cancellation_fee: None,
+            settlement: None,
+            milestones: vec![],
+            target: None,
+            funded: true,
+            failed_payouts: vec![],
+            payout_msg: None,
+            ibc_destination: None,
+            nonce: 1,
         }
     );
 
@@ -164,7 +378,10 @@ fn escrow_happy_path_native_tokens() {
     // prepare withdraw msg
     let withdraw_msg = ExecuteMsg::Withdraw(WithdrawMsg {
         id: T_ID.to_string(),
-        secret: ALICE_SECRET.to_string(),
+        secret: Some(ALICE_SECRET.to_string()),
+        signature: None,
+        milestone: None,
+        payout_msg: None,
     });
     // send the TX from bob's account
     _ = router
@@ -210,7 +427,24 @@ fn escrow_happy_path_native_tokens() {
             user_b: BOB.to_string(),
             deposit:Balance::Native(NativeBalance(coins(10, "btc"))),
             lock: ALICE_LOCK.to_string(),
-            closed: true,
+            lock_kind: LockKind::K256,
+            status: EscrowStatus::Completed,
+            history: vec![
+                StatusChange { status: EscrowStatus::Created, time: created_at },
+                StatusChange { status: EscrowStatus::Completed, time: created_at },
+            ],
+            expires_at: None,
+            expired: false,
+            arbiter: None,
+            cancellation_fee: None,
+            settlement: None,
+            milestones: vec![],
+            target: None,
+            funded: true,
+            failed_payouts: vec![],
+            payout_msg: None,
+            ibc_destination: None,
+            nonce: 1,
         }
     );    
 }
@@ -275,7 +509,12 @@ fn escrow_happy_path_cw20_tokens() {
         .instantiate_contract(
             escrow_id,
             owner.clone(),
-            &InstantiateMsg {},
+            &InstantiateMsg {
+                fee_collector: owner.to_string(),
+                fee_bps: 0,
+                allowed_native: vec![],
+                allowed_cw20: vec![cash_addr.to_string()],
+            },
             &[],
             "Escrow",
             None,
@@ -291,7 +530,7 @@ fn escrow_happy_path_cw20_tokens() {
     // ensure our balances
     let a_balance = cash.balance::<_, _, Empty>(&router, ALICE.to_string()).unwrap();
     assert_eq!(a_balance, Uint128::new(ALICE_INIT_BAL));
-    let b_balance = cash.balance::<_, _, Empty>(&router, BOB.clone()).unwrap();
+    let b_balance = cash.balance::<_, _, Empty>(&router, BOB).unwrap();
     assert_eq!(b_balance, Uint128::new(BOB_INIT_BAL));
     let escrow_balance = cash
         .balance::<_, _, Empty>(&router, escrow_addr.clone())
@@ -306,9 +545,18 @@ fn escrow_happy_path_cw20_tokens() {
 
     // prepare the inner escrow msg    
     let create_msg = ReceiveMsg::Create(CreateMsg {
-        id: T_ID.to_string(),
+        id: Some(T_ID.to_string()),
         user_b: BOB.to_string(),
         lock: String::from(ALICE_LOCK),
+        lock_kind: LockKind::K256,
+        expires_at: None,
+        arbiter: None,
+        expected_amount: None,
+        cancellation_fee: None,
+        milestones: vec![],
+        target: None,
+        payout_msg: None,
+        ibc_destination: None,
     });
     // prepare the cw20 message, containing the escrow msg
     // the amount of tokens sent here to the cw20 will make it to the escrow contract
@@ -323,7 +571,7 @@ fn escrow_happy_path_cw20_tokens() {
     let res = router
         .execute_contract(Addr::unchecked(ALICE), cash_addr.clone(), &send_msg, &[])
         .unwrap();
-    assert_eq!(4, res.events.len());
+    assert_eq!(5, res.events.len());
 
     assert_eq!(res.events[0].ty.as_str(), "execute");
     let cw20_attr = res.custom_attrs(1);
@@ -332,11 +580,13 @@ fn escrow_happy_path_cw20_tokens() {
     assert_eq!(res.events[2].ty.as_str(), "execute");
     let escrow_attr = res.custom_attrs(3);
     assert_eq!(2, escrow_attr.len());
+    assert_eq!(res.events[4].ty.as_str(), "wasm-escrow_status");
+    let created_at = router.block_info().time;
 
     // ensure balances updated
     let a_balance = cash.balance::<_, _, Empty>(&router, ALICE.to_string()).unwrap();
     assert_eq!(a_balance, Uint128::new(ALICE_INIT_BAL - T_DEPOSIT_AMOUNT));
-    let b_balance = cash.balance::<_, _, Empty>(&router, BOB.clone()).unwrap();
+    let b_balance = cash.balance::<_, _, Empty>(&router, BOB).unwrap();
     assert_eq!(b_balance, Uint128::new(BOB_INIT_BAL));
     let escrow_balance = cash
         .balance::<_, _, Empty>(&router, escrow_addr.clone())
@@ -361,7 +611,21 @@ fn escrow_happy_path_cw20_tokens() {
                 },
             ),
             lock: ALICE_LOCK.to_string(),
-            closed: false,
+            lock_kind: LockKind::K256,
+            status: EscrowStatus::Created,
+            history: vec![StatusChange { status: EscrowStatus::Created, time: created_at }],
+            expires_at: None,
+            expired: false,
+            arbiter: None,
+            cancellation_fee: None,
+            settlement: None,
+            milestones: vec![],
+            target: None,
+            funded: true,
+            failed_payouts: vec![],
+            payout_msg: None,
+            ibc_destination: None,
+            nonce: 1,
         }
     );
 
@@ -373,7 +637,10 @@ fn escrow_happy_path_cw20_tokens() {
     // prepare withdraw msg
     let withdraw_msg = ExecuteMsg::Withdraw(WithdrawMsg {
         id: T_ID.to_string(),
-        secret: ALICE_SECRET.to_string(),
+        secret: Some(ALICE_SECRET.to_string()),
+        signature: None,
+        milestone: None,
+        payout_msg: None,
     });
     // send the TX from bob's account
     _ = router
@@ -383,7 +650,7 @@ fn escrow_happy_path_cw20_tokens() {
     // ensure balances updated
     let a_balance = cash.balance::<_, _, Empty>(&router, ALICE.to_string()).unwrap();
     assert_eq!(a_balance, Uint128::new(ALICE_INIT_BAL - T_DEPOSIT_AMOUNT));
-    let b_balance = cash.balance::<_, _, Empty>(&router, BOB.clone()).unwrap();
+    let b_balance = cash.balance::<_, _, Empty>(&router, BOB).unwrap();
     assert_eq!(b_balance, Uint128::new(BOB_INIT_BAL + T_DEPOSIT_AMOUNT));
     let escrow_balance = cash
         .balance::<_, _, Empty>(&router, escrow_addr.clone())
@@ -408,7 +675,405 @@ fn escrow_happy_path_cw20_tokens() {
                 },
             ),
             lock: ALICE_LOCK.to_string(),
-            closed: true,
+            lock_kind: LockKind::K256,
+            status: EscrowStatus::Completed,
+            history: vec![
+                StatusChange { status: EscrowStatus::Created, time: created_at },
+                StatusChange { status: EscrowStatus::Completed, time: created_at },
+            ],
+            expires_at: None,
+            expired: false,
+            arbiter: None,
+            cancellation_fee: None,
+            settlement: None,
+            milestones: vec![],
+            target: None,
+            funded: true,
+            failed_payouts: vec![],
+            payout_msg: None,
+            ibc_destination: None,
+            nonce: 1,
         }
     );
-}
\ No newline at end of file
+}
+#[test]
+// upgrade a populated v0.2.0 escrow contract in place
+fn migrate_from_v0_2() {
+
+    const ALICE: &str = "alice"; // alice Addr
+    const BOB: &str  = "bob";   // bob Addr
+    const ALICE_LOCK: &str = "0330347c5cb0f1627bdd2e7b082504a443b2bf50ad2e3efbb4e754ebd687c78c24";
+    const ALICE_SECRET: &str  =  "27874aa2b70ce7281c94413c36d44fac6fa6a1198f2c529188c4dd4f7a4e1870";
+
+    let owner = Addr::unchecked("owner");
+    let mut router = App::new(|router, _, storage| {
+        router
+            .bank
+            .init_balance(storage, &owner, coins(2000, "btc"))
+            .unwrap();
+    });
+
+    let legacy_escrow = |closed: bool| LegacyEscrow {
+        user_a: Addr::unchecked(ALICE),
+        user_b: Addr::unchecked(BOB),
+        deposit: Balance::from(coins(10, "btc")),
+        lock: ALICE_LOCK.to_string(),
+        closed,
+    };
+
+    // deploy v0.2.0 holding the deposit of its only open escrow
+    let legacy_id = router.store_code(contract_escrow_v0_2());
+    let escrow_addr = router
+        .instantiate_contract(
+            legacy_id,
+            owner.clone(),
+            &LegacyInstantiateMsg {
+                escrows: vec![
+                    ("ride1".to_string(), legacy_escrow(true)),
+                    ("ride2".to_string(), legacy_escrow(false)),
+                ],
+            },
+            &coins(10, "btc"),
+            "Escrow",
+            Some(owner.to_string()),
+        )
+        .unwrap();
+
+    // migrate to the current code
+    let escrow_id = router.store_code(contract_escrow());
+    router
+        .migrate_contract(
+            owner.clone(),
+            escrow_addr.clone(),
+            &MigrateMsg {
+                admin: Some(owner.to_string()),
+                fee_collector: Some(owner.to_string()),
+                fee_bps: None,
+                allowed_native: vec!["btc".to_string()],
+                allowed_cw20: vec![],
+            },
+            escrow_id,
+        )
+        .unwrap();
+
+    let version = cw2::query_contract_info(&router, escrow_addr.clone()).unwrap();
+    assert_eq!(env!("CARGO_PKG_VERSION"), version.version);
+
+    let config: ConfigResponse = router
+        .wrap()
+        .query_wasm_smart(escrow_addr.clone(), &QueryMsg::Config {})
+        .unwrap();
+    assert_eq!(owner.to_string(), config.fee_collector);
+
+    // escrows are indexed
+    let open: DetailsListResponse = router
+        .wrap()
+        .query_wasm_smart(
+            escrow_addr.clone(),
+            &QueryMsg::ListOpen { start_after: None, limit: None },
+        )
+        .unwrap();
+    assert_eq!(
+        open.escrows,
+        vec![DetailsResponse {
+            id: "ride2".to_string(),
+            user_a: ALICE.to_string(),
+            user_b: BOB.to_string(),
+            deposit: Balance::Native(NativeBalance(coins(10, "btc"))),
+            lock: ALICE_LOCK.to_string(),
+            lock_kind: LockKind::K256,
+            status: EscrowStatus::Created,
+            history: vec![],
+            expires_at: None,
+            expired: false,
+            arbiter: None,
+            cancellation_fee: None,
+            settlement: None,
+            milestones: vec![],
+            target: None,
+            funded: true,
+            failed_payouts: vec![],
+            payout_msg: None,
+            ibc_destination: None,
+            nonce: 0,
+        }]
+    );
+
+    let by_creator: DetailsListResponse = router
+        .wrap()
+        .query_wasm_smart(
+            escrow_addr.clone(),
+            &QueryMsg::ListByCreator { user_a: ALICE.to_string(), start_after: None, limit: None },
+        )
+        .unwrap();
+    assert_eq!(2, by_creator.escrows.len());
+
+    // the migrated escrow can still be withdrawn
+    let withdraw_msg = ExecuteMsg::Withdraw(WithdrawMsg {
+        id: "ride2".to_string(),
+        secret: Some(ALICE_SECRET.to_string()),
+        signature: None,
+        milestone: None,
+        payout_msg: None,
+    });
+    router
+        .execute_contract(Addr::unchecked(BOB), escrow_addr.clone(), &withdraw_msg, &[])
+        .unwrap();
+
+    assert_eq!(
+        router.wrap()
+            .query_balance(BOB, "btc")
+            .unwrap()
+            .amount
+            .u128(),
+        10,
+    );
+}
+
+#[test]
+// a relayer creates a cw20 escrow from the allowance of the rider
+fn escrow_from_cw20_allowance() {
+    const ALICE: &str = "alice";
+    const BOB: &str = "bob";
+    const BACKEND: &str = "backend";
+    const ALICE_LOCK: &str = "0330347c5cb0f1627bdd2e7b082504a443b2bf50ad2e3efbb4e754ebd687c78c24";
+    const T_DEPOSIT_AMOUNT: u128 = 1200;
+
+    let owner = Addr::unchecked("owner");
+    let mut router = App::default();
+
+    let cw20_id = router.store_code(contract_cw20());
+    let msg = cw20_base::msg::InstantiateMsg {
+        name: "Cash Money".to_string(),
+        symbol: "CASH".to_string(),
+        decimals: 2,
+        initial_balances: vec![Cw20Coin {
+            address: ALICE.to_string(),
+            amount: Uint128::new(5000),
+        }],
+        mint: None,
+        marketing: None,
+    };
+    let cash_addr = router
+        .instantiate_contract(cw20_id, owner.clone(), &msg, &[], "CASH", None)
+        .unwrap();
+
+    let escrow_id = router.store_code(contract_escrow());
+    let escrow_addr = router
+        .instantiate_contract(
+            escrow_id,
+            owner.clone(),
+            &InstantiateMsg {
+                fee_collector: owner.to_string(),
+                fee_bps: 0,
+                allowed_native: vec![],
+                allowed_cw20: vec![cash_addr.to_string()],
+            },
+            &[],
+            "Escrow",
+            None,
+        )
+        .unwrap();
+    router
+        .execute_contract(
+            owner,
+            escrow_addr.clone(),
+            &ExecuteMsg::UpdateRelayers { relayers: vec![BACKEND.to_string()] },
+            &[],
+        )
+        .unwrap();
+
+    // alice lets the escrow contract spend her tokens, through the backend
+    router
+        .execute_contract(
+            Addr::unchecked(ALICE),
+            escrow_addr.clone(),
+            &ExecuteMsg::ApproveRelayer { relayer: BACKEND.to_string() },
+            &[],
+        )
+        .unwrap();
+    router
+        .execute_contract(
+            Addr::unchecked(ALICE),
+            cash_addr.clone(),
+            &Cw20ExecuteMsg::IncreaseAllowance {
+                spender: escrow_addr.to_string(),
+                amount: Uint128::new(T_DEPOSIT_AMOUNT),
+                expires: None,
+            },
+            &[],
+        )
+        .unwrap();
+
+    let create = |id: &str| ExecuteMsg::CreateFromAllowance {
+        token: cash_addr.to_string(),
+        amount: Uint128::new(T_DEPOSIT_AMOUNT),
+        owner: ALICE.to_string(),
+        msg: CreateMsg {
+            id: Some(id.to_string()),
+            user_b: BOB.to_string(),
+            lock: String::from(ALICE_LOCK),
+            lock_kind: LockKind::K256,
+            expires_at: None,
+            arbiter: None,
+            expected_amount: None,
+            cancellation_fee: None,
+            milestones: vec![],
+            target: None,
+            payout_msg: None,
+            ibc_destination: None,
+        },
+    };
+    router
+        .execute_contract(Addr::unchecked(BACKEND), escrow_addr.clone(), &create("ride1"), &[])
+        .unwrap();
+
+    // the deposit moved from alice to the escrow, which alice created
+    let cash = Cw20Contract(cash_addr.clone());
+    let a_balance = cash.balance::<_, _, Empty>(&router, ALICE).unwrap();
+    assert_eq!(a_balance, Uint128::new(5000 - T_DEPOSIT_AMOUNT));
+    let escrow_balance = cash.balance::<_, _, Empty>(&router, escrow_addr.clone()).unwrap();
+    assert_eq!(escrow_balance, Uint128::new(T_DEPOSIT_AMOUNT));
+    let details: DetailsResponse = router
+        .wrap()
+        .query_wasm_smart(&escrow_addr, &QueryMsg::Details { id: "ride1".to_string() })
+        .unwrap();
+    assert_eq!(ALICE, details.user_a);
+    assert_eq!(
+        Balance::Cw20(Cw20CoinVerified {
+            address: cash_addr.clone(),
+            amount: Uint128::new(T_DEPOSIT_AMOUNT),
+        }),
+        details.deposit
+    );
+
+    // without allowance left, nothing is created
+    router
+        .execute_contract(Addr::unchecked(BACKEND), escrow_addr.clone(), &create("ride2"), &[])
+        .unwrap_err();
+    router
+        .wrap()
+        .query_wasm_smart::<DetailsResponse>(&escrow_addr, &QueryMsg::Details { id: "ride2".to_string() })
+        .unwrap_err();
+}
+
+#[test]
+// withdraw over IBC, with the transfers coming back to the fallback
+fn escrow_ibc_withdraw_to_fallback() {
+    const ALICE: &str = "alice";
+    const BOB: &str = "bob";
+    const FALLBACK: &str = "fallback";
+    const CHANNEL: &str = "channel-0";
+    const ALICE_LOCK: &str = "0330347c5cb0f1627bdd2e7b082504a443b2bf50ad2e3efbb4e754ebd687c78c24";
+    const ALICE_SECRET: &str = "27874aa2b70ce7281c94413c36d44fac6fa6a1198f2c529188c4dd4f7a4e1870";
+
+    let owner = Addr::unchecked("owner");
+    let mut router = App::new(|router, _, storage| {
+        router
+            .bank
+            .init_balance(storage, &Addr::unchecked(ALICE), coins(2000, "btc"))
+            .unwrap();
+    });
+
+    let transfer_id = router.store_code(contract_transfer());
+    let transfer_addr = router
+        .instantiate_contract(transfer_id, owner.clone(), &Empty {}, &[], "Transfer", None)
+        .unwrap();
+    let escrow_id = router.store_code(Box::new(IbcTransferStub {
+        escrow: contract_escrow(),
+        transfer: transfer_addr.clone(),
+    }));
+    let escrow_addr = router
+        .instantiate_contract(
+            escrow_id,
+            owner.clone(),
+            &InstantiateMsg {
+                fee_collector: owner.to_string(),
+                fee_bps: 0,
+                allowed_native: vec!["btc".to_string()],
+                allowed_cw20: vec![],
+            },
+            &[],
+            "Escrow",
+            None,
+        )
+        .unwrap();
+
+    // bob is paid on another chain
+    let create = |id: &str| ExecuteMsg::Create(CreateMsg {
+        id: Some(id.to_string()),
+        user_b: BOB.to_string(),
+        lock: String::from(ALICE_LOCK),
+        lock_kind: LockKind::K256,
+        expires_at: None,
+        arbiter: None,
+        expected_amount: None,
+        cancellation_fee: None,
+        milestones: vec![],
+        target: None,
+        payout_msg: None,
+        ibc_destination: Some(IbcDestinationMsg {
+            channel_id: CHANNEL.to_string(),
+            remote_address: "osmo1bob".to_string(),
+            timeout_seconds: 600,
+            fallback: Some(FALLBACK.to_string()),
+        }),
+    });
+    let withdraw = |id: &str| ExecuteMsg::Withdraw(WithdrawMsg {
+        id: id.to_string(),
+        secret: Some(ALICE_SECRET.to_string()),
+        signature: None,
+        milestone: None,
+        payout_msg: None,
+    });
+    let balance = |router: &App, addr: &str| router.wrap().query_balance(addr, "btc").unwrap().amount.u128();
+    for id in ["ride1", "ride2", "ride3"] {
+        router
+            .execute_contract(Addr::unchecked(ALICE), escrow_addr.clone(), &create(id), &coins(100, "btc"))
+            .unwrap();
+    }
+
+    // each withdraw is transferred, and numbered by the transfer module
+    for (sequence, id) in ["ride1", "ride2", "ride3"].iter().enumerate() {
+        let res = router
+            .execute_contract(Addr::unchecked(BOB), escrow_addr.clone(), &withdraw(id), &[])
+            .unwrap();
+        let transfer = res.events.iter().find(|e| e.attributes.iter().any(|a| a.value == "ibc_transfer")).unwrap();
+        assert!(transfer.attributes.iter().any(|a| a.key == "sequence" && a.value == (sequence + 1).to_string()));
+    }
+    assert_eq!(300, balance(&router, transfer_addr.as_str()));
+    assert_eq!(0, balance(&router, escrow_addr.as_str()));
+
+    // an error acknowledgement refunds the coins, paid to the fallback
+    router.send_tokens(transfer_addr.clone(), escrow_addr.clone(), &coins(100, "btc")).unwrap();
+    let failed = SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcAck {
+        channel: CHANNEL.to_string(),
+        sequence: 1,
+        ack: "error".to_string(),
+        success: false,
+    });
+    router.wasm_sudo(escrow_addr.clone(), &failed).unwrap();
+    assert_eq!(100, balance(&router, FALLBACK));
+    // and only once
+    router.wasm_sudo(escrow_addr.clone(), &failed).unwrap_err();
+
+    // so does a timeout
+    router.send_tokens(transfer_addr.clone(), escrow_addr.clone(), &coins(100, "btc")).unwrap();
+    let timeout = SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcTimeout {
+        channel: CHANNEL.to_string(),
+        sequence: 2,
+    });
+    router.wasm_sudo(escrow_addr.clone(), &timeout).unwrap();
+    assert_eq!(200, balance(&router, FALLBACK));
+
+    // while a successful acknowledgement leaves the coins with bob
+    let acknowledged = SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcAck {
+        channel: CHANNEL.to_string(),
+        sequence: 3,
+        ack: "AQ==".to_string(),
+        success: true,
+    });
+    router.wasm_sudo(escrow_addr.clone(), &acknowledged).unwrap();
+    assert_eq!(200, balance(&router, FALLBACK));
+    assert_eq!(0, balance(&router, escrow_addr.as_str()));
+}
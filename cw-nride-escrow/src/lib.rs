@@ -4,6 +4,10 @@ pub mod contract;
 pub mod error;
 pub mod msg;
 
+mod balance;
+mod cache;
 mod escrow;
+mod ibc;
+mod migrations;
 mod state;
 
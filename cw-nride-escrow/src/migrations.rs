@@ -0,0 +1,247 @@
+use semver::Version;
+
+use cosmwasm_std::{DepsMut, Env, Order, StdResult, Storage, Uint128};
+
+use crate::balance::with_amount;
+use crate::error::ContractError;
+use crate::msg::MigrateMsg;
+use crate::state::{escrows, Stats, STATS};
+
+/// migrate_state upgrades the stored state, written by the contract version
+/// `from`, to the shape expected by the current version. Each step rewrites
+/// the state of one released version into the next, so they run in order.
+pub fn migrate_state(
+    mut deps: DepsMut,
+    env: &Env,
+    from: &Version,
+    msg: &MigrateMsg,
+) -> Result<(), ContractError> {
+    if *from < Version::new(0, 3, 0) {
+        v0_2::migrate(deps.branch(), env, from, msg)?;
+        backfill_stats(deps.storage)?;
+    }
+    Ok(())
+}
+
+/// backfill_stats computes the running totals, kept since v0.3.0, from the
+/// stored escrows
+fn backfill_stats(storage: &mut dyn Storage) -> StdResult<()> {
+    let mut stats = Stats::default();
+    for item in escrows().range(storage, None, None, Order::Ascending) {
+        let (_, escrow) = item?;
+        stats.add_escrow(&escrow.deposit);
+        if escrow.is_closed() {
+            stats.release(&escrow.deposit);
+            stats.close(escrow.status);
+        } else {
+            let released: Uint128 = escrow.milestones
+                .iter()
+                .filter(|m| m.released)
+                .map(|m| m.amount)
+                .sum();
+            if !released.is_zero() {
+                stats.release(&with_amount(&escrow.deposit, released));
+            }
+        }
+    }
+    STATS.save(storage, &stats)
+}
+
+/// v0.2.0 stored escrows in a plain map, without config or indexes
+pub mod v0_2 {
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    use cosmwasm_std::{Addr, DepsMut, Env, Order, StdResult};
+    use cw20::Balance;
+    use cw_storage_plus::Map;
+    use semver::Version;
+
+    use crate::contract::validate_addrs;
+    use crate::error::ContractError;
+    use crate::escrow::{Escrow, EscrowStatus, LockKind, Paid, StatusChange};
+    use crate::msg::MigrateMsg;
+    use crate::state::{escrows, Config, CONFIG, MAX_FEE_BPS};
+
+    /// Escrow as stored by v0.2.0
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+    pub struct LegacyEscrow {
+        pub user_a: Addr,
+        pub user_b: Addr,
+        pub deposit: Balance,
+        pub lock: String,
+        pub closed: bool,
+    }
+
+    impl From<LegacyEscrow> for Escrow {
+        fn from(legacy: LegacyEscrow) -> Self {
+            Escrow {
+                user_a: legacy.user_a,
+                user_b: legacy.user_b,
+                deposit: legacy.deposit,
+                lock: legacy.lock,
+                lock_kind: LockKind::K256,
+                // v0.2.0 did not record whether the escrow was withdrawn
+                // or cancelled, nor when
+                status: if legacy.closed {
+                    EscrowStatus::Completed
+                } else {
+                    EscrowStatus::Created
+                },
+                history: vec![],
+                expires_at: None,
+                cancel_approvals: vec![],
+                arbiter: None,
+                cancellation_fee: None,
+                settlement: None,
+                milestones: vec![],
+                target: None,
+                failed_payouts: vec![],
+                payout_msg: None,
+                ibc_destination: None,
+                nonce: 0,
+                paid: Paid::default(),
+            }
+        }
+    }
+
+    pub const LEGACY_ESCROWS: Map<&str, LegacyEscrow> = Map::new("escrow");
+
+    pub fn migrate(
+        deps: DepsMut,
+        env: &Env,
+        from: &Version,
+        msg: &MigrateMsg,
+    ) -> Result<(), ContractError> {
+        if CONFIG.may_load(deps.storage)?.is_none() {
+            let (admin, fee_collector) = match (&msg.admin, &msg.fee_collector) {
+                (Some(admin), Some(fee_collector)) => (admin, fee_collector),
+                _ => return Err(ContractError::MissingConfig { version: from.to_string() }),
+            };
+            let fee_bps = msg.fee_bps.unwrap_or_default();
+            if fee_bps > MAX_FEE_BPS {
+                return Err(ContractError::InvalidFee {});
+            }
+            let config = Config {
+                admin: deps.api.addr_validate(admin)?,
+                fee_collector: deps.api.addr_validate(fee_collector)?,
+                fee_bps,
+                allowed_native: msg.allowed_native.clone(),
+                allowed_cw20: validate_addrs(deps.api, &msg.allowed_cw20)?,
+                pending_admin: None,
+                paused: false,
+                relayers: vec![],
+            };
+            CONFIG.save(deps.storage, &config)?;
+        }
+
+        // rewrite every escrow in the new shape, which also builds the
+        // indexes. There is no old value to unindex.
+        let legacy: Vec<(String, LegacyEscrow)> = LEGACY_ESCROWS
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<_>>()?;
+        for (id, escrow) in legacy {
+            let mut escrow: Escrow = escrow.into();
+            // closed escrows are taken as closed at the migration, so that
+            // they can be pruned by time like the others
+            if escrow.is_closed() {
+                escrow.history.push(StatusChange { status: escrow.status, time: env.block.time });
+            }
+            escrows().replace(deps.storage, &id, Some(&escrow), None)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::v0_2::{LegacyEscrow, LEGACY_ESCROWS};
+
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+    use cosmwasm_std::{coins, Addr};
+    use cw20::Balance;
+
+    use crate::contract::execute;
+    use crate::escrow::{EscrowStatus, LockKind};
+    use crate::msg::ExecuteMsg;
+    use crate::state::{escrows_by_creator, open_escrows, CONFIG};
+
+    fn legacy_escrow(closed: bool) -> LegacyEscrow {
+        LegacyEscrow {
+            user_a: Addr::unchecked("user_a"),
+            user_b: Addr::unchecked("user_b"),
+            deposit: Balance::from(coins(100, "tokens")),
+            lock: "LOCK".to_string(),
+            closed,
+        }
+    }
+
+    #[test]
+    fn migrate_from_v0_2() {
+        let mut deps = mock_dependencies();
+        LEGACY_ESCROWS.save(&mut deps.storage, "open", &legacy_escrow(false)).unwrap();
+        LEGACY_ESCROWS.save(&mut deps.storage, "done", &legacy_escrow(true)).unwrap();
+
+        // v0.2.0 had no config, so an admin and a fee collector are required
+        let mut msg = MigrateMsg {
+            admin: None,
+            fee_collector: Some("collector".to_string()),
+            fee_bps: None,
+            allowed_native: vec!["tokens".to_string()],
+            allowed_cw20: vec![],
+        };
+        let err = migrate_state(deps.as_mut(), &mock_env(), &Version::new(0, 2, 0), &msg).unwrap_err();
+        assert_eq!(ContractError::MissingConfig { version: "0.2.0".to_string() }, err);
+
+        msg.admin = Some("admin".to_string());
+        migrate_state(deps.as_mut(), &mock_env(), &Version::new(0, 2, 0), &msg).unwrap();
+
+        let config = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(Addr::unchecked("admin"), config.admin);
+        assert_eq!(Addr::unchecked("collector"), config.fee_collector);
+        assert_eq!(0, config.fee_bps);
+        assert_eq!(vec!["tokens".to_string()], config.allowed_native);
+
+        let escrow = escrows().load(&deps.storage, "open").unwrap();
+        assert_eq!(LockKind::K256, escrow.lock_kind);
+        assert_eq!(None, escrow.expires_at);
+        assert_eq!(EscrowStatus::Created, escrow.status);
+
+        let user_a = Addr::unchecked("user_a");
+        let ids: Vec<String> = escrows_by_creator(&deps.storage, &user_a, None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(vec!["done".to_string(), "open".to_string()], ids);
+
+        let open: Vec<String> = open_escrows(&deps.storage, None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(vec!["open".to_string()], open);
+
+        // the statistics are computed from the escrows
+        let stats = STATS.load(&deps.storage).unwrap();
+        assert_eq!((1, 1, 0), (stats.open, stats.withdrawn, stats.cancelled));
+        assert_eq!(coins(100, "tokens"), stats.locked.native);
+        assert_eq!(coins(100, "tokens"), stats.released.native);
+
+        // closed escrows are closed at the migration, and pruned by time
+        let done = escrows().load(&deps.storage, "done").unwrap();
+        assert_eq!(Some(mock_env().block.time), done.closed_at());
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(60);
+        let prune = ExecuteMsg::Prune {
+            ids: vec![],
+            before: Some(env.block.time),
+            limit: None,
+            reuse_ids: false,
+        };
+        execute(deps.as_mut(), env, mock_info("admin", &[]), prune).unwrap();
+        assert!(!escrows().has(&deps.storage, "done"));
+        assert!(escrows().has(&deps.storage, "open"));
+    }
+}
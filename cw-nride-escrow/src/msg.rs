@@ -1,18 +1,168 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cw20::{ Cw20ReceiveMsg, Balance};
+use cosmwasm_std::{Binary, Coin, CosmosMsg, Timestamp, Uint128};
+use cw20::{ Cw20Coin, Cw20ReceiveMsg, Balance, Denom};
+use cw_utils::Expiration;
+
+pub use crate::escrow::{
+    CancellationFee, EscrowStatus, IbcDestination, LockKind, Milestone, Paid, Settlement,
+    StatusChange,
+};
+pub use crate::state::PayoutMode;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
-pub struct InstantiateMsg {}
+pub struct InstantiateMsg {
+    /// address receiving the platform fee on every withdraw
+    pub fee_collector: String,
+    /// platform fee in basis points (1/100th of a percent), at most 10000
+    pub fee_bps: u16,
+    /// native denoms accepted as deposit
+    pub allowed_native: Vec<String>,
+    /// cw20 contracts accepted as deposit
+    pub allowed_cw20: Vec<String>,
+}
+
+/// v0.2.0 had no config, so migrating from it requires an admin and a
+/// fee_collector. The fields are ignored when migrating from later versions.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {
+    /// address allowed to update the config
+    pub admin: Option<String>,
+    /// address receiving the platform fee
+    pub fee_collector: Option<String>,
+    /// platform fee in basis points, defaults to 0
+    pub fee_bps: Option<u16>,
+    /// native denoms accepted as deposit
+    #[serde(default)]
+    pub allowed_native: Vec<String>,
+    /// cw20 contracts accepted as deposit
+    #[serde(default)]
+    pub allowed_cw20: Vec<String>,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
+#[allow(clippy::large_enum_variant)]
 pub enum ExecuteMsg {
     /// Create, Withdraw and Cancel can be sent directly to the escrow contract
     Create(CreateMsg),
+    /// Accept and Start are sent by the recipient (the driver) when they
+    /// acknowledge the ride and when it begins
+    Accept{id:String},
+    Start{id:String},
     Withdraw(WithdrawMsg),
     Cancel{id:String},
+    /// Refund sends the whole deposit back to the creator. Only the
+    /// recipient can send it, e.g. when the ride cannot be completed.
+    Refund{id:String},
+    /// ApproveSettlement proposes or approves a split of the deposit between
+    /// the two users. The escrow is cancelled and paid out once both users
+    /// approved the same split.
+    ApproveSettlement{
+        id: String,
+        to_user_a: Balance,
+        to_user_b: Balance,
+    },
+    /// TopUp adds the attached funds to the deposit of an open escrow.
+    /// Only the creator can send it.
+    TopUp{id:String},
+    /// Contribute adds the attached funds to a shared fare, up to its
+    /// target. Any rider can send it.
+    Contribute{id:String},
+    /// Dispute freezes the escrow until its arbiter resolves it.
+    /// It can be sent by either user.
+    Dispute{id:String},
+    /// Resolve settles a disputed escrow by splitting the deposit between
+    /// the two users. Only the arbiter can send it.
+    Resolve{
+        id: String,
+        to_user_a: Balance,
+        to_user_b: Balance,
+    },
+    /// UpdateAllowlist replaces the tokens accepted as deposit.
+    /// Only the admin can send it.
+    UpdateAllowlist{
+        allowed_native: Vec<String>,
+        allowed_cw20: Vec<String>,
+    },
+    /// TransferAdmin proposes a new admin, who takes over once it sends
+    /// AcceptAdmin. Only the admin can send it.
+    TransferAdmin{admin: String},
+    /// AcceptAdmin completes the transfer of the admin role. Only the
+    /// proposed admin can send it.
+    AcceptAdmin{},
+    /// Pause blocks new deposits (Create, BatchCreate, TopUp, Contribute and
+    /// Receive) until Unpause. Existing escrows can still be withdrawn,
+    /// cancelled and refunded. Only the admin can send them.
+    Pause{},
+    Unpause{},
+    /// BatchCreate creates several escrows funded from a single native coin,
+    /// split between them by amount
+    BatchCreate{
+        items: Vec<BatchCreateItem>,
+        /// apply the items that succeed instead of failing the whole batch.
+        /// The deposit of the failed items is refunded.
+        #[serde(default)]
+        skip_failures: bool,
+    },
+    /// BatchWithdraw withdraws several escrows
+    BatchWithdraw{
+        items: Vec<WithdrawMsg>,
+        #[serde(default)]
+        skip_failures: bool,
+    },
+    /// BatchCancel cancels, or approves the cancellation of, several escrows
+    BatchCancel{
+        ids: Vec<String>,
+        #[serde(default)]
+        skip_failures: bool,
+    },
+    /// RetryPayout sends again the payouts of an escrow that were rejected,
+    /// e.g. by a paused cw20 token. Anyone can send it.
+    RetryPayout{id:String},
+    /// RecoverPayouts credits the rejected payouts of an escrow to the
+    /// claimable balance of their recipient, who then gets them with Claim
+    /// as plain transfers. Only that recipient can send it, e.g. when its
+    /// payout_msg is always rejected.
+    RecoverPayouts{id:String},
+    /// SetPayoutMode chooses how the funds released to the sender are paid:
+    /// transferred on every withdraw (the default), or credited to a ledger
+    /// and claimed later
+    SetPayoutMode{mode: PayoutMode},
+    /// Claim pays out the credited funds of the sender in the given tokens,
+    /// or in every token if the list is empty
+    Claim{tokens: Vec<Denom>},
+    /// Prune deletes settled escrows, either the listed ids or at most
+    /// limit escrows closed before the given time, and keeps a small
+    /// archive record of each. Only the admin can send it.
+    Prune{
+        #[serde(default)]
+        ids: Vec<String>,
+        before: Option<Timestamp>,
+        limit: Option<u32>,
+        /// let new escrows be created with the pruned ids. By default the
+        /// ids stay taken.
+        #[serde(default)]
+        reuse_ids: bool,
+    },
+    /// UpdateRelayers replaces the relayers allowed to send
+    /// CreateFromAllowance. Only the admin can send it.
+    UpdateRelayers{relayers: Vec<String>},
+    /// ApproveRelayer lets a relayer create escrows from the allowance of
+    /// the sender, and RevokeRelayer withdraws that approval
+    ApproveRelayer{relayer: String},
+    RevokeRelayer{relayer: String},
+    /// CreateFromAllowance creates a cw20 escrow on behalf of owner, who
+    /// becomes user_a. The deposit is pulled with TransferFrom from the
+    /// allowance owner granted to the contract. Only a relayer approved by
+    /// owner can send it.
+    CreateFromAllowance{
+        token: String,
+        amount: Uint128,
+        owner: String,
+        msg: CreateMsg,
+    },
     /// This accepts a properly-encoded ReceiveMsg from a cw20 contract
     /// to create escrows
     Receive(Cw20ReceiveMsg),
@@ -20,33 +170,235 @@ pub enum ExecuteMsg {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
+#[allow(clippy::large_enum_variant)]
 pub enum ReceiveMsg {
     Create(CreateMsg),
+    TopUp{id:String},
+    Contribute{id:String},
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct CreateMsg {
     /// id is a human-readable name for the escrow to use later
-    /// 3-20 bytes of utf-8 text
-    pub id: String,
+    /// 3-20 bytes of utf-8 text, without control characters or leading and
+    /// trailing whitespace. Purely numeric ids are reserved: when id is not
+    /// set, the contract assigns the next number from a counter and returns
+    /// it in the response.
+    pub id: Option<String>,
     pub user_b: String,
     pub lock: String,
+    /// how the lock is opened, a k256 public key by default
+    #[serde(default)]
+    pub lock_kind: LockKind,
+    /// optional deadline after which anyone can refund the deposit to the
+    /// creator. Before that, cancelling requires the approval of both users.
+    pub expires_at: Option<Expiration>,
+    /// optional third party who can resolve disputes between the users
+    pub arbiter: Option<String>,
+    /// optional amount the deposit must match, as a single coin
+    pub expected_amount: Option<Uint128>,
+    /// optional fee paid to user_b out of the deposit when the escrow is
+    /// cancelled after user_b accepted it
+    pub cancellation_fee: Option<CancellationFee>,
+    /// optional tranches of the deposit, released in order each with its
+    /// own lock. The deposit must be a single token and the amounts must
+    /// add up to it. The lock of the escrow then no longer releases funds.
+    #[serde(default)]
+    pub milestones: Vec<MilestoneMsg>,
+    /// optional amount for a shared fare funded by several riders with
+    /// Contribute. The deposit is the first contribution, and the escrow
+    /// can only be withdrawn once it reaches the target.
+    pub target: Option<Uint128>,
+    /// optional message notifying user_b, when it is a contract such as a
+    /// fleet vault, of every payout it receives: cw20 tokens are paid with
+    /// Send and this message, native coins with an Execute of this message
+    /// carrying the coins
+    pub payout_msg: Option<Binary>,
+    /// optional destination on another chain, where the native coins
+    /// withdrawn are sent to user_b over IBC
+    pub ibc_destination: Option<IbcDestinationMsg>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct IbcDestinationMsg {
+    /// channel from this chain to the chain of user_b
+    pub channel_id: String,
+    /// address of user_b on the other chain
+    pub remote_address: String,
+    /// seconds the transfer has to complete before it times out, at most
+    /// a week
+    pub timeout_seconds: u64,
+    /// address paid on this chain if the transfer fails or times out,
+    /// user_b by default
+    pub fallback: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MilestoneMsg {
+    /// amount of the deposit token released by this milestone
+    pub amount: Uint128,
+    pub lock: String,
+    /// how the lock is opened, a k256 public key by default
+    #[serde(default)]
+    pub lock_kind: LockKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BatchCreateItem {
+    /// part of the batch deposit funding this escrow
+    pub amount: Uint128,
+    pub escrow: CreateMsg,
+}
+
+/// BatchResponse is returned in the data of a batch, with one result per
+/// item in order
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BatchResponse {
+    pub results: Vec<BatchResult>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BatchResult {
+    /// id of the escrow, if known
+    pub id: Option<String>,
+    /// why the item failed, when failures are skipped
+    pub error: Option<String>,
+}
+
+/// TopUpResponse is returned in the data of a top up
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TopUpResponse {
+    /// deposit after the top up
+    pub deposit: Balance,
+}
+
+/// CreateResponse is returned in the data of a create
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CreateResponse {
+    /// id of the created escrow, chosen by the creator or assigned
+    pub id: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct WithdrawMsg {
     pub id: String,
-    pub secret: String,
+    /// hex-encoded private key corresponding to the lock
+    pub secret: Option<String>,
+    /// hex-encoded signature (64 bytes, r || s) of the WithdrawPayload by
+    /// the private key corresponding to the lock. This unlocks the escrow
+    /// without revealing the private key.
+    pub signature: Option<String>,
+    /// index of the milestone to release, required for escrows with
+    /// milestones. Only the first milestone not released yet is accepted.
+    pub milestone: Option<u32>,
+    /// optional message notifying user_b of this payout, in place of the
+    /// payout_msg of the escrow. With a secret, it is only taken when sent
+    /// by user_b.
+    pub payout_msg: Option<Binary>,
+}
+
+/// WithdrawPayload is the message signed by the lock key to withdraw an
+/// escrow with a signature. It is serialized to JSON, in this field order,
+/// and hashed with SHA-256 before signing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawPayload {
+    /// address of the escrow contract
+    pub contract: String,
+    /// id of the escrow
+    pub id: String,
+    /// nonce of the escrow, from the details query, which tells apart
+    /// escrows created with the same id
+    pub nonce: u64,
+    /// address receiving the deposit (user_b)
+    pub recipient: String,
+    /// index of the milestone released, omitted for plain escrows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub milestone: Option<u32>,
+    /// payout_msg of the withdraw, omitted when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payout_msg: Option<Binary>,
+}
+
+/// SudoMsg is sent by the chain. The ibc-hooks middleware reports with
+/// IbcLifecycleComplete how the IBC transfers sent by the contract ended.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SudoMsg {
+    IbcLifecycleComplete(IbcLifecycleComplete),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IbcLifecycleComplete {
+    /// the transfer was acknowledged, successfully or with an error
+    IbcAck {
+        channel: String,
+        sequence: u64,
+        ack: String,
+        success: bool,
+    },
+    /// the transfer timed out and the coins came back
+    IbcTimeout {
+        channel: String,
+        sequence: u64,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
-    /// Show all open escrows. Return type is ListResponse.
-    List {},
+    /// Show the ids of all escrows, paginated. Return type is ListResponse.
+    List {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Show the escrows created by user_a, paginated.
+    /// Return type is DetailsListResponse.
+    ListByCreator {
+        user_a: String,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Show the escrows paying out to user_b, paginated.
+    /// Return type is DetailsListResponse.
+    ListByRecipient {
+        user_b: String,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Show the escrows that are not closed yet, paginated.
+    /// Return type is DetailsListResponse.
+    ListOpen {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
     /// Returns the details of the named escrow, error if not created
     /// Return type: DetailsResponse.
     Details { id: String },
+    /// Returns the contract configuration
+    /// Return type: ConfigResponse.
+    Config {},
+    /// Returns the payout mode of an address and the funds it can claim
+    /// Return type: BalanceResponse.
+    Balance { address: String },
+    /// Show the contributions to a shared fare, by address, paginated.
+    /// Return type: ContributionsResponse.
+    Contributions {
+        id: String,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Show the archive records of pruned escrows, of every escrow or of a
+    /// single id, in the order they were pruned, paginated.
+    /// Return type: HistoryResponse.
+    History {
+        id: Option<String>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Returns running totals over every escrow
+    /// Return type: StatsResponse.
+    Stats {},
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -55,6 +407,11 @@ pub struct ListResponse {
     pub escrows: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct DetailsListResponse {
+    pub escrows: Vec<DetailsResponse>,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
 pub struct DetailsResponse {
     /// id of this escrow
@@ -63,5 +420,107 @@ pub struct DetailsResponse {
     pub user_b: String,
     pub deposit: Balance,
     pub lock: String,
-    pub closed: bool,
-}
\ No newline at end of file
+    pub lock_kind: LockKind,
+    pub status: EscrowStatus,
+    /// every status reached, with the time of the transition
+    pub history: Vec<StatusChange>,
+    pub expires_at: Option<Expiration>,
+    /// true if expires_at has been reached
+    pub expired: bool,
+    pub arbiter: Option<String>,
+    pub cancellation_fee: Option<CancellationFee>,
+    /// split of the deposit waiting for the approval of both users
+    pub settlement: Option<Settlement>,
+    /// tranches of the deposit and whether they were released
+    pub milestones: Vec<Milestone>,
+    /// amount a shared fare must reach before it can be withdrawn
+    pub target: Option<Uint128>,
+    /// false while a shared fare has not reached its target
+    pub funded: bool,
+    /// payouts rejected by their recipient or token, waiting for a retry
+    pub failed_payouts: Vec<CosmosMsg>,
+    /// message sent along with the payouts to user_b
+    pub payout_msg: Option<Binary>,
+    /// where the withdraw is sent over IBC
+    pub ibc_destination: Option<IbcDestination>,
+    /// nonce to sign in the WithdrawPayload
+    pub nonce: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct BalanceResponse {
+    pub payout_mode: PayoutMode,
+    /// native coins that can be claimed
+    pub native: Vec<Coin>,
+    /// cw20 tokens that can be claimed
+    pub cw20: Vec<Cw20Coin>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ContributionsResponse {
+    pub contributions: Vec<Contribution>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct Contribution {
+    pub address: String,
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct StatsResponse {
+    /// escrows not closed yet
+    pub open: u64,
+    /// escrows completed, by withdraw or by the arbiter
+    pub withdrawn: u64,
+    /// escrows cancelled, refunded or settled
+    pub cancelled: u64,
+    /// funds held by open escrows, by token
+    pub locked: Totals,
+    /// funds paid out of escrows so far, by token
+    pub released: Totals,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct Totals {
+    pub native: Vec<Coin>,
+    pub cw20: Vec<Cw20Coin>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct HistoryResponse {
+    pub escrows: Vec<ArchiveRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ArchiveRecord {
+    /// number of the record, to paginate with start_after
+    pub index: u64,
+    pub id: String,
+    pub user_a: String,
+    pub user_b: String,
+    /// final deposit, after top ups and contributions
+    pub deposit: Balance,
+    /// completed or cancelled
+    pub outcome: EscrowStatus,
+    /// final amounts paid to each party
+    pub paid: Paid,
+    pub closed_at: Timestamp,
+    /// height of the block the escrow was pruned in
+    pub pruned_at: u64,
+    /// true while no new escrow can be created with this id
+    pub reserved: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ConfigResponse {
+    pub admin: String,
+    pub fee_collector: String,
+    pub fee_bps: u16,
+    pub allowed_native: Vec<String>,
+    pub allowed_cw20: Vec<String>,
+    /// admin proposed by TransferAdmin, not accepted yet
+    pub pending_admin: Option<String>,
+    pub paused: bool,
+    pub relayers: Vec<String>,
+}
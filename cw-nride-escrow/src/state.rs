@@ -1,14 +1,584 @@
-use cosmwasm_std::{ Order, StdResult, Storage};
-use cw_storage_plus::Map;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-use crate::escrow::Escrow;
+use std::fmt;
 
-pub const ESCROWS: Map<&str, Escrow> = Map::new("escrow");
+use cosmwasm_std::{Addr, Coin, CosmosMsg, Empty, Order, StdResult, Storage, Timestamp, Uint128};
+use cw20::{Balance, Cw20CoinVerified, Denom};
+use cw_storage_plus::{Bound, Index, IndexList, IndexedMap, Item, Map, MultiIndex};
+use cw_utils::NativeBalance;
 
-/// This returns the list of ids for all registered escrows
-pub fn all_escrow_ids(storage: &dyn Storage) -> StdResult<Vec<String>> {
-    ESCROWS
-        .keys(storage, None, None, Order::Ascending)
+use crate::error::ContractError;
+use crate::escrow::{Escrow, EscrowStatus, Paid};
+use crate::msg::CreateMsg;
+
+/// the maximum fee, in basis points, that can be configured (100%)
+pub const MAX_FEE_BPS: u16 = 10_000;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct Config {
+    /// admin can update the config
+    pub admin: Addr,
+    /// fee_collector receives the platform fee on every withdraw
+    pub fee_collector: Addr,
+    /// fee_bps is the platform fee in basis points (1/100th of a percent)
+    pub fee_bps: u16,
+    /// allowed_native lists the native denoms accepted as deposit
+    pub allowed_native: Vec<String>,
+    /// allowed_cw20 lists the cw20 contracts accepted as deposit
+    pub allowed_cw20: Vec<Addr>,
+    /// pending_admin was proposed by the admin and becomes admin once it
+    /// accepts
+    pub pending_admin: Option<Addr>,
+    /// paused blocks new deposits. Existing escrows can still be withdrawn,
+    /// cancelled and refunded.
+    pub paused: bool,
+    /// relayers can create cw20 escrows from the allowances granted to them
+    /// by the creators
+    pub relayers: Vec<Addr>,
+}
+
+impl Config {
+    /// check_allowed fails unless every token of the deposit is allowed
+    pub fn check_allowed(&self, deposit: &Balance) -> Result<(), ContractError> {
+        match deposit {
+            Balance::Native(balance) => {
+                if let Some(coin) = balance.0.iter().find(|c| !self.allowed_native.contains(&c.denom)) {
+                    return Err(ContractError::TokenNotAllowed { token: coin.denom.clone() });
+                }
+            }
+            Balance::Cw20(token) => {
+                if !self.allowed_cw20.contains(&token.address) {
+                    return Err(ContractError::TokenNotAllowed { token: token.address.to_string() });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub const CONFIG: Item<Config> = Item::new("config");
+
+/// number of the last escrow id assigned by the contract
+pub const ESCROW_COUNT: Item<u64> = Item::new("escrow_count");
+
+/// number of escrows created so far, the nonce of the last one
+pub const NONCE_COUNT: Item<u64> = Item::new("nonce_count");
+
+/// amount contributed to a shared fare, by escrow id and contributor
+pub const CONTRIBUTIONS: Map<(&str, &Addr), Uint128> = Map::new("contributions");
+
+/// PayoutMode tells how the funds released to an address are paid
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PayoutMode {
+    /// every payout is transferred right away
+    #[default]
+    Transfer,
+    /// payouts are credited to the claimable balance of the address, which
+    /// sweeps them with a single Claim
+    Ledger,
+}
+
+impl fmt::Display for PayoutMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self {
+            PayoutMode::Transfer => "transfer",
+            PayoutMode::Ledger => "ledger",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+/// payout mode chosen by an address, transfer if not set
+pub const PAYOUT_MODES: Map<&Addr, PayoutMode> = Map::new("payout_mode");
+
+/// Funds tallies amounts of several tokens, such as the funds credited to
+/// an address
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug, Default)]
+pub struct Funds {
+    pub native: Vec<Coin>,
+    pub cw20: Vec<Cw20CoinVerified>,
+}
+
+impl Funds {
+    /// add credits the funds of balance
+    pub fn add(&mut self, balance: &Balance) {
+        match balance {
+            Balance::Native(native) => {
+                let mut sum = NativeBalance(std::mem::take(&mut self.native)) + native.clone();
+                sum.normalize();
+                self.native = sum.into_vec();
+            }
+            Balance::Cw20(token) => {
+                match self.cw20.iter_mut().find(|t| t.address == token.address) {
+                    Some(credited) => credited.amount += token.amount,
+                    None => self.cw20.push(token.clone()),
+                }
+            }
+        }
+    }
+
+    /// take removes the funds of the given tokens, or of every token when
+    /// none is given, and returns them as balances to pay out
+    pub fn take(&mut self, tokens: &[Denom]) -> Vec<Balance> {
+        let wanted = |denom: Denom| tokens.is_empty() || tokens.contains(&denom);
+
+        let (native, kept): (Vec<Coin>, Vec<Coin>) = std::mem::take(&mut self.native)
+            .into_iter()
+            .partition(|coin| wanted(Denom::Native(coin.denom.clone())));
+        self.native = kept;
+        let (cw20, kept): (Vec<Cw20CoinVerified>, Vec<Cw20CoinVerified>) = std::mem::take(&mut self.cw20)
+            .into_iter()
+            .partition(|token| wanted(Denom::Cw20(token.address.clone())));
+        self.cw20 = kept;
+
+        let mut balances = vec![];
+        if !native.is_empty() {
+            balances.push(Balance::from(native));
+        }
+        balances.extend(cw20.into_iter().map(Balance::Cw20));
+        balances
+    }
+
+    /// sub debits the funds of balance, down to zero
+    pub fn sub(&mut self, balance: &Balance) {
+        match balance {
+            Balance::Native(native) => {
+                for coin in &native.0 {
+                    if let Some(held) = self.native.iter_mut().find(|c| c.denom == coin.denom) {
+                        held.amount = held.amount.saturating_sub(coin.amount);
+                    }
+                }
+                self.native.retain(|c| !c.amount.is_zero());
+            }
+            Balance::Cw20(token) => {
+                if let Some(held) = self.cw20.iter_mut().find(|t| t.address == token.address) {
+                    held.amount = held.amount.saturating_sub(token.amount);
+                }
+                self.cw20.retain(|t| !t.amount.is_zero());
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.native.is_empty() && self.cw20.is_empty()
+    }
+}
+
+/// funds credited to an address in the ledger payout mode
+pub const CLAIMABLE: Map<&Addr, Funds> = Map::new("claimable");
+
+/// Stats are running totals over every escrow, kept up to date by the
+/// handlers so that they can be read without scanning the escrows
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug, Default)]
+pub struct Stats {
+    /// escrows not closed yet
+    pub open: u64,
+    /// escrows completed, by withdraw or by the arbiter
+    pub withdrawn: u64,
+    /// escrows cancelled, refunded or settled
+    pub cancelled: u64,
+    /// funds held by open escrows
+    pub locked: Funds,
+    /// funds paid out of escrows, to either user and the fee collector.
+    /// A payout that fails and waits for a retry still counts as released.
+    pub released: Funds,
+}
+
+impl Stats {
+    /// add_escrow counts a new escrow and its deposit
+    pub fn add_escrow(&mut self, deposit: &Balance) {
+        self.open += 1;
+        self.locked.add(deposit);
+    }
+
+    /// lock adds funds to an open escrow
+    pub fn lock(&mut self, funds: &Balance) {
+        self.locked.add(funds);
+    }
+
+    /// release moves funds paid out of an escrow from locked to released
+    pub fn release(&mut self, funds: &Balance) {
+        self.locked.sub(funds);
+        self.released.add(funds);
+    }
+
+    /// close counts an escrow closing with the given status
+    pub fn close(&mut self, status: EscrowStatus) {
+        self.open = self.open.saturating_sub(1);
+        match status {
+            EscrowStatus::Completed => self.withdrawn += 1,
+            EscrowStatus::Cancelled => self.cancelled += 1,
+            _ => {}
+        }
+    }
+}
+
+pub const STATS: Item<Stats> = Item::new("stats");
+
+/// update_stats applies change to the running totals
+pub fn update_stats(storage: &mut dyn Storage, change: impl FnOnce(&mut Stats)) -> StdResult<()> {
+    let mut stats = STATS.may_load(storage)?.unwrap_or_default();
+    change(&mut stats);
+    STATS.save(storage, &stats)
+}
+
+/// Payout is a transfer of escrowed funds sent with reply_on_error, so that
+/// a rejected transfer can be kept for a retry instead of aborting
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct Payout {
+    /// id of the escrow paying out
+    pub escrow: String,
+    pub msg: CosmosMsg,
+    /// height of the block the transfer was sent in
+    pub height: u64,
+}
+
+/// reply id of the last payout sent
+pub const PAYOUT_COUNT: Item<u64> = Item::new("payout_count");
+
+/// payouts by reply id. Replies only come back within the transaction that
+/// sent the transfer, so payouts from earlier blocks are no longer needed.
+pub const PAYOUTS: Map<u64, Payout> = Map::new("payouts");
+
+/// remove_stale_payouts drops the payouts sent before the given height,
+/// whose replies can no longer come back
+pub fn remove_stale_payouts(storage: &mut dyn Storage, height: u64) -> StdResult<()> {
+    // reply ids grow with the height, so the stale payouts come first
+    let mut stale = vec![];
+    for item in PAYOUTS.range(storage, None, None, Order::Ascending) {
+        let (id, payout) = item?;
+        if payout.height >= height {
+            break;
+        }
+        stale.push(id);
+    }
+    for id in stale {
+        PAYOUTS.remove(storage, id);
+    }
+    Ok(())
+}
+
+/// IbcTransfer is a withdraw sent over IBC, waiting for its outcome
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct IbcTransfer {
+    /// id of the escrow paying out
+    pub escrow: String,
+    /// address paid on this chain if the transfer fails or times out
+    pub fallback: Addr,
+    pub amount: Coin,
+}
+
+pub struct IbcTransferIndexes<'a> {
+    pub escrow: MultiIndex<'a, String, IbcTransfer, (String, u64)>,
+}
+
+impl<'a> IndexList<IbcTransfer> for IbcTransferIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<IbcTransfer>> + '_> {
+        let v: Vec<&dyn Index<IbcTransfer>> = vec![&self.escrow];
+        Box::new(v.into_iter())
+    }
+}
+
+/// IBC transfers in flight are stored by source channel and packet sequence,
+/// and indexed by escrow id
+pub fn ibc_transfers<'a>() -> IndexedMap<'a, (&'a str, u64), IbcTransfer, IbcTransferIndexes<'a>> {
+    let indexes = IbcTransferIndexes {
+        escrow: MultiIndex::new(
+            |t| t.escrow.clone(),
+            "ibc_transfers",
+            "ibc_transfers__escrow",
+        ),
+    };
+    IndexedMap::new("ibc_transfers", indexes)
+}
+
+/// has_ibc_transfers tells whether an escrow has IBC transfers waiting for
+/// their outcome, which may still bring funds back to it
+pub fn has_ibc_transfers(storage: &dyn Storage, id: &str) -> StdResult<bool> {
+    let first = ibc_transfers()
+        .idx
+        .escrow
+        .prefix(id.to_string())
+        .keys_raw(storage, None, None, Order::Ascending)
+        .next();
+    Ok(first.is_some())
+}
+
+/// reply id of the TransferFrom pulling the deposit of CreateFromAllowance.
+/// It is never used by payouts, whose reply ids start at 1.
+pub const ALLOWANCE_REPLY_ID: u64 = 0;
+
+/// PendingCreate is an escrow waiting for its deposit to be pulled from the
+/// allowance of its creator
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct PendingCreate {
+    pub owner: Addr,
+    pub deposit: Cw20CoinVerified,
+    pub msg: CreateMsg,
+}
+
+/// escrow created by the CreateFromAllowance being executed, until the
+/// reply of its TransferFrom
+pub const PENDING_CREATE: Item<PendingCreate> = Item::new("pending_create");
+
+/// relayers each owner approved to create escrows from its allowance, keyed
+/// by (owner, relayer)
+pub const RELAYER_APPROVALS: Map<(&Addr, &Addr), Empty> = Map::new("relayer_approvals");
+
+/// values of the status index
+pub const STATUS_OPEN: &str = "open";
+pub const STATUS_CLOSED: &str = "closed";
+pub const STATUS_SETTLED: &str = "settled";
+
+pub struct EscrowIndexes<'a> {
+    pub user_a: MultiIndex<'a, Addr, Escrow, String>,
+    pub user_b: MultiIndex<'a, Addr, Escrow, String>,
+    pub status: MultiIndex<'a, String, Escrow, String>,
+    pub settled: MultiIndex<'a, (String, u64), Escrow, String>,
+}
+
+impl<'a> IndexList<Escrow> for EscrowIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<Escrow>> + '_> {
+        let v: Vec<&dyn Index<Escrow>> = vec![&self.user_a, &self.user_b, &self.status, &self.settled];
+        Box::new(v.into_iter())
+    }
+}
+
+/// escrows are stored by id, and indexed by creator, recipient, status and
+/// the time settled escrows were closed
+pub fn escrows<'a>() -> IndexedMap<'a, &'a str, Escrow, EscrowIndexes<'a>> {
+    let indexes = EscrowIndexes {
+        user_a: MultiIndex::new(
+            |e| e.user_a.clone(),
+            "escrow",
+            "escrow__user_a",
+        ),
+        user_b: MultiIndex::new(
+            |e| e.user_b.clone(),
+            "escrow",
+            "escrow__user_b",
+        ),
+        status: MultiIndex::new(
+            |e| status_key(e).to_string(),
+            "escrow",
+            "escrow__status",
+        ),
+        settled: MultiIndex::new(
+            settled_key,
+            "escrow",
+            "escrow__settled",
+        ),
+    };
+    IndexedMap::new("escrow", indexes)
+}
+
+/// status_key returns the value of the status index for an escrow
+pub fn status_key(escrow: &Escrow) -> &'static str {
+    if escrow.is_closed() {
+        STATUS_CLOSED
+    } else {
+        STATUS_OPEN
+    }
+}
+
+/// settled_key returns the value of the settled index for an escrow: the
+/// time it was closed, in nanoseconds, if settled. Other escrows are all
+/// indexed under their status.
+pub fn settled_key(escrow: &Escrow) -> (String, u64) {
+    match escrow.closed_at() {
+        Some(time) if escrow.is_settled() => (STATUS_SETTLED.to_string(), time.nanos()),
+        _ => (status_key(escrow).to_string(), 0),
+    }
+}
+
+/// This returns a page of ids of registered escrows, starting after
+/// start_after
+pub fn escrow_ids(
+    storage: &dyn Storage,
+    start_after: Option<String>,
+    limit: usize,
+) -> StdResult<Vec<String>> {
+    escrows()
+        .keys(storage, start_after.as_deref().map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
+        .collect()
+}
+
+/// This returns a page of escrows created by user_a
+pub fn escrows_by_creator(
+    storage: &dyn Storage,
+    user_a: &Addr,
+    start_after: Option<String>,
+    limit: usize,
+) -> StdResult<Vec<(String, Escrow)>> {
+    escrows()
+        .idx
+        .user_a
+        .prefix(user_a.clone())
+        .range(storage, start_after.map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
+        .collect()
+}
+
+/// This returns a page of escrows paying out to user_b
+pub fn escrows_by_recipient(
+    storage: &dyn Storage,
+    user_b: &Addr,
+    start_after: Option<String>,
+    limit: usize,
+) -> StdResult<Vec<(String, Escrow)>> {
+    escrows()
+        .idx
+        .user_b
+        .prefix(user_b.clone())
+        .range(storage, start_after.map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
+        .collect()
+}
+
+/// This returns a page of escrows that are not closed yet
+pub fn open_escrows(
+    storage: &dyn Storage,
+    start_after: Option<String>,
+    limit: usize,
+) -> StdResult<Vec<(String, Escrow)>> {
+    escrows()
+        .idx
+        .status
+        .prefix(STATUS_OPEN.to_string())
+        .range(storage, start_after.map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
+        .collect()
+}
+
+/// ArchivedEscrow is the record kept of a settled escrow once it is pruned
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct ArchivedEscrow {
+    pub id: String,
+    pub user_a: Addr,
+    pub user_b: Addr,
+    /// final deposit, after top ups and contributions
+    pub deposit: Balance,
+    /// status the escrow was closed with, completed or cancelled
+    pub outcome: EscrowStatus,
+    /// final amounts paid to each party
+    pub paid: Paid,
+    /// time the escrow was closed
+    pub closed_at: Timestamp,
+    /// height of the block the escrow was pruned in
+    pub pruned_at: u64,
+    /// reserved keeps the id taken, so that no new escrow can be created
+    /// with it
+    pub reserved: bool,
+}
+
+/// number of the last archive record
+pub const ARCHIVE_COUNT: Item<u64> = Item::new("archive_count");
+
+pub struct ArchiveIndexes<'a> {
+    pub id: MultiIndex<'a, String, ArchivedEscrow, u64>,
+}
+
+impl<'a> IndexList<ArchivedEscrow> for ArchiveIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<ArchivedEscrow>> + '_> {
+        let v: Vec<&dyn Index<ArchivedEscrow>> = vec![&self.id];
+        Box::new(v.into_iter())
+    }
+}
+
+/// archive records are stored in the order escrows are pruned, and indexed
+/// by escrow id. An id can have several records when it is reused.
+pub fn archive<'a>() -> IndexedMap<'a, u64, ArchivedEscrow, ArchiveIndexes<'a>> {
+    let indexes = ArchiveIndexes {
+        id: MultiIndex::new(
+            |a| a.id.clone(),
+            "archive",
+            "archive__id",
+        ),
+    };
+    IndexedMap::new("archive", indexes)
+}
+
+/// is_reserved tells whether an id is kept by the archive record of a
+/// pruned escrow
+pub fn is_reserved(storage: &dyn Storage, id: &str) -> StdResult<bool> {
+    for item in archive().idx.id.prefix(id.to_string()).range(storage, None, None, Order::Ascending) {
+        if item?.1.reserved {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// This returns a page of archive records, of every escrow or of a single
+/// id, starting after the record numbered start_after
+pub fn archived_escrows(
+    storage: &dyn Storage,
+    id: Option<&str>,
+    start_after: Option<u64>,
+    limit: usize,
+) -> StdResult<Vec<(u64, ArchivedEscrow)>> {
+    let start = start_after.map(Bound::exclusive);
+    match id {
+        Some(id) => archive()
+            .idx
+            .id
+            .prefix(id.to_string())
+            .range(storage, start, None, Order::Ascending)
+            .take(limit)
+            .collect(),
+        None => archive()
+            .range(storage, start, None, Order::Ascending)
+            .take(limit)
+            .collect(),
+    }
+}
+
+/// the most settled escrows read by a prune by time, so that escrows kept
+/// by IBC transfers in flight cannot make it unbounded
+pub const MAX_PRUNE_SCAN: usize = 100;
+
+/// This returns the ids of the first limit settled escrows closed before the
+/// given time, oldest first, leaving out those with IBC transfers in flight.
+/// At most MAX_PRUNE_SCAN escrows are read.
+pub fn settled_before(
+    storage: &dyn Storage,
+    time: Timestamp,
+    limit: usize,
+) -> StdResult<Vec<String>> {
+    let mut ids = vec![];
+    let end = Bound::exclusive((time.nanos(), String::new()));
+    let settled = escrows()
+        .idx
+        .settled
+        .sub_prefix(STATUS_SETTLED.to_string())
+        .keys(storage, None, Some(end), Order::Ascending)
+        .take(MAX_PRUNE_SCAN);
+    for item in settled {
+        if ids.len() >= limit {
+            break;
+        }
+        let id = item?;
+        if !has_ibc_transfers(storage, &id)? {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// This returns a page of the contributions to a shared fare, by address
+pub fn contributions(
+    storage: &dyn Storage,
+    id: &str,
+    start_after: Option<&Addr>,
+    limit: usize,
+) -> StdResult<Vec<(Addr, Uint128)>> {
+    CONTRIBUTIONS
+        .prefix(id)
+        .range(storage, start_after.map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
         .collect()
 }
 
@@ -16,44 +586,203 @@ pub fn all_escrow_ids(storage: &dyn Storage) -> StdResult<Vec<String>> {
 mod tests {
     use super::*;
 
-    use cosmwasm_std::{Addr, Uint128} ;    
+    use crate::escrow::{EscrowStatus, LockKind};
+    use cosmwasm_std::{Addr, Timestamp, Uint128} ;    
     use cw20::{Balance, Cw20CoinVerified};
     use cosmwasm_std::testing::MockStorage;
 
+    #[test]
+    fn claimable_add_and_take() {
+        let token = |address: &str, amount: u128| Cw20CoinVerified {
+            address: Addr::unchecked(address),
+            amount: Uint128::new(amount),
+        };
+        let mut claimable = Funds::default();
+        claimable.add(&Balance::from(vec![Coin::new(10, "osmo"), Coin::new(5, "atom")]));
+        claimable.add(&Balance::from(vec![Coin::new(7, "atom")]));
+        claimable.add(&Balance::Cw20(token("cw20_a", 3)));
+        claimable.add(&Balance::Cw20(token("cw20_b", 4)));
+        claimable.add(&Balance::Cw20(token("cw20_a", 2)));
+
+        // the native coins are taken together, in one balance
+        let taken = claimable.take(&[
+            Denom::Native("atom".to_string()),
+            Denom::Cw20(Addr::unchecked("cw20_a")),
+        ]);
+        assert_eq!(
+            vec![Balance::from(vec![Coin::new(12, "atom")]), Balance::Cw20(token("cw20_a", 5))],
+            taken
+        );
+
+        let taken = claimable.take(&[]);
+        assert_eq!(
+            vec![Balance::from(vec![Coin::new(10, "osmo")]), Balance::Cw20(token("cw20_b", 4))],
+            taken
+        );
+        assert!(claimable.is_empty());
+    }
+
+    #[test]
+    fn stats_move_funds() {
+        let mut stats = Stats::default();
+        stats.add_escrow(&Balance::from(vec![Coin::new(100, "osmo")]));
+        stats.add_escrow(&Balance::from(vec![Coin::new(40, "osmo"), Coin::new(5, "atom")]));
+        stats.lock(&Balance::from(vec![Coin::new(10, "osmo")]));
+
+        stats.release(&Balance::from(vec![Coin::new(110, "osmo")]));
+        stats.close(EscrowStatus::Completed);
+        assert_eq!(1, stats.open);
+        assert_eq!(1, stats.withdrawn);
+        assert_eq!(vec![Coin::new(5, "atom"), Coin::new(40, "osmo")], stats.locked.native);
+        assert_eq!(vec![Coin::new(110, "osmo")], stats.released.native);
+
+        // the funds that are no longer locked are dropped
+        stats.release(&Balance::from(vec![Coin::new(40, "osmo"), Coin::new(5, "atom")]));
+        stats.close(EscrowStatus::Cancelled);
+        assert_eq!(0, stats.open);
+        assert_eq!(1, stats.cancelled);
+        assert!(stats.locked.is_empty());
+        assert_eq!(vec![Coin::new(5, "atom"), Coin::new(150, "osmo")], stats.released.native);
+    }
+
+    #[test]
+    fn stale_payouts_are_removed() {
+        let mut storage = MockStorage::new();
+        for (id, height) in [(1, 10), (2, 11), (3, 12)] {
+            let payout = Payout {
+                escrow: "ride".to_string(),
+                msg: cosmwasm_std::BankMsg::Send { to_address: "user_b".to_string(), amount: vec![] }.into(),
+                height,
+            };
+            PAYOUTS.save(&mut storage, id, &payout).unwrap();
+        }
+
+        remove_stale_payouts(&mut storage, 12).unwrap();
+        let ids = PAYOUTS
+            .keys(&storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(vec![3], ids);
+    }
+
     #[test]
     fn no_escrow_ids() {
         let storage = MockStorage::new();
-        let ids = all_escrow_ids(&storage).unwrap();
+        let ids = escrow_ids(&storage, None, 10).unwrap();
         assert_eq!(0, ids.len());
     }
 
-    fn dummy_escrow() -> Escrow {
+    fn dummy_escrow(user_a: &str, user_b: &str) -> Escrow {
         let coin = Cw20CoinVerified {
             address: Addr::unchecked("coin_address"),
             amount: Uint128::new(100),
         };
     
         let e = Escrow::create(
-            Addr::unchecked("user_a"),
-            Addr::unchecked("user_b"),
+            Addr::unchecked(user_a),
+            Addr::unchecked(user_b),
             Balance::Cw20(coin),
-            "LOCK",
+            "0330347c5cb0f1627bdd2e7b082504a443b2bf50ad2e3efbb4e754ebd687c78c24",
+            LockKind::K256,
+            None,
+            None,
+            Timestamp::from_seconds(0),
         );
-        return e.unwrap();
+        e.unwrap()
     }
 
     #[test]
-    fn all_escrow_ids_in_order() {
+    fn escrow_ids_in_order() {
         let mut storage = MockStorage::new();
-        ESCROWS.save(&mut storage, "lazy", &dummy_escrow()).unwrap();
-        ESCROWS.save(&mut storage, "assign", &dummy_escrow()).unwrap();
-        ESCROWS.save(&mut storage, "zen", &dummy_escrow()).unwrap();
+        escrows().save(&mut storage, "lazy", &dummy_escrow("user_a", "user_b")).unwrap();
+        escrows().save(&mut storage, "assign", &dummy_escrow("user_a", "user_b")).unwrap();
+        escrows().save(&mut storage, "zen", &dummy_escrow("user_a", "user_b")).unwrap();
 
-        let ids = all_escrow_ids(&storage).unwrap();
+        let ids = escrow_ids(&storage, None, 10).unwrap();
         assert_eq!(3, ids.len());
         assert_eq!(
             vec!["assign".to_string(), "lazy".to_string(), "zen".to_string()],
             ids
-        )
+        );
+
+        // paginate
+        let ids = escrow_ids(&storage, None, 2).unwrap();
+        assert_eq!(vec!["assign".to_string(), "lazy".to_string()], ids);
+        let ids = escrow_ids(&storage, Some("lazy".to_string()), 2).unwrap();
+        assert_eq!(vec!["zen".to_string()], ids);
+    }
+
+    #[test]
+    fn escrows_by_index() {
+        let mut storage = MockStorage::new();
+        escrows().save(&mut storage, "ride1", &dummy_escrow("alice", "bob")).unwrap();
+        escrows().save(&mut storage, "ride2", &dummy_escrow("alice", "carl")).unwrap();
+        escrows().save(&mut storage, "ride3", &dummy_escrow("dave", "bob")).unwrap();
+        let mut closed = dummy_escrow("alice", "bob");
+        closed.set_status(EscrowStatus::Completed, Timestamp::from_seconds(1)).unwrap();
+        escrows().save(&mut storage, "ride4", &closed).unwrap();
+
+        let ids = |page: Vec<(String, Escrow)>| page.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+
+        let page = escrows_by_creator(&storage, &Addr::unchecked("alice"), None, 10).unwrap();
+        assert_eq!(vec!["ride1", "ride2", "ride4"], ids(page));
+        let page = escrows_by_creator(&storage, &Addr::unchecked("alice"), Some("ride1".to_string()), 1).unwrap();
+        assert_eq!(vec!["ride2"], ids(page));
+
+        let page = escrows_by_recipient(&storage, &Addr::unchecked("bob"), None, 10).unwrap();
+        assert_eq!(vec!["ride1", "ride3", "ride4"], ids(page));
+
+        let page = open_escrows(&storage, None, 10).unwrap();
+        assert_eq!(vec!["ride1", "ride2", "ride3"], ids(page));
+
+        // closing an escrow updates the status index
+        let mut escrow = escrows().load(&storage, "ride1").unwrap();
+        escrow.set_status(EscrowStatus::Cancelled, Timestamp::from_seconds(1)).unwrap();
+        escrows().save(&mut storage, "ride1", &escrow).unwrap();
+        let page = open_escrows(&storage, None, 10).unwrap();
+        assert_eq!(vec!["ride2", "ride3"], ids(page));
+    }
+
+    #[test]
+    fn settled_before_in_close_order() {
+        let mut storage = MockStorage::new();
+        let closed = |status: EscrowStatus, seconds: u64| {
+            let mut escrow = dummy_escrow("alice", "bob");
+            escrow.set_status(status, Timestamp::from_seconds(seconds)).unwrap();
+            escrow
+        };
+        escrows().save(&mut storage, "open", &dummy_escrow("alice", "bob")).unwrap();
+        escrows().save(&mut storage, "late", &closed(EscrowStatus::Completed, 30)).unwrap();
+        escrows().save(&mut storage, "first", &closed(EscrowStatus::Cancelled, 10)).unwrap();
+        escrows().save(&mut storage, "second", &closed(EscrowStatus::Completed, 20)).unwrap();
+        escrows().save(&mut storage, "failed", &closed(EscrowStatus::PayoutFailed, 5)).unwrap();
+
+        // oldest first, closed strictly before the time
+        let ids = settled_before(&storage, Timestamp::from_seconds(30), 10).unwrap();
+        assert_eq!(vec!["first", "second"], ids);
+        let ids = settled_before(&storage, Timestamp::from_seconds(31), 1).unwrap();
+        assert_eq!(vec!["first"], ids);
+
+        // an escrow with an IBC transfer in flight is left out
+        let transfer = IbcTransfer {
+            escrow: "first".to_string(),
+            fallback: Addr::unchecked("bob"),
+            amount: Coin::new(100, "token"),
+        };
+        ibc_transfers().save(&mut storage, ("channel-0", 1), &transfer).unwrap();
+        let ids = settled_before(&storage, Timestamp::from_seconds(31), 10).unwrap();
+        assert_eq!(vec!["second", "late"], ids);
+        // without taking up the limit
+        let ids = settled_before(&storage, Timestamp::from_seconds(31), 1).unwrap();
+        assert_eq!(vec!["second"], ids);
+
+        // but no more than MAX_PRUNE_SCAN escrows are read
+        for i in 0..MAX_PRUNE_SCAN as u64 {
+            let id = format!("flight{}", i);
+            escrows().save(&mut storage, &id, &closed(EscrowStatus::Completed, 1)).unwrap();
+            ibc_transfers().save(&mut storage, ("channel-0", i + 2), &IbcTransfer { escrow: id, ..transfer.clone() }).unwrap();
+        }
+        let ids = settled_before(&storage, Timestamp::from_seconds(31), 10).unwrap();
+        assert!(ids.is_empty());
     }
 }
@@ -0,0 +1,22 @@
+#!/bin/sh
+
+source ./scripts/util.sh
+
+# let a relayer create escrows from the allowance of FROM
+# ex: escrow-approve-relayer.sh alice backend
+FROM=$1
+RELAYER=$2
+
+MSG='{"approve_relayer": {"relayer": "'"$($CMD keys show -a $RELAYER)"'"}}';
+
+command=($CMD tx wasm execute $ESCROW)
+command+=("$MSG")
+command+=(--from $FROM)
+command+=(--gas-prices 0.1$FEETOKEN)
+command+=(--gas auto)
+command+=(--gas-adjustment 1.3)
+command+=(--chain-id $CHAINID)
+command+=(--node $NODE)
+command+=(-y)
+
+execute_tx_block "${command[@]}"
@@ -0,0 +1,5 @@
+#!/bin/sh
+
+$CMD query wasm contract-state smart $ESCROW \
+'{"balance":{"address":"'"$($CMD keys show -a $1)"'"}}' \
+--node $NODE
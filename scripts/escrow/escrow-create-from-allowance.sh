@@ -0,0 +1,37 @@
+#!/bin/sh
+
+source ./scripts/util.sh
+
+# create a NRIDE escrow on behalf of OWNER, sent by a relayer
+# ex: escrow-create-from-allowance.sh backend alice test bob 100 <lock>
+FROM=$1
+OWNER=$2
+ID=$3
+USER_B=$4
+AMOUNT=$5
+LOCK=$6
+
+CREATE_CONTENT='{'\
+'"id": "'$ID'",'\
+'"user_b": "'"$($CMD keys show -a $USER_B)"'",'\
+'"lock": "'"$LOCK"'"'\
+'}';
+
+ESCROW_EXECUTE='{"create_from_allowance": {'\
+'"token": "'$NRIDE'",'\
+'"amount": "'$AMOUNT'",'\
+'"owner": "'"$($CMD keys show -a $OWNER)"'",'\
+'"msg": '"$CREATE_CONTENT"\
+'}}';
+
+command=($CMD tx wasm execute $ESCROW)
+command+=("$ESCROW_EXECUTE")
+command+=(--from $FROM)
+command+=(--gas-prices 0.1$FEETOKEN)
+command+=(--gas auto)
+command+=(--gas-adjustment 1.3)
+command+=(--chain-id $CHAINID)
+command+=(--node $NODE)
+command+=(-y)
+
+execute_tx_block "${command[@]}"
@@ -0,0 +1,11 @@
+#!/bin/sh
+
+# archive records of the pruned escrows, of a single id if given
+if [ -z "$1" ]; then
+    QUERY='{"history":{}}'
+else
+    QUERY='{"history":{"id":"'"$1"'"}}'
+fi
+
+$CMD query wasm contract-state smart $ESCROW "$QUERY" \
+--node $NODE
@@ -0,0 +1,29 @@
+#!/bin/sh
+
+source ./scripts/util.sh
+
+# let the escrow contract pull NRIDE tokens from the rider
+# ex: escrow-increase-allowance.sh alice 100
+FROM=$1
+AMOUNT=$2
+
+ALLOWANCE_CONTENT='{'\
+'"spender": "'$ESCROW'",'\
+'"amount": "'$AMOUNT'"'\
+'}';
+
+TOKEN_EXECUTE='{"increase_allowance": '"$ALLOWANCE_CONTENT"'}';
+
+# cw20 contract
+command=($CMD tx wasm execute $NRIDE)
+command+=("$TOKEN_EXECUTE")
+command+=(--from $FROM)
+command+=(--fee-granter $($CMD keys show -a faucet))
+command+=(--gas-prices 0.1$FEETOKEN)
+command+=(--gas auto)
+command+=(--gas-adjustment 1.3)
+command+=(--chain-id $CHAINID)
+command+=(--node $NODE)
+command+=(-y)
+
+execute_tx_block "${command[@]}"
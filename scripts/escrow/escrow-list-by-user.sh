@@ -0,0 +1,16 @@
+#!/bin/sh
+
+# list the escrows created by (creator) or paying out to (recipient) a user
+# ex: escrow-list-by-user.sh creator alice
+ROLE=$1
+USER=$2
+
+if [ "$ROLE" = "creator" ]; then
+    query='{"list_by_creator":{"user_a":"'"$($CMD keys show -a $USER)"'"}}'
+else
+    query='{"list_by_recipient":{"user_b":"'"$($CMD keys show -a $USER)"'"}}'
+fi
+
+$CMD query wasm contract-state smart $ESCROW \
+"$query" \
+--node $NODE
@@ -0,0 +1,21 @@
+#!/bin/sh
+
+source ./scripts/util.sh
+
+# prune settled escrows, sent by the admin (faucet)
+# ex: escrow-prune.sh '["ride1","ride2"]'
+IDS=$1
+
+MSG='{"prune": {"ids": '$IDS'}}';
+
+command=($CMD tx wasm execute $ESCROW)
+command+=("$MSG")
+command+=(--from faucet)
+command+=(--gas-prices 0.1$FEETOKEN)
+command+=(--gas auto)
+command+=(--gas-adjustment 1.3)
+command+=(--chain-id $CHAINID)
+command+=(--node $NODE)
+command+=(-y)
+
+execute_tx_block "${command[@]}"
@@ -0,0 +1,5 @@
+#!/bin/sh
+
+$CMD query wasm contract-state smart $ESCROW \
+'{"stats":{}}' \
+--node $NODE
@@ -0,0 +1,25 @@
+#!/bin/sh
+
+source ./scripts/util.sh
+
+# replace the tokens accepted as deposit, sent by the admin (faucet)
+# ex: escrow-update-allowlist.sh '["uusdc"]' '["juno1..."]'
+ALLOWED_NATIVE=$1
+ALLOWED_CW20=$2
+
+MSG='{"update_allowlist": {'\
+'"allowed_native": '$ALLOWED_NATIVE','\
+'"allowed_cw20": '$ALLOWED_CW20\
+'}}';
+
+command=($CMD tx wasm execute $ESCROW)
+command+=("$MSG")
+command+=(--from faucet)
+command+=(--gas-prices 0.1$FEETOKEN)
+command+=(--gas auto)
+command+=(--gas-adjustment 1.3)
+command+=(--chain-id $CHAINID)
+command+=(--node $NODE)
+command+=(-y)
+
+execute_tx_block "${command[@]}"
@@ -0,0 +1,22 @@
+#!/bin/sh
+
+source ./scripts/util.sh
+
+# replace the relayers allowed to create escrows from allowances, sent by the
+# admin (faucet)
+# ex: escrow-update-relayers.sh '["juno1..."]'
+RELAYERS=$1
+
+MSG='{"update_relayers": {"relayers": '$RELAYERS'}}';
+
+command=($CMD tx wasm execute $ESCROW)
+command+=("$MSG")
+command+=(--from faucet)
+command+=(--gas-prices 0.1$FEETOKEN)
+command+=(--gas auto)
+command+=(--gas-adjustment 1.3)
+command+=(--chain-id $CHAINID)
+command+=(--node $NODE)
+command+=(-y)
+
+execute_tx_block "${command[@]}"
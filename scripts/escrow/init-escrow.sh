@@ -3,10 +3,20 @@
 source ./scripts/util.sh
 
 CODE=$1
+# platform fee in basis points, collected by the faucet account
+FEE_BPS=${2:-0}
 
-command=($CMD tx wasm instantiate $CODE {}) 
+# only NRIDE and the native stablecoin are accepted as deposit
+INIT_MSG='{'\
+'"fee_collector": "'"$($CMD keys show -a faucet)"'",'\
+'"fee_bps": '$FEE_BPS','\
+'"allowed_native": ["'$NATIVE'"],'\
+'"allowed_cw20": ["'$NRIDE'"]'\
+'}';
+
+command=($CMD tx wasm instantiate $CODE "$INIT_MSG") 
 command+=(--label "NRIDE ESCROW INIT")
-command+=(--no-admin)
+command+=(--admin $($CMD keys show -a faucet))
 command+=(--from faucet)
 command+=(--chain-id $CHAINID)
 command+=(--gas-prices 0.1$FEETOKEN)
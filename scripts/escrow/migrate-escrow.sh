@@ -0,0 +1,27 @@
+#!/bin/sh
+
+source ./scripts/util.sh
+
+ADDR=$1
+CODE=$2
+
+# contracts instantiated from v0.2.0 have no config yet: the faucet account
+# becomes the admin and collects the fee, and only NRIDE and the native
+# stablecoin are accepted as deposit
+MIGRATE_MSG='{'\
+'"admin": "'"$($CMD keys show -a faucet)"'",'\
+'"fee_collector": "'"$($CMD keys show -a faucet)"'",'\
+'"allowed_native": ["'$NATIVE'"],'\
+'"allowed_cw20": ["'$NRIDE'"]'\
+'}';
+
+command=($CMD tx wasm migrate $ADDR $CODE "$MIGRATE_MSG")
+command+=(--from faucet)
+command+=(--chain-id $CHAINID)
+command+=(--gas-prices 0.1$FEETOKEN)
+command+=(--gas auto)
+command+=(--gas-adjustment 1.3)
+command+=(--node $NODE)
+command+=(-y) 
+
+execute_tx_block "${command[@]}"